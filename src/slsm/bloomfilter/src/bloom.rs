@@ -8,6 +8,7 @@ use std::f64;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 
+use error::{Error, Result};
 use rand::prelude::*;
 use bit_vec::BitVec;
 use siphasher::sip::SipHasher13;
@@ -47,26 +48,28 @@ impl<T: ?Sized> Bloom<T> {
      * */
 
 
-    pub fn new(bitmap_size: usize, items_count: usize) -> Self {
-        assert!(bitmap_size > 0 && items_count > 0);
+    pub fn new(bitmap_size: usize, items_count: usize) -> Result<Self> {
+        if bitmap_size == 0 || items_count == 0 {
+            return Err(Error::invalid_argument("bloom filter sized for no bits or no items"));
+        }
         let bitmap_bits = (bitmap_size as u64) * 8u64;
         let k_num = Self::optimal_k_num(bitmap_bits, items_count);
         let bitmap = BitVec::from_elem(bitmap_bits as usize, false);
         let sips = [Self::sip_new(), Self::sip_new()];
-        Self {
+        Ok(Self {
             bitmap,
             bitmap_bits,
             k_num,
             sips,
             _phantom: PhantomData,
-        }
+        })
     }
 
     /// Create a new bloom filter structure.
     /// items_count is an estimation of the maximum number of items to store.
     /// fp_p is the wanted rate of false positives, in ]0.0, 1.0[
-    pub fn new_for_fp_rate(items_count: usize, fp_p: f64) -> Self {
-        let bitmap_size = Self::compute_bitmap_size(items_count, fp_p);
+    pub fn new_for_fp_rate(items_count: usize, fp_p: f64) -> Result<Self> {
+        let bitmap_size = Self::compute_bitmap_size(items_count, fp_p)?;
         Bloom::new(bitmap_size, items_count)
     }
 
@@ -94,22 +97,35 @@ impl<T: ?Sized> Bloom<T> {
     /// Compute a recommended bitmap size for items_count items
     /// and a fp_p rate of false positives.
     /// fp_p obviously has to be within the ]0.0, 1.0[ range.
-    pub fn compute_bitmap_size(items_count: usize, fp_p: f64) -> usize {
-        assert!(items_count > 0);
-        assert!(fp_p > 0.0 && fp_p < 1.0);
+    pub fn compute_bitmap_size(items_count: usize, fp_p: f64) -> Result<usize> {
+        if items_count == 0 {
+            return Err(Error::invalid_argument("bloom filter sized for no items"));
+        }
+        if !(fp_p > 0.0 && fp_p < 1.0) {
+            return Err(Error::invalid_argument(format!("false positive rate {} not in ]0.0, 1.0[", fp_p)));
+        }
         let log2 = f64::consts::LN_2;
         let log2_2 = log2 * log2;
-        ((items_count as f64) * f64::ln(fp_p) / (-8.0 * log2_2)).ceil() as usize
+        Ok(((items_count as f64) * f64::ln(fp_p) / (-8.0 * log2_2)).ceil() as usize)
     }
 
     /// Record the presence of an item.
     pub fn set(&mut self, item: &T)
         where
         T: Hash,
+        {
+            self.set_with(|mut state| item.hash(&mut state))
+        }
+
+    /// Record the presence of an item fed to the hasher by `hash`, for items
+    /// that have to be hashed otherwise than by their `Hash` impl.
+    pub fn set_with<F>(&mut self, hash: F)
+        where
+        F: Fn(&mut dyn Hasher),
         {
             let mut hashes = [0u64, 0u64];
             for k_i in 0..self.k_num {
-                let bit_offset = (self.bloom_hash(&mut hashes, &item, k_i) % self.bitmap_bits) as usize;
+                let bit_offset = (self.bloom_hash(&mut hashes, &hash, k_i) % self.bitmap_bits) as usize;
                 self.bitmap.set(bit_offset, true);
             }
         }
@@ -119,11 +135,19 @@ impl<T: ?Sized> Bloom<T> {
     pub fn check(&self, item: &T) -> bool
         where
         T: Hash,
+        {
+            self.check_with(|mut state| item.hash(&mut state))
+        }
+
+    /// Check if an item fed to the hasher by `hash` is present in the set.
+    pub fn check_with<F>(&self, hash: F) -> bool
+        where
+        F: Fn(&mut dyn Hasher),
         {
             let mut hashes = [0u64, 0u64];
             for k_i in 0..self.k_num {
-                let bit_offset = (self.bloom_hash(&mut hashes, &item, k_i) % self.bitmap_bits) as usize;
-                if self.bitmap.get(bit_offset).unwrap() == false {
+                let bit_offset = (self.bloom_hash(&mut hashes, &hash, k_i) % self.bitmap_bits) as usize;
+                if !self.bitmap.get(bit_offset).unwrap() {
                     return false;
                 }
             }
@@ -138,9 +162,10 @@ impl<T: ?Sized> Bloom<T> {
         {
             let mut hashes = [0u64, 0u64];
             let mut found = true;
+            let hash = |mut state: &mut dyn Hasher| item.hash(&mut state);
             for k_i in 0..self.k_num {
-                let bit_offset = (self.bloom_hash(&mut hashes, &item, k_i) % self.bitmap_bits) as usize;
-                if self.bitmap.get(bit_offset).unwrap() == false {
+                let bit_offset = (self.bloom_hash(&mut hashes, &hash, k_i) % self.bitmap_bits) as usize;
+                if !self.bitmap.get(bit_offset).unwrap() {
                     found = false;
                     self.bitmap.set(bit_offset, true);
                 }
@@ -175,21 +200,18 @@ impl<T: ?Sized> Bloom<T> {
         cmp::max(k_num, 1)
     }
 
-    fn bloom_hash(&self, hashes: &mut [u64; 2], item: &T, k_i: u32) -> u64
-        where
-            T: Hash,
-        {
-            if k_i < 2 {
-                let sip = &mut self.sips[k_i as usize].clone();
-                item.hash(sip);
-                let hash = sip.finish();
-                hashes[k_i as usize] = hash;
-                hash
-            } else {
-                (hashes[0] as u128).wrapping_add((k_i as u128).wrapping_mul(hashes[1] as u128)) as u64
-                    % 0xffffffffffffffc5
-            }
+    fn bloom_hash(&self, hashes: &mut [u64; 2], hash: &dyn Fn(&mut dyn Hasher), k_i: u32) -> u64 {
+        if k_i < 2 {
+            let sip = &mut self.sips[k_i as usize].clone();
+            hash(sip);
+            let hash = sip.finish();
+            hashes[k_i as usize] = hash;
+            hash
+        } else {
+            (hashes[0] as u128).wrapping_add((k_i as u128).wrapping_mul(hashes[1] as u128)) as u64
+                % 0xffffffffffffffc5
         }
+    }
 
     /// Clear all of the bits in the filter, removing all keys from the set
     pub fn clear(&mut self) {
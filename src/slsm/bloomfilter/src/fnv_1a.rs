@@ -1,15 +1,7 @@
 //! An implementation of the [Fowler–Noll–Vo hash function][chongo].
 
-extern crate alloc;
-
-#[cfg(feature = "std")]
 use std::default::Default;
-#[cfg(feature = "std")]
 use std::hash::{Hasher, BuildHasherDefault};
-#[cfg(not(feature = "std"))]
-use core::default::Default;
-#[cfg(not(feature = "std"))]
-use core::hash::{Hasher, BuildHasherDefault};
 
 #[allow(missing_copy_implementations)]
 pub struct FnvHasher(u64);
@@ -40,7 +32,7 @@ impl Hasher for FnvHasher {
         let FnvHasher(mut hash) = *self;
 
         for byte in bytes.iter() {
-            hash = hash ^ (*byte as u64);
+            hash ^= *byte as u64;
             hash = hash.wrapping_mul(0x100000001b3);
         }
 
@@ -51,7 +43,7 @@ impl Hasher for FnvHasher {
 /// A builder for default FNV hashers.
 pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
 
-fn fnv1a(bytes: &[u8]) -> u64 {
+pub fn fnv1a(bytes: &[u8]) -> u64 {
     let mut hasher = FnvHasher::default();
     hasher.write(bytes);
     hasher.finish()
@@ -61,10 +53,14 @@ fn fnv1a(bytes: &[u8]) -> u64 {
 #[cfg(test)]
 mod test {
     use super::*;
-    #[cfg(feature = "std")]
-    use std::hash::Hasher;
-    #[cfg(not(feature = "std"))]
-    use alloc::vec::Vec;
+
+    fn repeat_10(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().cycle().take(bytes.len() * 10).cloned().collect()
+    }
+
+    fn repeat_500(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().cycle().take(bytes.len() * 500).cloned().collect()
+    }
 
     #[test]
     fn basic_tests() {
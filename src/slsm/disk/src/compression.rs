@@ -0,0 +1,130 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::io;
+
+use error::Result;
+
+use crate::format::corruption;
+
+/// Zstd level used for the deeper levels by default.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Size of the largest block the writer compresses, so the most a block
+/// decompresses to. Larger blocks, holding huge values, are stored as they
+/// are.
+pub const MAX_BLOCK_SIZE: usize = 32 << 20;
+
+/// How the data blocks of a disk run are compressed.
+///
+/// Each block records the codec it was written with, so runs written with
+/// different settings, or blocks left uncompressed because compressing them
+/// saved too little, are read alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// LZ4, fast to compress and to decompress.
+    Lz4,
+    /// Zstandard at the given level, slower but smaller.
+    Zstd(i32),
+}
+
+const ID_NONE: u8 = 0;
+const ID_LZ4: u8 = 1;
+const ID_ZSTD: u8 = 2;
+
+impl Compression {
+    /// The compression of disk level `level` unless configured otherwise:
+    /// the upper levels, rewritten often, use LZ4, and the deeper levels,
+    /// holding most of the data, use Zstandard.
+    pub fn default_for_level(level: usize) -> Self {
+        if level < 2 {
+            Compression::Lz4
+        } else {
+            Compression::Zstd(DEFAULT_ZSTD_LEVEL)
+        }
+    }
+
+    /// The codec id stored in the trailer of a block.
+    #[inline]
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => ID_NONE,
+            Compression::Lz4 => ID_LZ4,
+            Compression::Zstd(_) => ID_ZSTD,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd(level) => Ok(zstd::bulk::compress(data, level)?),
+        }
+    }
+
+    /// Decompress `data`, compressed by the codec with id `id`. Data which
+    /// does not decompress, or would decompress to more than
+    /// `MAX_BLOCK_SIZE` bytes, is reported as an `InvalidData` error, for
+    /// the reader to tell where it was found. The size recorded by the
+    /// codec is checked before allocating, so a corrupt block read without
+    /// verifying its checksum can not take much memory.
+    pub(crate) fn decompress(id: u8, data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+        match id {
+            ID_NONE => Ok(Cow::Borrowed(data)),
+            ID_LZ4 => {
+                let size = match data.get(..4) {
+                    Some(size) => u32::from_le_bytes(size.try_into().unwrap()) as usize,
+                    None => return Err(corruption("bad lz4 block")),
+                };
+                if size > MAX_BLOCK_SIZE {
+                    return Err(corruption("lz4 block too large"));
+                }
+                lz4_flex::decompress_size_prepended(data)
+                    .map(Cow::Owned)
+                    .map_err(|_| corruption("bad lz4 block"))
+            }
+            ID_ZSTD => {
+                let capacity = match zstd::zstd_safe::get_frame_content_size(data) {
+                    Ok(Some(size)) if size > MAX_BLOCK_SIZE as u64 => return Err(corruption("zstd block too large")),
+                    Ok(Some(size)) => size as usize,
+                    Ok(None) => MAX_BLOCK_SIZE,
+                    Err(_) => return Err(corruption("bad zstd block")),
+                };
+                zstd::bulk::decompress(data, capacity)
+                    .map(Cow::Owned)
+                    .map_err(|_| corruption("bad zstd block"))
+            }
+            _ => Err(corruption("unknown compression codec")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, MAX_BLOCK_SIZE};
+
+    #[test]
+    fn round_trips() {
+        let data: Vec<u8> = (0..4096u32).flat_map(|i| format!("key{:04}", i % 300).into_bytes()).collect();
+        for &compression in &[Compression::None, Compression::Lz4, Compression::Zstd(3), Compression::Zstd(19)] {
+            let compressed = compression.compress(&data).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 4);
+            }
+            assert_eq!(&*Compression::decompress(compression.id(), &compressed).unwrap(), &data[..]);
+        }
+        assert!(Compression::decompress(Compression::Lz4.id(), b"garbage").is_err());
+        assert!(Compression::decompress(7, b"").is_err());
+    }
+
+    #[test]
+    fn oversized_blocks_are_rejected() {
+        let mut lz4 = Compression::Lz4.compress(b"abc").unwrap();
+        lz4[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Compression::decompress(Compression::Lz4.id(), &lz4).is_err());
+
+        let data = vec![0; MAX_BLOCK_SIZE + 1];
+        let zstd = Compression::Zstd(1).compress(&data).unwrap();
+        assert!(Compression::decompress(Compression::Zstd(1).id(), &zstd).is_err());
+    }
+}
@@ -1,27 +1,567 @@
-// use std::collections::BinaryHeap;
-// use std::iter;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use skiplist::run::KVpair;
-use crate::disk_run::DiskRun;
+use error::Result;
+use skiplist::run::{KVpair, RangeTombstone};
+use skiplist::Comparator;
+use crate::compression::Compression;
+use crate::disk_run::{DiskRun, DiskRunWriter};
+use crate::encoding::Encoding;
+use crate::env::Env;
+use crate::ttl::Expiry;
 
-#[derive(Debug, Clone)]
-pub struct KVIntPairT<K, V> {
+/// A pair waiting in the merge heap, along with the index of the input it
+/// was read from and the comparator ordering the heap.
+#[derive(Clone)]
+pub struct KVIntPairT<'c, K, V> {
     pub kvpair: KVpair<K, V>,
     pub i: isize,
+    cmp: &'c dyn Comparator<K>,
 }
 
-pub struct DiskLevel<K, V> {
-    tombstone:      isize,
-    KVINTPAIRTMAX:  isize,
-    KVPAIRMAX:      isize,
+impl<'c, K, V> KVIntPairT<'c, K, V> {
+    #[inline]
+    fn same_key(&self, other: &Self) -> bool {
+        self.cmp.compare(self.kvpair.key.as_ref().unwrap(), other.kvpair.key.as_ref().unwrap()) == Ordering::Equal
+    }
+}
+
+// `BinaryHeap` is a max-heap: the smallest key comes out first, and for
+// equal keys the pair of the newest input, with the largest index.
+impl<'c, K, V> Ord for KVIntPairT<'c, K, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp
+            .compare(other.kvpair.key.as_ref().unwrap(), self.kvpair.key.as_ref().unwrap())
+            .then(self.i.cmp(&other.i))
+    }
+}
+
+impl<'c, K, V> PartialOrd for KVIntPairT<'c, K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'c, K, V> PartialEq for KVIntPairT<'c, K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'c, K, V> Eq for KVIntPairT<'c, K, V> {}
+
+/// Streaming k-way merge of inputs sorted by `cmp`, ordered from oldest to
+/// newest, into one sorted stream with unique keys.
+///
+/// Only the head of each input is held in memory. For a key found in
+/// several inputs the pair of the newest one wins and the others are
+/// skipped, shadowed tombstones included. Winning tombstones are dropped
+/// too when `drop_tombstones` is set. The merge stops after the first error
+/// of an input.
+///
+/// With an `Expiry`, a winning pair whose value has expired turns into a
+/// tombstone, which still hides the older pairs of its key.
+pub struct MergeIter<'c, K, V, I> {
+    inputs: Vec<I>,
+    heap: BinaryHeap<KVIntPairT<'c, K, V>>,
+    cmp: &'c dyn Comparator<K>,
+    drop_tombstones: bool,
+    expiry: Option<Expiry<V>>,
+    started: bool,
+    failed: bool,
+}
+
+impl<'c, K, V, I> MergeIter<'c, K, V, I>
+where
+    I: Iterator<Item = Result<KVpair<K, V>>>,
+{
+    pub fn new(inputs: Vec<I>, drop_tombstones: bool, cmp: &'c dyn Comparator<K>) -> Self {
+        MergeIter {
+            heap: BinaryHeap::with_capacity(inputs.len()),
+            inputs,
+            cmp,
+            drop_tombstones,
+            expiry: None,
+            started: false,
+            failed: false,
+        }
+    }
+
+    /// Turn the pairs `expiry` finds expired into tombstones.
+    pub fn with_expiry(mut self, expiry: Option<Expiry<V>>) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Push the next pair of input `i` on the heap.
+    fn refill(&mut self, i: usize) -> Result<()> {
+        if let Some(kvpair) = self.inputs[i].next() {
+            self.heap.push(KVIntPairT {
+                kvpair: kvpair?,
+                i: i as isize,
+                cmp: self.cmp,
+            });
+        }
+        Ok(())
+    }
+
+    fn next_pair(&mut self) -> Result<Option<KVpair<K, V>>> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.inputs.len() {
+                self.refill(i)?;
+            }
+        }
+
+        while let Some(mut top) = self.heap.pop() {
+            self.refill(top.i as usize)?;
+            while let Some(older) = self.heap.peek() {
+                if !older.same_key(&top) {
+                    break;
+                }
+                let older = self.heap.pop().unwrap();
+                self.refill(older.i as usize)?;
+            }
+            if let (Some(expiry), Some(value)) = (&self.expiry, &top.kvpair.value) {
+                if expiry.is_expired(value) {
+                    top.kvpair.value = None;
+                }
+            }
+            if !(self.drop_tombstones && top.kvpair.is_tombstone()) {
+                return Ok(Some(top.kvpair));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'c, K, V, I> Iterator for MergeIter<'c, K, V, I>
+where
+    I: Iterator<Item = Result<KVpair<K, V>>>,
+{
+    type Item = Result<KVpair<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_pair() {
+            Ok(kv) => kv.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
 
-    pub level:      isize,
+/// One level of the disk-backed store, full once it holds `run_nums` runs
+/// of `run_size` pairs each. `runs[0]` is the oldest run of the level.
+///
+/// The runs are shared, so cloning a level is cheap and gives a snapshot of
+/// it that readers can keep using while the level changes. Every run of the
+/// level is sorted by the comparator of the level.
+#[derive(Clone)]
+pub struct DiskLevel<K, V> {
+    pub level:      usize,
     pub page_size:  usize,
     pub run_size:   usize,
     pub run_nums:   usize,
     pub active_run: usize,
     pub merge_size: usize,
     pub bf_fp:      f64,
-    pub runs:       Vec<DiskRun<K, V>>,
-    
+    /// How the data blocks of the runs written to the level are compressed.
+    pub compression: Compression,
+    /// Whether the runs of the level verify the checksums of the data blocks
+    /// they read, set on the runs as they are added.
+    pub verify_checksums: bool,
+    /// Tells the expired values apart, if they may expire. Merges turn them
+    /// into tombstones and lookups find tombstones instead.
+    pub expiry: Option<Expiry<V>>,
+    pub runs:       Vec<Arc<DiskRun<K, V>>>,
+
+    env:            Arc<dyn Env>,
+    dir:            PathBuf,
+    cmp:            Arc<dyn Comparator<K>>,
+}
+
+impl<K, V> DiskLevel<K, V>
+where
+    K: Clone + Hash + Encoding,
+    V: Clone + Encoding,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        env: Arc<dyn Env>,
+        dir: &Path,
+        page_size: usize,
+        level: usize,
+        run_size: usize,
+        run_nums: usize,
+        merge_size: usize,
+        bf_fp: f64,
+        compression: Compression,
+        cmp: Arc<dyn Comparator<K>>,
+    ) -> Self {
+        DiskLevel {
+            level,
+            page_size,
+            run_size,
+            run_nums,
+            active_run: 0,
+            merge_size,
+            bf_fp,
+            compression,
+            verify_checksums: true,
+            expiry: None,
+            runs: Vec::with_capacity(run_nums),
+            env,
+            dir: dir.to_path_buf(),
+            cmp,
+        }
+    }
+
+    #[inline]
+    pub fn comparator(&self) -> &Arc<dyn Comparator<K>> {
+        &self.cmp
+    }
+
+    #[inline]
+    pub fn env(&self) -> &Arc<dyn Env> {
+        &self.env
+    }
+
+    /// Stream `pairs`, sorted by the comparator of the level with unique
+    /// keys, such as the output of a `MergeIter`, into a new run of this
+    /// level with id `run_id` holding `range_tombstones` as well. The bloom
+    /// filter is sized for `capacity` pairs. Nothing is written if there are
+    /// neither pairs nor range tombstones.
+    pub fn add_sorted<I>(
+        &mut self,
+        run_id: usize,
+        mut pairs: I,
+        range_tombstones: Vec<RangeTombstone<K>>,
+        capacity: usize,
+    ) -> Result<Option<&Arc<DiskRun<K, V>>>>
+    where
+        I: Iterator<Item = Result<KVpair<K, V>>>,
+    {
+        let first = match pairs.next() {
+            Some(kv) => Some(kv?),
+            None if range_tombstones.is_empty() => return Ok(None),
+            None => None,
+        };
+
+        let mut writer = DiskRunWriter::new(
+            Arc::clone(&self.env),
+            &self.dir,
+            capacity,
+            self.page_size,
+            self.level,
+            run_id,
+            self.bf_fp,
+            self.compression,
+            Arc::clone(&self.cmp),
+        )?;
+        for tombstone in range_tombstones {
+            writer.add_range_tombstone(tombstone);
+        }
+        if let Some(first) = first {
+            writer.add(&first)?;
+        }
+        for kv in pairs {
+            writer.add(&kv?)?;
+        }
+        self.push_run(writer.finish()?);
+        Ok(self.runs.last())
+    }
+
+    /// Write an already sorted array of pairs, with unique keys, as a new
+    /// run of this level with id `run_id`, and return it. Nothing is written
+    /// for an empty array.
+    pub fn add_run_by_array(
+        &mut self,
+        run_id: usize,
+        run_to_add: &[KVpair<K, V>],
+    ) -> Result<Option<&Arc<DiskRun<K, V>>>> {
+        if run_to_add.is_empty() {
+            return Ok(None);
+        }
+        let run = DiskRun::create(
+            Arc::clone(&self.env),
+            &self.dir,
+            run_to_add,
+            self.page_size,
+            self.level,
+            run_id,
+            self.bf_fp,
+            self.compression,
+            Arc::clone(&self.cmp),
+        )?;
+        self.push_run(run);
+        Ok(self.runs.last())
+    }
+
+    /// Append an existing run, newer than every run of the level.
+    pub fn push_run(&mut self, mut run: DiskRun<K, V>) {
+        debug_assert_eq!(run.level(), self.level);
+        run.set_verify_checksums(self.verify_checksums);
+        self.runs.push(Arc::new(run));
+        self.active_run += 1;
+    }
+
+    /// Take the `n` oldest runs out of the level. Their files are left for
+    /// the caller to remove.
+    pub fn take_runs(&mut self, n: usize) -> Vec<Arc<DiskRun<K, V>>> {
+        self.active_run -= n;
+        self.runs.drain(..n).collect()
+    }
+
+    #[inline]
+    pub fn level_full(&self) -> bool {
+        self.active_run >= self.run_nums
+    }
+
+    #[inline]
+    pub fn level_empty(&self) -> bool {
+        self.active_run == 0
+    }
+
+    /// Look `key` up in the runs of this level, newest first. The pair found
+    /// may be a tombstone, and is one if a range tombstone deletes the key
+    /// or its value has expired.
+    pub fn lookup(&self, key: &K) -> Result<Option<KVpair<K, V>>> {
+        for run in self.runs.iter().rev() {
+            if !run.is_range_deleted(key) && !run.may_contain(key) {
+                continue;
+            }
+            if let Some(mut kv) = run.lookup(key)? {
+                if let (Some(expiry), Some(value)) = (&self.expiry, &kv.value) {
+                    if expiry.is_expired(value) {
+                        kv.value = None;
+                    }
+                }
+                return Ok(Some(kv));
+            }
+        }
+        Ok(None)
+    }
+
+    /// All the pairs with `key1 <= key < key2`, newest run first. A key may
+    /// show up once per run holding it.
+    pub fn get_elements_in_range(&self, key1: &K, key2: &K) -> Result<Vec<KVpair<K, V>>> {
+        let mut elements = Vec::new();
+        for run in self.runs.iter().rev() {
+            elements.extend(run.range(key1, key2)?);
+        }
+        Ok(elements)
+    }
+
+    pub fn num_elements(&self) -> usize {
+        self.runs.iter().map(|run| run.get_capacity()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use error::Error;
+    use skiplist::run::{KVpair, RangeTombstone};
+    use skiplist::OrdComparator;
+
+    use super::{DiskLevel, MergeIter};
+    use crate::disk_run::DiskRun;
+    use crate::compression::Compression;
+    use crate::env::PosixEnv;
+    use crate::ttl::{Expiring, Expiry, ManualClock};
+
+    /// The values of the tests expire at the time they hold.
+    impl Expiring for i64 {
+        fn expires_at(&self) -> Option<u64> {
+            Some(*self as u64)
+        }
+    }
+
+    fn pairs(keys: impl Iterator<Item = i64>, value: i64) -> Vec<KVpair<i64, i64>> {
+        keys.map(|k| KVpair::new(k, value)).collect()
+    }
+
+    fn new_level(
+        dir: &Path,
+        level: usize,
+        run_size: usize,
+        run_nums: usize,
+        compression: Compression,
+    ) -> DiskLevel<i64, i64> {
+        DiskLevel::new(PosixEnv::shared(), dir, 4, level, run_size, run_nums, 2, 0.01, compression, Arc::new(OrdComparator))
+    }
+
+    fn value(level: &DiskLevel<i64, i64>, key: i64) -> Option<i64> {
+        level.lookup(&key).unwrap().and_then(|kv| kv.value)
+    }
+
+    /// Merge the oldest `merge_size` runs of `upper` into a new run of
+    /// `lower` with id `run_id`, along with their range tombstones unless
+    /// `last_level`, and delete them.
+    fn merge_down(
+        upper: &mut DiskLevel<i64, i64>,
+        lower: &mut DiskLevel<i64, i64>,
+        run_id: usize,
+        last_level: bool,
+    ) -> Arc<DiskRun<i64, i64>> {
+        let runs = upper.take_runs(upper.merge_size.min(upper.active_run));
+        let capacity = runs.iter().map(|run| run.get_capacity()).sum();
+        let inputs = runs.iter().map(|run| run.iter()).collect();
+        let merged = MergeIter::new(inputs, last_level, &OrdComparator).with_expiry(lower.expiry.clone());
+        let tombstones = match last_level {
+            true => Vec::new(),
+            false => runs.iter().flat_map(|run| run.range_tombstones().iter().cloned()).collect(),
+        };
+        let added = Arc::clone(lower.add_sorted(run_id, merged, tombstones, capacity).unwrap().unwrap());
+        for run in runs {
+            run.remove().unwrap();
+        }
+        added
+    }
+
+    fn merge(inputs: Vec<Vec<KVpair<i64, i64>>>, drop_tombstones: bool) -> Vec<KVpair<i64, i64>> {
+        let inputs = inputs.into_iter().map(|input| input.into_iter().map(Ok)).collect();
+        MergeIter::new(inputs, drop_tombstones, &OrdComparator).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn merge_newest_wins() {
+        let merged = merge(vec![pairs(0..6, 1), pairs(2..4, 2), pairs(3..8, 3)], false);
+        let expected: Vec<_> = pairs(0..2, 1).into_iter().chain(pairs(2..3, 2)).chain(pairs(3..8, 3)).collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn merge_drops_shadowed_tombstones() {
+        let old = vec![KVpair::tombstone(1), KVpair::new(2, 0), KVpair::tombstone(3)];
+        let new = vec![KVpair::new(1, 1), KVpair::tombstone(2)];
+        let merged = merge(vec![old.clone(), new.clone()], false);
+        assert_eq!(merged, vec![KVpair::new(1, 1), KVpair::tombstone(2), KVpair::tombstone(3)]);
+
+        let merged = merge(vec![old, new], true);
+        assert_eq!(merged, vec![KVpair::new(1, 1)]);
+    }
+
+    #[test]
+    fn merge_stops_on_error() {
+        let broken = vec![Ok(KVpair::new(0, 0)), Err(Error::Io(io::Error::other("bad page")))];
+        let inputs = vec![pairs(1..3, 1).into_iter().map(Ok).collect::<Vec<_>>(), broken];
+        let mut merged = MergeIter::new(inputs.into_iter().map(|input| input.into_iter()).collect(), false, &OrdComparator);
+        assert!(merged.next().unwrap().is_err());
+        assert!(merged.next().is_none());
+    }
+
+    #[test]
+    fn lookup_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut level = new_level(dir.path(), 0, 10, 3, Compression::Lz4);
+        level.add_run_by_array(0, &pairs(0..10, 1)).unwrap();
+        level.add_run_by_array(1, &pairs(5..15, 2)).unwrap();
+        assert_eq!(value(&level, 0), Some(1));
+        assert_eq!(value(&level, 7), Some(2));
+        assert_eq!(value(&level, 20), None);
+        assert_eq!(level.num_elements(), 20);
+        assert_eq!(level.get_elements_in_range(&8, &12).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn merge_into_next_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut level0 = new_level(dir.path(), 0, 10, 3, Compression::Lz4);
+        let mut level1 = new_level(dir.path(), 1, 20, 3, Compression::Lz4);
+        level0.add_run_by_array(0, &pairs(0..10, 1)).unwrap();
+        level0.add_run_by_array(1, &pairs(5..15, 2)).unwrap();
+        level0.add_run_by_array(2, &pairs(20..30, 3)).unwrap();
+        assert!(level0.level_full());
+
+        let run = merge_down(&mut level0, &mut level1, 3, false);
+        assert_eq!(run.path(), dir.path().join("C_1_3.txt"));
+
+        assert_eq!(level0.active_run, 1);
+        assert_eq!(level0.runs[0].run_id(), 2);
+        assert!(!dir.path().join("C_0_0.txt").exists());
+        assert!(dir.path().join("C_0_2.txt").exists());
+        assert_eq!(level1.num_elements(), 15);
+        assert_eq!(value(&level1, 3), Some(1));
+        assert_eq!(value(&level1, 12), Some(2));
+        assert_eq!(value(&level0, 25), Some(3));
+    }
+
+    #[test]
+    fn range_tombstones_hide_older_pairs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut level0 = new_level(dir.path(), 0, 10, 2, Compression::None);
+        let mut level1 = new_level(dir.path(), 1, 20, 2, Compression::None);
+        level0.add_run_by_array(0, &pairs(0..20, 1)).unwrap();
+        let newer = pairs(30..32, 2).into_iter().map(Ok);
+        level0.add_sorted(1, newer, vec![RangeTombstone::new(4, 12)], 2).unwrap();
+        assert!(level0.lookup(&5).unwrap().unwrap().is_tombstone());
+        assert_eq!(value(&level0, 3), Some(1));
+
+        merge_down(&mut level0, &mut level1, 2, false);
+        assert_eq!(level1.runs[0].range_tombstones(), &[RangeTombstone::new(4, 12)]);
+        assert!(level1.lookup(&5).unwrap().unwrap().is_tombstone());
+        assert_eq!(value(&level1, 12), Some(1));
+        assert_eq!(value(&level1, 31), Some(2));
+    }
+
+    #[test]
+    fn tombstones_dropped_on_last_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut level0 = new_level(dir.path(), 0, 10, 2, Compression::Lz4);
+        let mut level1 = new_level(dir.path(), 1, 20, 2, Compression::Lz4);
+        let mut level2 = new_level(dir.path(), 2, 40, 2, Compression::Zstd(3));
+        level0.add_run_by_array(0, &pairs(0..4, 1)).unwrap();
+        level0.add_run_by_array(1, &[KVpair::tombstone(1), KVpair::tombstone(9)]).unwrap();
+
+        merge_down(&mut level0, &mut level1, 2, false);
+        assert!(level1.lookup(&1).unwrap().unwrap().is_tombstone());
+        assert!(level1.lookup(&9).unwrap().unwrap().is_tombstone());
+        assert_eq!(level1.num_elements(), 5);
+
+        merge_down(&mut level1, &mut level2, 3, true);
+        assert!(level2.lookup(&1).unwrap().is_none());
+        assert!(level2.lookup(&9).unwrap().is_none());
+        assert_eq!(level2.num_elements(), 3);
+    }
+
+    #[test]
+    fn expired_pairs_become_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(10));
+        let mut levels: Vec<_> = (0..3).map(|level| new_level(dir.path(), level, 10, 2, Compression::Lz4)).collect();
+        for level in levels.iter_mut() {
+            level.expiry = Some(Expiry::new(clock.clone()));
+        }
+        levels[0].add_run_by_array(0, &pairs(0..4, 100)).unwrap();
+        levels[0].add_run_by_array(1, &pairs(2..4, 20)).unwrap();
+        assert_eq!(value(&levels[0], 2), Some(20));
+
+        clock.set(50);
+        assert!(levels[0].lookup(&2).unwrap().unwrap().is_tombstone());
+        assert_eq!(value(&levels[0], 0), Some(100));
+
+        // the expired pairs still hide the older values of their keys.
+        let (upper, lower) = levels.split_at_mut(1);
+        merge_down(&mut upper[0], &mut lower[0], 2, false);
+        assert_eq!(lower[0].num_elements(), 4);
+        assert!(lower[0].lookup(&3).unwrap().unwrap().is_tombstone());
+
+        let (upper, lower) = levels.split_at_mut(2);
+        merge_down(&mut upper[1], &mut lower[0], 3, true);
+        assert_eq!(lower[0].num_elements(), 2);
+        assert_eq!(value(&lower[0], 1), Some(100));
+        assert!(lower[0].lookup(&2).unwrap().is_none());
+    }
 }
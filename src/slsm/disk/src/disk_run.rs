@@ -1,379 +1,982 @@
+use std::cmp::Ordering;
 use std::fmt;
-use std::convert::TryInto;
-use std::fs::remove_file;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom, Write};
-use std::iter;
+use std::hash::Hash;
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
 use std::mem;
-use std::os::unix::prelude::AsRawFd;
-use std::path::Path;
-use std::ptr;
-use core::ffi;
-
-use libc;
-use nix;
-use tempfile;
-use nix::unistd::close;
-
-use skiplist::run::KVpair;
-
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec;
+
+use bloomfilter::Bloom;
+use error::{Error, Result};
+use skiplist::run::{KVpair, RangeTombstone};
+use skiplist::Comparator;
+
+use crate::compression::Compression;
+use crate::encoding::Encoding;
+use crate::env::{Env, RandomAccessFile, WritableFile};
+use crate::format::{self, Block, BlockBuilder, BlockHandle, Footer, Meta, FOOTER_SIZE, HEADER_SIZE};
+
+/// An immutable, sorted run of key-value pairs stored in a file named
+/// `C_<level>_<run_id>.txt`, laid out as described in the `format` module.
+///
+/// Only the fence pointers, the bloom filter and the key range are kept in
+/// memory; a lookup reads the one page that may hold its key and binary
+/// searches its restart points. Dropping a run only closes its file, which
+/// is deleted by `remove`.
+///
+/// Keys are sorted by the comparator the run is written with, whose name is
+/// stored in the file and checked when the run is opened again.
+///
+/// The range tombstones of the run are stored in its meta block and kept in
+/// memory. `lookup` respects them, while `seek`, `range` and the iterators
+/// return the pairs as stored, for the callers merging several runs to
+/// apply the tombstones of all of them.
 pub struct DiskRun<K, V> {
-    pub fd: isize,
-    pub page_size: isize,
-    pub min_key: Option<KVpair<K, V>>,
-    pub max_key: Option<KVpair<K, V>>,
-    pub map: Vec<KVpair<K, V>>,
+    pub min_key: Option<K>,
+    pub max_key: Option<K>,
+    pub bf: Bloom<K>,
 
+    env: Arc<dyn Env>,
+    file: Box<dyn RandomAccessFile>,
     capacity: usize,
+    dir: PathBuf,
     filename: String,
-    level: isize,
-    fence_pointers: Vec<Option<KVpair<K, V>>>,
-    imax_fp: usize,
+    level: usize,
+    fence_pointers: Vec<K>,
+    blocks: Vec<BlockHandle>,
+    range_tombstones: Vec<RangeTombstone<K>>,
     run_id: usize,
-    bf_fp: f64,
+    cmp: Arc<dyn Comparator<K>>,
+    verify_checksums: bool,
+    _marker: PhantomData<V>,
 }
 
+impl<K, V> DiskRun<K, V>
+where
+    K: Clone + Hash + Encoding,
+    V: Clone + Encoding,
+{
+    /// Write `data`, sorted by `cmp` with unique keys, as a new run, with a
+    /// fence pointer every `page_size` pairs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        env: Arc<dyn Env>,
+        dir: &Path,
+        data: &[KVpair<K, V>],
+        page_size: usize,
+        level: usize,
+        run_id: usize,
+        bf_fp: f64,
+        compression: Compression,
+        cmp: Arc<dyn Comparator<K>>,
+    ) -> Result<Self> {
+        let mut writer = DiskRunWriter::new(env, dir, data.len(), page_size, level, run_id, bf_fp, compression, cmp)?;
+        for kv in data {
+            writer.add(kv)?;
+        }
+        writer.finish()
+    }
+
+    /// Open the run written to `C_<level>_<run_id>.txt` in `dir`, reading
+    /// back its index, bloom filter and key range. Fails if the run was
+    /// sorted by another comparator than `cmp`.
+    pub fn open(
+        env: Arc<dyn Env>,
+        dir: &Path,
+        level: usize,
+        run_id: usize,
+        cmp: Arc<dyn Comparator<K>>,
+    ) -> Result<Self> {
+        let filename = Self::filename(level, run_id);
+        let path = dir.join(&filename);
+        let file = env.open(&path)?;
+        let file_len = file.len()?;
+        if file_len < HEADER_SIZE + FOOTER_SIZE {
+            return Err(Error::corruption(path, 0, "file too short for a disk run"));
+        }
 
-impl<K, V> DiskRun<K, V> {
-    fn new(capacity: usize, page_size: usize, level: isize, run_id: isize, bf_fp: f32) -> Self {
-        let size = 1024 * 1024;
-        let _filename = "C_".to_owned() + &level.to_string() + "_" + &run_id.to_string() + ".txt";
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&_filename)
-            .expect("unable to open file.");
-
-        let mut result: i64;
-
-        // allocate space in the file first.
-        f.seek(SeekFrom::Start(size as u64)).unwrap();
-        f.write_all(&[0]).unwrap();
-        f.seek(SeekFrom::Start(0)).unwrap();
-
-        unsafe {
-            let c_void_map = libc::mmap(
-                ptr::null_mut(),
-                size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                f.as_raw_fd(),
-                0,
-            );
-
-            if c_void_map == libc::MAP_FAILED {
-                panic!("Could not access data from memory mapped file.")
-            };
-
-
-            let mut map = Vec::new();
-            let mut kv: &mut KVpair<K, V> = &mut *(c_void_map as *mut KVpair<K, V>);
-
-            map.push(*kv);
-            
-
-            let tempdir = tempfile::tempdir().unwrap();
-            let fullpath = tempdir.path().join(&_filename);
-            drop(File::create(&fullpath).unwrap());
-
-            let path = Path::new(&_filename);
-            let display = path.display();
-
-            let fd = match nix::fcntl::open(
-                &fullpath,
-                nix::fcntl::OFlag::empty(),
-                nix::sys::stat::Mode::empty(),
-            ) {
-                Err(why) => panic!("couldn't open {}: {}", display, why),
-                Ok(fd) => fd,
-            };
-
-            DiskRun {
-                fd: fd as isize,
-                filename: _filename,
-                min_key: None,
-                max_key: None,
-                map: map,
-                capacity: capacity,
-                page_size: page_size as isize,
-                level: level,
-                fence_pointers: iter::repeat(None).take(level.try_into().unwrap()).collect(),
-                imax_fp: 0,
-                run_id: 0,
-                bf_fp: 0.0,
-            }
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0)?;
+        format::check_header(&header).map_err(|e| Error::locate(e, &path, 0))?;
+        let footer_offset = file_len - FOOTER_SIZE;
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, footer_offset)?;
+        let footer = Footer::decode(&footer, file_len).map_err(|e| Error::locate(e, &path, footer_offset))?;
+
+        // the index, bloom and meta blocks are read at once.
+        let mut blocks = vec![0; (footer_offset - footer.index_offset) as usize];
+        file.read_exact_at(&mut blocks, footer.index_offset)?;
+        let (index, rest) = blocks.split_at((footer.bloom_offset - footer.index_offset) as usize);
+        let (bloom, meta) = rest.split_at((footer.meta_offset - footer.bloom_offset) as usize);
+
+        let (fence_pointers, blocks) = decode_checked(&path, footer.index_offset, index, format::decode_index)?;
+        let bf = decode_checked(&path, footer.bloom_offset, bloom, format::decode_bloom)?;
+        let meta: Meta<K> = decode_checked(&path, footer.meta_offset, meta, format::decode_meta)?;
+        let data_end = blocks.last().map_or(HEADER_SIZE, |b| b.offset + b.len as u64);
+        if (meta.entries == 0) != blocks.is_empty() || data_end > footer.index_offset {
+            return Err(Error::corruption(path, footer.index_offset, "index does not match the data"));
         }
+        if meta.comparator != cmp.name() {
+            return Err(Error::InvalidArgument(format!(
+                "{} is sorted by comparator {}, not {}",
+                path.display(),
+                meta.comparator,
+                cmp.name()
+            )));
+        }
+
+        Ok(DiskRun {
+            min_key: meta.min_key,
+            max_key: meta.max_key,
+            bf,
+            env,
+            file,
+            capacity: meta.entries as usize,
+            dir: dir.to_path_buf(),
+            filename,
+            level,
+            fence_pointers,
+            blocks,
+            range_tombstones: meta.range_tombstones,
+            run_id,
+            cmp,
+            verify_checksums: true,
+            _marker: PhantomData,
+        })
     }
 
     #[inline]
-    fn set_capacity(&mut self, new_capacity: usize) {
-        self.capacity = new_capacity;
+    pub fn filename(level: usize, run_id: usize) -> String {
+        "C_".to_owned() + &level.to_string() + "_" + &run_id.to_string() + ".txt"
+    }
+
+    /// The level and run id of a file named by `filename`.
+    pub fn parse_filename(name: &str) -> Option<(usize, usize)> {
+        let name = name.strip_prefix("C_")?.strip_suffix(".txt")?;
+        let (level, run_id) = name.split_once('_')?;
+        Some((level.parse().ok()?, run_id.parse().ok()?))
     }
 
     #[inline]
-    fn get_capacity(self) -> usize {
-        return self.capacity;
-    }
-
-    fn write_data(&mut self, run: &mut Vec<KVpair<K, V>>, offset: usize, len: usize) {
-        // todo cover self.map to String
-        unsafe {
-            ptr::copy_nonoverlapping(
-                &self.map as *const Vec<KVpair<K, V>>,
-                run,
-                &self.filename.len() + offset,
-            );
-        }
-        self.capacity = len
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(&self.filename)
     }
 
-    fn construct_index(&mut self) {
-        self.fence_pointers.reverse();
+    #[inline]
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
 
-        let mut max_fp = 0;
-        let mut j: usize = 0;
-        while j < self.capacity {
-            j += 1;
-            // todo init bloom
-            // self.bf.
-            if j % (self.page_size as usize) == 0 {
-                self.fence_pointers.push(self.map.get(j).unwrap());
-                max_fp += 1;
-            }
+    #[inline]
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    #[inline]
+    pub fn run_id(&self) -> usize {
+        self.run_id
+    }
+
+    #[inline]
+    pub fn comparator(&self) -> &Arc<dyn Comparator<K>> {
+        &self.cmp
+    }
+
+    #[inline]
+    pub fn range_tombstones(&self) -> &[RangeTombstone<K>] {
+        &self.range_tombstones
+    }
+
+    /// Whether a range tombstone of the run deletes `key`.
+    pub fn is_range_deleted(&self, key: &K) -> bool {
+        self.range_tombstones.iter().any(|tombstone| self.cmp.covers(tombstone, key))
+    }
+
+    /// Whether the checksums of the data blocks are verified when they are
+    /// read, which they are unless turned off. The other blocks are always
+    /// verified when the run is opened.
+    #[inline]
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+    }
+
+    /// Delete the file of the run. Handles on the run keep reading the
+    /// deleted file until they are dropped.
+    pub fn remove(&self) -> Result<()> {
+        Ok(self.env.remove_file(&self.path())?)
+    }
+
+    /// Read the data block of page `page`, still compressed.
+    fn read_block(&self, page: usize) -> io::Result<Vec<u8>> {
+        let block = self.blocks[page];
+        let mut data = vec![0; block.len as usize];
+        self.file.read_exact_at(&mut data, block.offset)?;
+        Ok(data)
+    }
+
+    /// Read the data block of page `page` and decode it with `decode`,
+    /// reporting a block that fails to decode as a `Corruption`.
+    fn decode_page<T>(&self, page: usize, decode: impl FnOnce(Block<'_>) -> io::Result<T>) -> Result<T> {
+        let data = self.read_block(page)?;
+        format::decompress_block(&data, self.verify_checksums)
+            .and_then(|contents| decode(Block::new(&contents)?))
+            .map_err(|e| Error::locate(e, &self.path(), self.blocks[page].offset))
+    }
+
+    /// Read and decode the pairs of page `page`.
+    fn read_page(&self, page: usize) -> Result<Vec<KVpair<K, V>>> {
+        self.decode_page(page, |block| block.entries())
+    }
+
+    /// The page that has to contain `key` if the run holds it.
+    fn get_flanking_fp(&self, key: &K) -> Option<usize> {
+        match self.fence_pointers.partition_point(|fp| self.cmp.compare(fp, key) != Ordering::Greater) {
+            0 => None,
+            page => Some(page - 1),
         }
+    }
 
-        if max_fp >= 0 {
-            
-            self.fence_pointers.resize(max_fp as usize + 1, None);
+    /// Whether `key` is within the key range of the run and passes its
+    /// bloom filter.
+    pub fn may_contain(&self, key: &K) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(min_key), Some(max_key))
+                if self.cmp.compare(min_key, key) != Ordering::Greater
+                    && self.cmp.compare(key, max_key) != Ordering::Greater => {}
+            _ => return false,
         }
+        self.bf.check_with(|state| self.cmp.hash_key(key, state))
+    }
 
-       self.min_key = Some(self.map[0]);
-       self.max_key = Some(self.map[self.capacity - 1]);
+    /// The pair stored for `key`, which may be a tombstone. A key deleted by
+    /// a range tombstone of the run reads as a tombstone, whether the run
+    /// holds it or not.
+    pub fn lookup(&self, key: &K) -> Result<Option<KVpair<K, V>>> {
+        if self.is_range_deleted(key) {
+            return Ok(Some(KVpair::tombstone(key.clone())));
+        }
+        let page = match self.get_flanking_fp(key) {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let kv = self.decode_page(page, |block| block.seek(|found| self.cmp.compare(found, key)))?;
+        Ok(kv.filter(|kv| self.cmp.compare(kv.key.as_ref().unwrap(), key) == Ordering::Equal))
     }
 
-    fn binary_search(&mut self, offset: usize, n: usize, key: KVpair<K, V>, found: bool) -> usize {
-        let min = offset;
+    /// The first pair whose key is not before `key`, which may be a
+    /// tombstone. It is on the page of `key`, or starts the next one.
+    pub fn seek(&self, key: &K) -> Result<Option<KVpair<K, V>>> {
+        match &self.max_key {
+            Some(max_key) if self.cmp.compare(key, max_key) != Ordering::Greater => {}
+            _ => return Ok(None),
+        }
+        let first = self.get_flanking_fp(key).unwrap_or(0);
+        for page in first..self.blocks.len() {
+            if let Some(kv) = self.decode_page(page, |block| block.seek(|found| self.cmp.compare(found, key)))? {
+                return Ok(Some(kv));
+            }
+        }
+        Ok(None)
+    }
 
-        while n == 0 {
-            found = true;
-            return offset;
+    /// The pairs with `key1 <= key < key2`, in key order.
+    pub fn range(&self, key1: &K, key2: &K) -> Result<Vec<KVpair<K, V>>> {
+        let mut ranged = Vec::new();
+        let cmp = &*self.cmp;
+        let in_range = |key: &K| cmp.compare(key1, key) != Ordering::Greater && cmp.compare(key, key2) == Ordering::Less;
+        match (&self.min_key, &self.max_key) {
+            (Some(min_key), Some(max_key))
+                if cmp.compare(key1, max_key) != Ordering::Greater
+                    && cmp.compare(min_key, key2) == Ordering::Less
+                    && cmp.compare(key1, key2) == Ordering::Less => {}
+            _ => return Ok(ranged),
         }
 
-        let mut min = offset;
-        let mut max = offset + n - 1;
-        let mut middle = (min + max) >> 1;
-        while min <= max {
-            if key > self.map[middle].unwrap() {
-                min = middle + 1;
-            } else if key == self.map[middle].unwrap() {
-                found = true;
-                return middle;
-            } else {
-                max = middle - 1;
-                middle = (min + max) >> 1;
+        let first = self.get_flanking_fp(key1).unwrap_or(0);
+        for page in first..self.blocks.len() {
+            if cmp.compare(&self.fence_pointers[page], key2) != Ordering::Less {
+                break;
             }
+            ranged.extend(self.read_page(page)?.into_iter().filter(|kv| in_range(kv.key.as_ref().unwrap())));
+        }
+        Ok(ranged)
+    }
+
+    /// All the pairs of the run in key order, read one page at a time.
+    #[inline]
+    pub fn iter(&self) -> DiskRunIter<'_, K, V> {
+        DiskRunIter {
+            run: self,
+            page: 0,
+            entries: Vec::new().into_iter(),
+            covered: None,
         }
+    }
 
-        return min;
+    /// The pairs of the run in key order, as `iter` returns them, but for
+    /// the pages `covered` finds deleted, which are skipped without being
+    /// read. It is given the first key of a page and a key that none of the
+    /// keys of the page comes after, and has to tell whether every key in
+    /// between is deleted.
+    /// A run it finds deleted from its first key to its last one is skipped
+    /// as a whole.
+    pub fn iter_uncovered<'a, F>(&'a self, covered: F) -> DiskRunIter<'a, K, V>
+    where
+        F: Fn(&K, &K) -> bool + 'a,
+    {
+        let page = match (&self.min_key, &self.max_key) {
+            (Some(min_key), Some(max_key)) if covered(min_key, max_key) => self.blocks.len(),
+            _ => 0,
+        };
+        DiskRunIter {
+            page,
+            covered: Some(Box::new(covered)),
+            ..self.iter()
+        }
     }
 
-    fn get_flanking_fp(&mut self, key: KVpair<K, V>, start: &usize, end: &usize) {
-        if self.imax_fp == 0 {
-            start = &(0 as usize);
-            end = &(self.capacity as usize);
-        } else if key < self.fence_pointers[1].unwrap(){
-            // todo: impl Ord for K
-            start = &(0 as usize);
-            end = &(self.page_size as usize);
-        } else if key >= self.fence_pointers[self.imax_fp].unwrap(){
-            start = &(self.imax_fp * self.page_size as usize);
-            end = &(self.capacity);
+    /// Whether the iterator of `iter_uncovered` skips page `page`.
+    fn page_covered(&self, page: usize, covered: &dyn Fn(&K, &K) -> bool) -> bool {
+        let last = match self.fence_pointers.get(page + 1) {
+            Some(next) => next,
+            None => self.max_key.as_ref().unwrap(),
+        };
+        covered(&self.fence_pointers[page], last)
+    }
+}
+
+/// Decode the block at `offset` of the file at `path` with `decode` once
+/// its checksum is verified, reporting a failure as a `Corruption`.
+fn decode_checked<'a, T>(
+    path: &Path,
+    offset: u64,
+    data: &'a [u8],
+    decode: impl FnOnce(&'a [u8]) -> io::Result<T>,
+) -> Result<T> {
+    format::strip_checksum(data, true)
+        .and_then(decode)
+        .map_err(|e| Error::locate(e, path, offset))
+}
+
+impl<K, V> fmt::Display for DiskRun<K, V>
+where
+    K: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some(min_key), Some(max_key)) = (&self.min_key, &self.max_key) {
+            write!(f, "({}, {})", min_key, max_key)
         } else {
-            let mut min:usize = 0;
-            let mut max:usize = self.imax_fp;
-            while min < max {
-                let middle: usize = (min + max) >> 1;
-
-                if key > self.fence_pointers[middle]{
-                    if key < self.fence_pointers[middle + 1].unwrap() {
-                        start = &(middle * self.page_size as usize);
-                        end = &((middle + 1) * self.page_size as usize);
-                        return
-                    }
-                    min = middle + 1;
+            Ok(())
+        }
+    }
+}
+
+/// Iterator over the pairs of a `DiskRun`, returned by `DiskRun::iter` and
+/// `DiskRun::iter_uncovered`. It stops after the first error.
+pub struct DiskRunIter<'a, K, V> {
+    run: &'a DiskRun<K, V>,
+    page: usize,
+    entries: vec::IntoIter<KVpair<K, V>>,
+    /// Tells the pages to skip.
+    #[allow(clippy::type_complexity)]
+    covered: Option<Box<dyn Fn(&K, &K) -> bool + 'a>>,
+}
+
+impl<'a, K, V> Iterator for DiskRunIter<'a, K, V>
+where
+    K: Clone + Hash + Encoding,
+    V: Clone + Encoding,
+{
+    type Item = Result<KVpair<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(kv) = self.entries.next() {
+                return Some(Ok(kv));
+            }
+            if self.page == self.run.blocks.len() {
+                return None;
+            }
+            if let Some(covered) = &self.covered {
+                if self.run.page_covered(self.page, &**covered) {
+                    self.page += 1;
+                    continue;
                 }
-                else if key < self.fence_pointers[middle] {
-                    if key >= self.fence_pointers[middle - 1].unwrap(){
-                        start = &((middle - 1) * self.page_size as usize);
-                        end = &(middle * self.page_size as usize);
-                        return
-                    }
-                    max = middle - 1;
+            }
+            match self.run.read_page(self.page) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.page += 1;
                 }
-
-                else {
-                    start = &(middle * self.page_size as usize);
-                    end = start;
-                    return; 
+                Err(e) => {
+                    self.page = self.run.blocks.len();
+                    return Some(Err(e));
                 }
             }
         }
     }
+}
 
-    fn get_index(&mut self, key: KVpair<K, V>, found: &bool) -> usize {
-        let mut start: usize;
-        let mut end: usize;
-        self.get_flanking_fp(*key, &start, &end);
-        let mut ret: usize = self.binary_search(start, end-start, key, *found);
-        ret
-
-    }
+/// Streams pairs, sorted by the comparator of the run with unique keys, into
+/// a new disk run file.
+///
+/// Pairs are buffered one page at a time, in a prefix-compressed block
+/// which is then compressed by the codec of the writer; the index, the
+/// bloom filter and the footer are written by `finish`, which syncs the file
+/// and opens the run for reading.
+pub struct DiskRunWriter<K, V> {
+    env: Arc<dyn Env>,
+    file: BufWriter<Box<dyn WritableFile>>,
+    dir: PathBuf,
+    level: usize,
+    run_id: usize,
+    page_size: usize,
+    offset: u64,
+    page: Vec<u8>,
+    block: BlockBuilder,
+    compression: Compression,
+    fence_pointers: Vec<K>,
+    blocks: Vec<BlockHandle>,
+    bf: Bloom<K>,
+    entries: u64,
+    min_key: Option<K>,
+    max_key: Option<K>,
+    range_tombstones: Vec<RangeTombstone<K>>,
+    cmp: Arc<dyn Comparator<K>>,
+    _marker: PhantomData<V>,
+}
 
-    fn lookup(&self, key: KVpair<K, V>, found: &bool) -> Option<&KVpair<K, V>> {
-        let mut idx: usize = self.get_index(key, found);
-        let ret: V = self.map[idx].unwrap();
-        if Some(ret) == true{
-            return ret 
+impl<K, V> DiskRunWriter<K, V>
+where
+    K: Clone + Hash + Encoding,
+    V: Clone + Encoding,
+{
+    /// Create the file of run `run_id` of `level`, sizing the bloom filter
+    /// for `capacity` pairs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        env: Arc<dyn Env>,
+        dir: &Path,
+        capacity: usize,
+        page_size: usize,
+        level: usize,
+        run_id: usize,
+        bf_fp: f64,
+        compression: Compression,
+        cmp: Arc<dyn Comparator<K>>,
+    ) -> Result<Self> {
+        if page_size == 0 {
+            return Err(Error::invalid_argument("pages must hold at least one pair"));
         }
-
+        let bf = Bloom::new_for_fp_rate(capacity.max(1), bf_fp)?;
+        let file = env.create(&dir.join(DiskRun::<K, V>::filename(level, run_id)))?;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        format::encode_header(&mut header);
+        let mut file = BufWriter::new(file);
+        file.write_all(&header)?;
+
+        Ok(DiskRunWriter {
+            env,
+            file,
+            dir: dir.to_path_buf(),
+            level,
+            run_id,
+            page_size,
+            offset: HEADER_SIZE,
+            page: Vec::new(),
+            block: BlockBuilder::new(format::RESTART_INTERVAL),
+            compression,
+            fence_pointers: Vec::new(),
+            blocks: Vec::new(),
+            bf,
+            entries: 0,
+            min_key: None,
+            max_key: None,
+            range_tombstones: Vec::new(),
+            cmp,
+            _marker: PhantomData,
+        })
     }
 
-    fn range(&self, key1: KVpair<K, V>, key2: KVpair<K, V>, i1: &usize, i2: &usize) {
-
-        let mut i1: usize = 0;
-        let mut i2: usize = 0;
-        let mut found: bool;
-        // todo impl PartialOrd for KVpair
-        if key1 > self.max_key.unwrap() || key2 < self.min_key.unwrap() {
-            return
-        }
-        if key1 >= self.min_key.unwrap() {
-            found = true;
-            i1 = self.get_index(key1, &found);
+    /// Append a pair, whose key must be greater than every key added so far.
+    pub fn add(&mut self, kv: &KVpair<K, V>) -> Result<()> {
+        let key = match kv.key {
+            Some(ref key) => key,
+            None => return Err(Error::invalid_argument("disk run pair without a key")),
+        };
+        debug_assert!(
+            self.max_key.as_ref().is_none_or(|max_key| self.cmp.compare(max_key, key) == Ordering::Less),
+            "keys out of order"
+        );
+        if self.block.is_empty() {
+            self.fence_pointers.push(key.clone());
         }
-        if key2 > (self.max_key.unwrap()) {
-            i2 = self.capacity;
-            return
-        } else {
-            found = false;
-            i2 = self.get_index(key2, &found)
+        self.block.add(kv);
+
+        let cmp = &self.cmp;
+        self.bf.set_with(|state| cmp.hash_key(key, state));
+        if self.min_key.is_none() {
+            self.min_key = Some(key.clone());
         }
+        self.max_key = Some(key.clone());
+        self.entries += 1;
 
+        if self.block.len() == self.page_size {
+            self.flush_page()?;
+        }
+        Ok(())
     }
 
+    /// Record a range tombstone of the run. Range tombstones may be added in
+    /// any order, before, between or after the pairs.
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone<K>) {
+        self.range_tombstones.push(tombstone);
+    }
 
-    fn do_map(&mut self) {
+    /// Number of pairs added so far.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries as usize
+    }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
 
-        let size = 1024 * 1024;
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&self.filename)
-            .expect("unable to open file.");
+    fn flush_page(&mut self) -> Result<()> {
+        self.block.finish(&mut self.page);
+        format::compress_block(&mut self.page, self.compression)?;
+        self.blocks.push(BlockHandle {
+            offset: self.offset,
+            len: self.page.len() as u32,
+        });
+        Ok(self.write_block()?)
+    }
 
-        let filesize:usize = self.capacity * mem::size_of::<KVpair<K, V>>();
+    /// Write out the buffered block, advancing the file offset.
+    fn write_block(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.page)?;
+        self.offset += self.page.len() as u64;
+        self.page.clear();
+        Ok(())
+    }
 
-        let tempdir = tempfile::tempdir().unwrap();
-        let fullpath = tempdir.path().join(self.filename);
+    /// Write the index, bloom filter, meta block and footer, sync the file
+    /// and open the finished run.
+    pub fn finish(mut self) -> Result<DiskRun<K, V>> {
+        if !self.block.is_empty() {
+            self.flush_page()?;
+        }
 
-        let path = Path::new(&self.filename);
-        let display = path.display();
+        let index_offset = self.offset;
+        format::encode_index(&mut self.page, &self.fence_pointers, &self.blocks);
+        format::append_checksum(&mut self.page, 0);
+        self.write_block()?;
+        let bloom_offset = self.offset;
+        format::encode_bloom(&mut self.page, &self.bf);
+        format::append_checksum(&mut self.page, 0);
+        self.write_block()?;
+        let meta_offset = self.offset;
+        // the key range spans the range tombstones too.
+        let cmp = &self.cmp;
+        for tombstone in self.range_tombstones.iter() {
+            if self.min_key.as_ref().is_none_or(|min_key| cmp.compare(&tombstone.start, min_key) == Ordering::Less) {
+                self.min_key = Some(tombstone.start.clone());
+            }
+            if self.max_key.as_ref().is_none_or(|max_key| cmp.compare(&tombstone.end, max_key) == Ordering::Greater) {
+                self.max_key = Some(tombstone.end.clone());
+            }
+        }
+        let meta = Meta {
+            comparator: self.cmp.name().to_owned(),
+            entries: self.entries,
+            range_tombstones: mem::take(&mut self.range_tombstones),
+            min_key: self.min_key.take(),
+            max_key: self.max_key.take(),
+        };
+        format::encode_meta(&mut self.page, &meta);
+        format::append_checksum(&mut self.page, 0);
+        let meta_len = self.page.len() as u64;
+        self.write_block()?;
+        let footer = Footer {
+            index_offset,
+            bloom_offset,
+            meta_offset,
+            meta_len,
+        };
+        footer.encode(&mut self.page);
+        self.write_block()?;
+
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync()?;
+
+        let filename = DiskRun::<K, V>::filename(self.level, self.run_id);
+        Ok(DiskRun {
+            min_key: meta.min_key,
+            max_key: meta.max_key,
+            bf: self.bf,
+            file: self.env.open(&self.dir.join(&filename))?,
+            env: self.env,
+            capacity: self.entries as usize,
+            dir: self.dir,
+            filename,
+            level: self.level,
+            fence_pointers: self.fence_pointers,
+            blocks: self.blocks,
+            range_tombstones: meta.range_tombstones,
+            run_id: self.run_id,
+            cmp: self.cmp,
+            verify_checksums: true,
+            _marker: PhantomData,
+        })
+    }
+}
 
-        let fd = match nix::fcntl::open(
-                &fullpath,
-                nix::fcntl::OFlag::empty(),
-                nix::sys::stat::Mode::empty(),
-            ) {
-                Err(why) => panic!("couldn't open {}: {}", display, why),
-                Ok(fd) => fd,
-            };
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::fs;
+    use std::hash::Hash;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use error::{Corruption, Error};
+    use skiplist::run::{KVpair, RangeTombstone};
+    use skiplist::{Comparator, OrdComparator};
+
+    use super::{DiskRun, DiskRunWriter};
+    use crate::compression::Compression;
+    use crate::encoding::Encoding;
+    use crate::env::{Env, PosixEnv};
+    use crate::mem_env::MemEnv;
+    use crate::format::HEADER_SIZE;
+
+    struct Descending;
+
+    impl Comparator<i64> for Descending {
+        fn name(&self) -> &str {
+            "descending"
+        }
 
+        fn compare(&self, a: &i64, b: &i64) -> Ordering {
+            b.cmp(a)
+        }
+    }
 
+    /// Write `data` as run `run_id` of `level` in `dir`.
+    fn create<K, V>(
+        dir: &Path,
+        data: &[KVpair<K, V>],
+        page_size: usize,
+        level: usize,
+        run_id: usize,
+        compression: Compression,
+        cmp: Arc<dyn Comparator<K>>,
+    ) -> error::Result<DiskRun<K, V>>
+    where
+        K: Clone + Hash + Encoding,
+        V: Clone + Encoding,
+    {
+        DiskRun::create(PosixEnv::shared(), dir, data, page_size, level, run_id, 0.01, compression, cmp)
+    }
 
-         unsafe {
-            let c_void_map = libc::mmap(
-                ptr::null_mut(),
-                size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                f.as_raw_fd(),
-                0,
-            );
+    fn open<K, V>(dir: &Path, level: usize, run_id: usize, cmp: Arc<dyn Comparator<K>>) -> error::Result<DiskRun<K, V>>
+    where
+        K: Clone + Hash + Encoding,
+        V: Clone + Encoding,
+    {
+        DiskRun::open(PosixEnv::shared(), dir, level, run_id, cmp)
+    }
 
-            if c_void_map == libc::MAP_FAILED {
-                panic!("Could not access data from memory mapped file.")
-            };
+    fn pairs(keys: impl Iterator<Item = i64>) -> Vec<KVpair<i64, i64>> {
+        keys.map(|k| KVpair::new(k, k * 10)).collect()
+    }
 
+    #[test]
+    fn lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = pairs((0..100).map(|k| k * 2));
+        let run = create(dir.path(), &data, 8, 0, 0, Compression::None, Arc::new(OrdComparator)).unwrap();
 
-            let mut map = Vec::new();
-            let mut kv: &mut KVpair<K, V> = &mut *(c_void_map as *mut KVpair<K, V>);
+        assert!(run.path().exists());
+        for k in 0..100 {
+            assert_eq!(run.lookup(&(k * 2)).unwrap(), Some(KVpair::new(k * 2, k * 20)));
+            assert_eq!(run.lookup(&(k * 2 + 1)).unwrap(), None);
+        }
+        assert_eq!(run.lookup(&-1).unwrap(), None);
+        assert_eq!(run.min_key, Some(0));
+        assert_eq!(run.max_key, Some(198));
+    }
 
-            map.push(*kv);
+    #[test]
+    fn seek() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = pairs((0..100).map(|k| k * 2));
+        let run = create(dir.path(), &data, 8, 0, 0, Compression::None, Arc::new(OrdComparator)).unwrap();
 
-            let tempdir = tempfile::tempdir().unwrap();
-            let fullpath = tempdir.path().join(self.filename);
-            drop(File::create(&fullpath).unwrap());
+        for k in 0..100 {
+            assert_eq!(run.seek(&(k * 2)).unwrap(), Some(KVpair::new(k * 2, k * 20)));
+            assert_eq!(run.seek(&(k * 2 - 1)).unwrap(), Some(KVpair::new(k * 2, k * 20)));
+        }
+        // the page of 15 ends with 14, so 16 starts the next one.
+        assert_eq!(run.seek(&15).unwrap(), Some(KVpair::new(16, 160)));
+        assert_eq!(run.seek(&-10).unwrap(), Some(KVpair::new(0, 0)));
+        assert_eq!(run.seek(&199).unwrap(), None);
+    }
 
-            let path = Path::new(&self.filename);
-            let display = path.display();
+    #[test]
+    fn lookup_tombstone() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![KVpair::new(1, 1), KVpair::tombstone(2), KVpair::new(3, 3)];
+        let run = create(dir.path(), &data, 2, 0, 0, Compression::None, Arc::new(OrdComparator)).unwrap();
 
-            let fd = match nix::fcntl::open(
-                &fullpath,
-                nix::fcntl::OFlag::empty(),
-                nix::sys::stat::Mode::empty(),
-            ) {
-                Err(why) => panic!("couldn't open {}: {}", display, why),
-                Ok(fd) => fd,
-            };            
-    };
+        assert!(run.lookup(&2).unwrap().unwrap().is_tombstone());
+        assert!(!run.lookup(&3).unwrap().unwrap().is_tombstone());
+    }
 
-}
+    #[test]
+    fn range() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = pairs(0..50);
+        let run = create(dir.path(), &data, 4, 0, 0, Compression::None, Arc::new(OrdComparator)).unwrap();
+
+        assert_eq!(run.range(&10, &20).unwrap(), data[10..20]);
+        assert_eq!(run.range(&-5, &3).unwrap(), data[0..3]);
+        assert_eq!(run.range(&45, &100).unwrap(), data[45..50]);
+        assert!(run.range(&60, &100).unwrap().is_empty());
+        assert_eq!(run.iter().map(Result::unwrap).collect::<Vec<_>>(), data);
+    }
 
-    fn do_unmap(&mut self) {
-        let filesize:usize = self.capacity * mem::size_of::<KVpair<K, V>>();
+    #[test]
+    fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<KVpair<String, String>> = (0..30)
+            .map(|k| KVpair::new(format!("key{:03}", k), "v".repeat(k)))
+            .chain(Some(KVpair::tombstone("key100".to_owned())))
+            .collect();
+        let run = create(dir.path(), &data, 4, 2, 1, Compression::None, Arc::new(OrdComparator)).unwrap();
+
+        let reopened: DiskRun<String, String> = open(dir.path(), 2, 1, Arc::new(OrdComparator)).unwrap();
+        assert_eq!(reopened.get_capacity(), 31);
+        assert_eq!(reopened.min_key, run.min_key);
+        assert_eq!(reopened.max_key, run.max_key);
+        assert!(reopened.bf.check(&"key007".to_owned()));
+        assert_eq!(reopened.lookup(&"key007".to_owned()).unwrap(), Some(data[7].clone()));
+        assert!(reopened.lookup(&"key100".to_owned()).unwrap().unwrap().is_tombstone());
+        assert_eq!(reopened.iter().map(Result::unwrap).collect::<Vec<_>>(), data);
+    }
 
-        unsafe {
-            if libc::munmap(&(self.map) as *mut libc::c_void) == -1 {
-            panic!("Error unmmapping the file.");
-                       }
+    #[test]
+    fn reopen_rejects_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = create(dir.path(), &pairs(0..20), 4, 0, 0, Compression::None, Arc::new(OrdComparator)).unwrap();
+        let path = run.path();
+        let data = fs::read(&path).unwrap();
+
+        let mut bad_footer = data.clone();
+        let n = bad_footer.len();
+        bad_footer[n - 30] ^= 1;
+        fs::write(&path, &bad_footer).unwrap();
+        assert!(open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).is_err());
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] ^= 1;
+        fs::write(&path, &bad_magic).unwrap();
+        assert!(open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).is_err());
+
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).is_err());
+    }
 
-            close(self.fd as i32).unwrap(); 
+    #[test]
+    fn checksums_detect_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = create(dir.path(), &pairs(0..20), 4, 0, 0, Compression::None, Arc::new(OrdComparator))
+            .unwrap();
+        let path = run.path();
+        let mut data = fs::read(&path).unwrap();
+        // the low byte of the value of key 0, in the first page.
+        data[HEADER_SIZE as usize + 21] ^= 1;
+        fs::write(&path, &data).unwrap();
+
+        let mut run = open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).unwrap();
+        assert_eq!(run.lookup(&5).unwrap(), Some(KVpair::new(5, 50)));
+        match run.lookup(&0) {
+            Err(Error::Corruption(Corruption { path: found, offset, .. })) => {
+                assert_eq!((found, offset), (path.clone(), HEADER_SIZE))
             }
-        self.fd = -5;
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(run.iter().any(|kv| kv.is_err()));
+
+        run.set_verify_checksums(false);
+        assert_eq!(run.lookup(&0).unwrap(), Some(KVpair::new(0, 1)));
+
+        // a bad meta block, just before the footer, is found on open.
+        let meta_offset = data.len() - 60;
+        data[meta_offset] ^= 1;
+        fs::write(&path, &data).unwrap();
+        match open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)) {
+            Err(Error::Corruption(corruption)) => assert_eq!(corruption.path, path),
+            _ => panic!("a corrupted meta block was opened"),
+        }
     }
 
-    fn double_size(&mut self) {}
-}
+    #[test]
+    fn range_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = DiskRunWriter::new(
+            PosixEnv::shared(),
+            dir.path(),
+            20,
+            4,
+            0,
+            0,
+            0.01,
+            Compression::None,
+            Arc::new(OrdComparator),
+        )
+        .unwrap();
+        writer.add_range_tombstone(RangeTombstone::new(-5, 6));
+        for kv in pairs(0..20).iter() {
+            writer.add(kv).unwrap();
+        }
+        writer.add_range_tombstone(RangeTombstone::new(15, 30));
+        let path = writer.finish().unwrap().path();
+        // the first page, with keys 0 to 3, is deleted as a whole.
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_SIZE as usize + 21] ^= 1;
+        fs::write(&path, &data).unwrap();
+
+        let run = open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).unwrap();
+        assert_eq!(run.range_tombstones(), &[RangeTombstone::new(-5, 6), RangeTombstone::new(15, 30)]);
+        assert_eq!((run.min_key, run.max_key), (Some(-5), Some(30)));
+        assert!(run.lookup(&5).unwrap().unwrap().is_tombstone());
+        assert!(run.lookup(&25).unwrap().unwrap().is_tombstone());
+        assert_eq!(run.lookup(&6).unwrap(), Some(KVpair::new(6, 60)));
+        // seeks return the pairs as stored.
+        assert_eq!(run.seek(&5).unwrap(), Some(KVpair::new(5, 50)));
+
+        assert!(run.iter().any(|kv| kv.is_err()));
+        let uncovered: Vec<_> = run
+            .iter_uncovered(|first, last| run.is_range_deleted(first) && run.is_range_deleted(last))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(uncovered, pairs(4..20));
+        assert_eq!(run.iter_uncovered(|_, _| true).count(), 0);
+    }
 
-impl<K, V> Drop for DiskRun<K, V> {
-    #[inline]
-    fn drop(&mut self) {
-        nix::unistd::fsync(*&self.fd as i32);
-        &self.do_unmap();
+    #[test]
+    fn range_tombstones_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer: DiskRunWriter<i64, i64> = DiskRunWriter::new(
+            PosixEnv::shared(),
+            dir.path(),
+            0,
+            4,
+            0,
+            0,
+            0.01,
+            Compression::None,
+            Arc::new(OrdComparator),
+        )
+        .unwrap();
+        writer.add_range_tombstone(RangeTombstone::new(10, 20));
+        writer.finish().unwrap();
+
+        let run = open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).unwrap();
+        assert_eq!(run.get_capacity(), 0);
+        assert_eq!((run.min_key, run.max_key), (Some(10), Some(20)));
+        assert!(run.lookup(&12).unwrap().unwrap().is_tombstone());
+        assert_eq!(run.lookup(&20).unwrap(), None);
+        assert_eq!(run.iter().count(), 0);
+    }
 
-        if let Err(e) = remove_file(&self.filename) {
-            panic!("failed to remove file, maybe file race? {}", e);
-        };
+    #[test]
+    fn drop_keeps_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = create(dir.path(), &pairs(0..4), 2, 1, 3, Compression::None, Arc::new(OrdComparator)).unwrap();
+        let path = run.path();
+        assert!(path.ends_with("C_1_3.txt"));
+        assert_eq!(DiskRun::<i64, i64>::parse_filename("C_1_3.txt"), Some((1, 3)));
+        drop(run);
+        assert!(path.exists());
+
+        let run = open::<i64, i64>(dir.path(), 1, 3, Arc::new(OrdComparator)).unwrap();
+        run.remove().unwrap();
+        assert!(!path.exists());
     }
-}
 
+    #[test]
+    fn prefix_compressed_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = |k: usize| format!("tenant-0042/table-orders/index-by-date/{:06}", k * 3);
+        let data: Vec<KVpair<String, u8>> = (0..1000).map(|k| KVpair::new(key(k), k as u8)).collect();
+        let run = create(dir.path(), &data, 50, 0, 0, Compression::None, Arc::new(OrdComparator)).unwrap();
+
+        let key_bytes: usize = data.iter().map(|kv| kv.key.as_ref().unwrap().len()).sum();
+        assert!((fs::metadata(run.path()).unwrap().len() as usize) < key_bytes / 2);
+        for k in (0..1000).step_by(7) {
+            assert_eq!(run.lookup(&key(k)).unwrap(), Some(data[k].clone()));
+            let missing = format!("tenant-0042/table-orders/index-by-date/{:06}", k * 3 + 1);
+            assert_eq!(run.lookup(&missing).unwrap(), None);
+        }
+        assert_eq!(run.range(&key(10), &key(20)).unwrap(), data[10..20]);
+        assert_eq!(run.iter().map(Result::unwrap).collect::<Vec<_>>(), data);
+    }
 
+    #[test]
+    fn compressed_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<KVpair<i64, String>> = (0..500).map(|k| KVpair::new(k, "pomegranate ".repeat(10))).collect();
+        let mut sizes = Vec::new();
+        for (run_id, &compression) in [Compression::None, Compression::Lz4, Compression::Zstd(3)].iter().enumerate() {
+            let run = create(dir.path(), &data, 16, 0, run_id, compression, Arc::new(OrdComparator))
+                .unwrap();
+            sizes.push(fs::metadata(run.path()).unwrap().len());
+            let reopened = open::<i64, String>(dir.path(), 0, run_id, Arc::new(OrdComparator)).unwrap();
+            assert_eq!(reopened.lookup(&123).unwrap(), Some(data[123].clone()));
+            assert_eq!(reopened.iter().map(Result::unwrap).collect::<Vec<_>>(), data);
+        }
+        assert!(sizes[1] < sizes[0] / 4);
+        assert!(sizes[2] < sizes[1]);
+    }
 
+    #[test]
+    fn custom_comparator() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = pairs((0..40).rev());
+        let run = create(dir.path(), &data, 4, 0, 0, Compression::None, Arc::new(Descending)).unwrap();
+        assert_eq!(run.min_key, Some(39));
+        assert_eq!(run.lookup(&17).unwrap(), Some(KVpair::new(17, 170)));
+        assert!(run.may_contain(&17));
+        assert!(!run.may_contain(&40));
+        assert_eq!(run.range(&30, &25).unwrap(), data[9..14]);
+
+        let err = open::<i64, i64>(dir.path(), 0, 0, Arc::new(OrdComparator)).err().unwrap();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        let reopened = open::<i64, i64>(dir.path(), 0, 0, Arc::new(Descending)).unwrap();
+        assert_eq!(reopened.iter().map(Result::unwrap).collect::<Vec<_>>(), data);
+    }
 
-impl<K, V> fmt::Display for DiskRun<K, V>
-where
-    K: fmt::Display,
-    V: fmt::Display,
-{
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // if let (&Some(ref K), &Some(ref V)) = (&self.min_key, &self.max_key) {
-            write!(f, "({}, {})", &self.min_key, &self.max_key)
-        // } else {
-            // Ok(())
-        // }
+    #[test]
+    fn in_memory_env() {
+        let mem: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let dir = Path::new("/db");
+        mem.create_dir_all(dir).unwrap();
+        let run =
+            DiskRun::create(Arc::clone(&mem), dir, &pairs(0..40), 4, 0, 0, 0.01, Compression::Lz4, Arc::new(OrdComparator))
+                .unwrap();
+        assert_eq!(mem.read_dir(dir).unwrap(), vec![DiskRun::<i64, i64>::filename(0, 0)]);
+
+        let reopened = DiskRun::<i64, i64>::open(Arc::clone(&mem), dir, 0, 0, Arc::new(OrdComparator)).unwrap();
+        assert_eq!(reopened.lookup(&17).unwrap(), Some(KVpair::new(17, 170)));
+        run.remove().unwrap();
+        assert!(mem.read_dir(dir).unwrap().is_empty());
+        // the removed file is still read through the open run.
+        assert_eq!(reopened.iter().count(), 40);
     }
 }
@@ -0,0 +1,126 @@
+use std::convert::TryInto;
+
+/// Conversion of keys and values to and from bytes, used wherever they
+/// leave memory.
+pub trait Encoding: Sized {
+    /// Append the encoded form of `self` to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decode a value from exactly `bytes`, or `None` if they are malformed.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_int_encoding {
+    ($($t:ty),*) => {
+        $(
+            impl Encoding for $t {
+                #[inline]
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                #[inline]
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    bytes.try_into().ok().map(<$t>::from_le_bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_int_encoding!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl Encoding for bool {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Encoding for char {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u32).encode(buf);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        u32::decode(bytes).and_then(char::from_u32)
+    }
+}
+
+impl<const N: usize> Encoding for [u8; N] {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+impl Encoding for Vec<u8> {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Encoding for String {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+
+    fn round_trip<T: Encoding + PartialEq + std::fmt::Debug>(value: T) {
+        let mut buf = Vec::new();
+        value.encode(&mut buf);
+        assert_eq!(T::decode(&buf), Some(value));
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(0u8);
+        round_trip(-12345i32);
+        round_trip(u64::MAX);
+        round_trip(1.5f64);
+        round_trip(true);
+        round_trip('石');
+        round_trip(*b"pomegranate");
+        round_trip(b"seeds".to_vec());
+        round_trip("石榴".to_owned());
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert_eq!(u32::decode(&[1, 2, 3]), None);
+        assert_eq!(bool::decode(&[2]), None);
+        assert_eq!(String::decode(&[0xff]), None);
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The file system of a tree. Every file of the tree, disk runs, write-ahead
+/// log and manifest, is read and written through it, so a tree can be kept
+/// in memory, or stored on a file system that fails on purpose to test how
+/// the tree survives crashes.
+///
+/// The semantics are those of POSIX: a file removed while a handle reads it
+/// is still read through that handle, and data only survives a crash once
+/// the file it was written to is synced.
+pub trait Env: Send + Sync + fmt::Debug {
+    /// Create the file at `path` for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Open the file at `path` for appending, creating it if needed.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Open the existing file at `path` for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename the file at `from` to `to`, replacing any file at `to`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Durably cut the file at `path` to its first `len` bytes.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// The names of the files in `dir`.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<String>>;
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Make the creations, renames and removals of files in `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// The whole contents of the file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path)?;
+        let mut data = vec![0; file.len()? as usize];
+        file.read_exact_at(&mut data, 0)?;
+        Ok(data)
+    }
+}
+
+/// A file written sequentially. Writes may be buffered until `flush`, and
+/// are only durable once `sync` returns.
+pub trait WritableFile: Write + Send {
+    /// Flush the data written so far to stable storage.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// A file read at arbitrary offsets, by any number of threads at once.
+pub trait RandomAccessFile: Send + Sync {
+    /// Fill `buf` with the bytes at `offset`, failing if the file ends first.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// The environment of the trees stored on the local file system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PosixEnv;
+
+impl PosixEnv {
+    /// A shared handle on the local file system, the default environment of
+    /// a tree.
+    pub fn shared() -> Arc<dyn Env> {
+        Arc::new(PosixEnv)
+    }
+}
+
+impl Env for PosixEnv {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+impl WritableFile for File {
+    #[inline]
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl RandomAccessFile for File {
+    #[inline]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[inline]
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use super::{Env, PosixEnv};
+    use crate::fault_env::FaultInjectionEnv;
+    use crate::mem_env::MemEnv;
+
+    /// Exercise the operations every environment has to support.
+    fn check_env(env: &dyn Env, dir: &std::path::Path) {
+        let sub = dir.join("sub");
+        env.create_dir_all(&sub).unwrap();
+        let path = sub.join("a");
+        let mut file = env.create(&path).unwrap();
+        file.write_all(b"hello ").unwrap();
+        file.flush().unwrap();
+        file.sync().unwrap();
+        drop(file);
+        let mut file = env.open_append(&path).unwrap();
+        file.write_all(b"world").unwrap();
+        file.flush().unwrap();
+        drop(file);
+        assert_eq!(env.read(&path).unwrap(), b"hello world");
+
+        let reader = env.open(&path).unwrap();
+        let mut buf = [0; 5];
+        reader.read_exact_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf, b"world");
+        assert!(reader.read_exact_at(&mut buf, 8).is_err());
+        assert_eq!(reader.len().unwrap(), 11);
+
+        env.truncate(&path, 5).unwrap();
+        assert_eq!(env.read(&path).unwrap(), b"hello");
+        env.rename(&path, &sub.join("b")).unwrap();
+        assert_eq!(env.read_dir(&sub).unwrap(), vec!["b".to_string()]);
+        env.sync_dir(&sub).unwrap();
+        env.remove_file(&sub.join("b")).unwrap();
+        assert!(env.read_dir(&sub).unwrap().is_empty());
+        assert_eq!(env.open(&path).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn environments() {
+        let dir = tempfile::tempdir().unwrap();
+        check_env(&PosixEnv, dir.path());
+        check_env(&MemEnv::new(), dir.path());
+        check_env(&FaultInjectionEnv::new(Arc::new(MemEnv::new())), dir.path());
+    }
+}
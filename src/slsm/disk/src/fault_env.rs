@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::env::{Env, RandomAccessFile, WritableFile};
+
+/// An environment wrapping another one to inject the faults a tree has to
+/// survive: failing writes and syncs, a full disk, data lost by a crash and
+/// corrupted bytes.
+///
+/// It tracks how much of each file it wrote is synced, so that
+/// `drop_unsynced_data` leaves the files as a machine crash would.
+#[derive(Debug)]
+pub struct FaultInjectionEnv {
+    base: Arc<dyn Env>,
+    faults: Arc<Faults>,
+    /// Synced length of the files written through this environment.
+    synced: Mutex<HashMap<PathBuf, Arc<AtomicU64>>>,
+}
+
+impl FaultInjectionEnv {
+    pub fn new(base: Arc<dyn Env>) -> Self {
+        FaultInjectionEnv {
+            base,
+            faults: Arc::new(Faults {
+                fail_writes: AtomicBool::new(false),
+                fail_syncs: AtomicBool::new(false),
+                write_budget: AtomicU64::new(u64::MAX),
+            }),
+            synced: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Make every write, sync and change of a directory fail with an I/O
+    /// error until turned off. Reads keep working.
+    pub fn set_fail_writes(&self, fail: bool) {
+        self.faults.fail_writes.store(fail, Ordering::SeqCst);
+    }
+
+    /// Make every sync of a file fail with an I/O error until turned off,
+    /// while the writes before it go through.
+    pub fn set_fail_syncs(&self, fail: bool) {
+        self.faults.fail_syncs.store(fail, Ordering::SeqCst);
+    }
+
+    /// Let only `bytes` more bytes be written to files, as if the disk then
+    /// filled up: the write crossing the limit is cut short and the next
+    /// ones fail. `None` lifts the limit.
+    pub fn set_write_budget(&self, bytes: Option<u64>) {
+        self.faults.write_budget.store(bytes.unwrap_or(u64::MAX), Ordering::SeqCst);
+    }
+
+    /// Cut every file written through this environment back to the length
+    /// it had when it was last synced, as a crash of the machine would.
+    pub fn drop_unsynced_data(&self) -> io::Result<()> {
+        for (path, synced) in self.synced.lock().unwrap().iter() {
+            let synced = synced.load(Ordering::SeqCst);
+            let len = match self.base.open(path) {
+                Ok(file) => file.len()?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if len > synced {
+                self.base.truncate(path, synced)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flip every bit of the `len` bytes at `offset` of the file at `path`,
+    /// or of as many of them as the file holds.
+    pub fn corrupt(&self, path: &Path, offset: u64, len: usize) -> io::Result<()> {
+        let mut data = self.base.read(path)?;
+        let start = (offset as usize).min(data.len());
+        let end = (start + len).min(data.len());
+        for b in data[start..end].iter_mut() {
+            *b = !*b;
+        }
+        let mut file = self.base.create(path)?;
+        file.write_all(&data)?;
+        file.flush()?;
+        file.sync()
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        check(&self.faults.fail_writes)
+    }
+
+    /// Wrap `file`, holding `len` bytes already synced, to track its syncs.
+    fn track(&self, path: &Path, file: Box<dyn WritableFile>, len: u64) -> Box<dyn WritableFile> {
+        let synced = Arc::new(AtomicU64::new(len));
+        self.synced.lock().unwrap().insert(path.to_path_buf(), Arc::clone(&synced));
+        Box::new(FaultFile {
+            file,
+            written: len,
+            synced,
+            faults: Arc::clone(&self.faults),
+        })
+    }
+}
+
+/// The faults injected, shared by the environment and its files.
+#[derive(Debug)]
+struct Faults {
+    fail_writes: AtomicBool,
+    fail_syncs: AtomicBool,
+    /// Bytes left to write before the disk is full, `u64::MAX` if unlimited.
+    write_budget: AtomicU64,
+}
+
+fn check(fail: &AtomicBool) -> io::Result<()> {
+    match fail.load(Ordering::SeqCst) {
+        true => Err(io::Error::other("injected write error")),
+        false => Ok(()),
+    }
+}
+
+impl Env for FaultInjectionEnv {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        self.check_writable()?;
+        let file = self.base.create(path)?;
+        Ok(self.track(path, file, 0))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        self.check_writable()?;
+        let file = self.base.open_append(path)?;
+        let len = self.base.open(path)?.len()?;
+        Ok(self.track(path, file, len))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        self.base.open(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        self.base.remove_file(path)?;
+        self.synced.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        self.base.rename(from, to)?;
+        let mut synced = self.synced.lock().unwrap();
+        synced.remove(to);
+        if let Some(length) = synced.remove(from) {
+            synced.insert(to.to_path_buf(), length);
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.base.truncate(path, len)?;
+        if let Some(synced) = self.synced.lock().unwrap().get(path) {
+            synced.fetch_min(len, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.base.read_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        self.base.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        self.base.sync_dir(dir)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.base.read(path)
+    }
+}
+
+/// A file written through a `FaultInjectionEnv`.
+struct FaultFile {
+    file: Box<dyn WritableFile>,
+    written: u64,
+    synced: Arc<AtomicU64>,
+    faults: Arc<Faults>,
+}
+
+impl Write for FaultFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        check(&self.faults.fail_writes)?;
+        let budget = self.faults.write_budget.load(Ordering::SeqCst);
+        if budget == 0 && !buf.is_empty() {
+            return Err(io::Error::other("injected full disk"));
+        }
+        let n = self.file.write(&buf[..budget.min(buf.len() as u64) as usize])?;
+        if budget != u64::MAX {
+            self.faults.write_budget.fetch_sub(n as u64, Ordering::SeqCst);
+        }
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        check(&self.faults.fail_writes)?;
+        self.file.flush()
+    }
+}
+
+impl WritableFile for FaultFile {
+    fn sync(&mut self) -> io::Result<()> {
+        check(&self.faults.fail_writes)?;
+        check(&self.faults.fail_syncs)?;
+        self.file.flush()?;
+        self.file.sync()?;
+        self.synced.store(self.written, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::FaultInjectionEnv;
+    use crate::env::Env;
+    use crate::mem_env::MemEnv;
+
+    #[test]
+    fn injected_faults() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        let dir = Path::new("/db");
+        env.create_dir_all(dir).unwrap();
+        let mut file = env.create(&dir.join("log")).unwrap();
+        file.write_all(b"synced").unwrap();
+        file.sync().unwrap();
+        file.write_all(b" lost").unwrap();
+        let mut other = env.create(&dir.join("tmp")).unwrap();
+        other.write_all(b"never synced").unwrap();
+        env.rename(&dir.join("tmp"), &dir.join("renamed")).unwrap();
+
+        env.drop_unsynced_data().unwrap();
+        assert_eq!(env.read(&dir.join("log")).unwrap(), b"synced");
+        assert_eq!(env.read(&dir.join("renamed")).unwrap(), b"");
+
+        env.corrupt(&dir.join("log"), 4, 10).unwrap();
+        assert_eq!(env.read(&dir.join("log")).unwrap(), b"sync\x9a\x9b");
+
+        env.set_fail_writes(true);
+        assert!(file.write_all(b"more").is_err());
+        assert!(env.create(&dir.join("other")).is_err());
+        assert!(env.remove_file(&dir.join("log")).is_err());
+        assert_eq!(env.read(&dir.join("log")).unwrap().len(), 6);
+        env.set_fail_writes(false);
+        env.remove_file(&dir.join("log")).unwrap();
+
+        env.set_fail_syncs(true);
+        file.write_all(b"written").unwrap();
+        assert!(file.sync().is_err());
+        env.set_fail_syncs(false);
+
+        let mut file = env.create(&dir.join("full")).unwrap();
+        env.set_write_budget(Some(4));
+        assert!(file.write_all(b"too long").is_err());
+        assert!(file.write_all(b"x").is_err());
+        env.set_write_budget(None);
+        file.write_all(b"!").unwrap();
+        assert_eq!(env.read(&dir.join("full")).unwrap(), b"too !");
+    }
+}
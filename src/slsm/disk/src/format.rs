@@ -0,0 +1,603 @@
+//! The layout of a disk run file, all integers being little endian:
+//!
+//! ```text
+//! header:  magic: [u8; 8] | version: u32
+//! data:    blocks of `page_size` entries, each stored as
+//!            contents, compressed by the codec of the trailer | trailer: codec: u8 | crc32c: u32
+//!          where the contents are the entries, each being
+//!            kind: u8 | shared: u32 | unshared: u32 | key suffix [| value_len: u32 | value]
+//!          then the restart array
+//!            restarts: [u32; num_restarts] | num_restarts: u32
+//! index:   pages: u32, then per page
+//!          offset: u64 | len: u32 | key_len: u32 | first key
+//!          | crc32c: u32
+//! bloom:   bits: u64 | hash_functions: u32 | sip keys: [u64; 4] | bitmap_len: u32 | bitmap
+//!          | crc32c: u32
+//! meta:    comparator_len: u32 | comparator name | entries: u64
+//!          | range_tombstones: u32, then per range tombstone
+//!            start_len: u32 | start key | end_len: u32 | end key
+//!          [| min_key_len: u32 | min key | max_key_len: u32 | max key] | crc32c: u32
+//! footer:  index_offset: u64 | bloom_offset: u64 | meta_offset: u64 | meta_len: u64
+//!          | version: u32 | magic: [u8; 8] | crc32c: u32
+//! ```
+//!
+//! The footer has a fixed size and closes the file, so a reader starts from
+//! the end of the file. Its checksum covers the rest of the footer, and the
+//! checksum closing every other block covers the rest of the block, the
+//! trailer of a data block included.
+//!
+//! The key range of the meta block spans the range tombstones as well as
+//! the entries, and is only left out of a run that has neither.
+//!
+//! The keys of a data block are prefix compressed: an entry stores the
+//! length of the prefix its encoded key shares with the key before it, then
+//! the rest of the key. Every `RESTART_INTERVAL` entries a restart point
+//! stores its whole key, and the restart array holds the offsets of these
+//! entries within the block, so a lookup binary searches the restart points
+//! before decoding a few entries.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::io;
+use std::mem;
+
+use bloomfilter::Bloom;
+use error::Result;
+use skiplist::run::{KVpair, RangeTombstone};
+
+use crate::compression::{Compression, MAX_BLOCK_SIZE};
+use crate::encoding::Encoding;
+
+pub const MAGIC: [u8; 8] = *b"pomegrnt";
+pub const VERSION: u32 = 6;
+pub const HEADER_SIZE: u64 = 12;
+pub const FOOTER_SIZE: u64 = 48;
+/// Number of entries from one restart point of a data block to the next.
+pub const RESTART_INTERVAL: usize = 16;
+
+const ENTRY_VALUE: u8 = 0;
+const ENTRY_TOMBSTONE: u8 = 1;
+
+/// Where a page of entries lies in the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// The contents of the meta block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meta<K> {
+    /// Name of the comparator the run is sorted by.
+    pub comparator: String,
+    pub entries: u64,
+    pub range_tombstones: Vec<RangeTombstone<K>>,
+    pub min_key: Option<K>,
+    pub max_key: Option<K>,
+}
+
+/// The offsets of the blocks following the data pages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footer {
+    pub index_offset: u64,
+    pub bloom_offset: u64,
+    pub meta_offset: u64,
+    pub meta_len: u64,
+}
+
+#[inline]
+pub fn corruption(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Close the block that starts at `start` in `buf` with its checksum.
+pub fn append_checksum(buf: &mut Vec<u8>, start: usize) {
+    let crc = crc32c::crc32c(&buf[start..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// The contents of a block closed by `append_checksum`, checked against
+/// the checksum unless `verify` is unset.
+pub fn strip_checksum(data: &[u8], verify: bool) -> io::Result<&[u8]> {
+    if data.len() < 4 {
+        return Err(corruption("truncated block"));
+    }
+    let (contents, crc) = data.split_at(data.len() - 4);
+    if verify && crc32c::crc32c(contents) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(corruption("block checksum mismatch"));
+    }
+    Ok(contents)
+}
+
+pub fn encode_header(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+}
+
+pub fn check_header(data: &[u8]) -> io::Result<()> {
+    let mut decoder = Decoder::new(data);
+    if decoder.take(MAGIC.len())? != MAGIC {
+        return Err(corruption("bad magic number"));
+    }
+    check_version(decoder.u32()?)
+}
+
+fn check_version(version: u32) -> io::Result<()> {
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported format version {}", version),
+        ));
+    }
+    Ok(())
+}
+
+/// Append `value` prefixed with the length of its encoding.
+pub fn encode_prefixed<T: Encoding>(buf: &mut Vec<u8>, value: &T) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    value.encode(buf);
+    let len = (buf.len() - start - 4) as u32;
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+/// Builds a data block out of pairs added in key order.
+pub struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    entries: usize,
+    last_key: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub fn new(restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart points must be at least one entry apart");
+        BlockBuilder {
+            buf: Vec::new(),
+            restarts: Vec::new(),
+            restart_interval,
+            entries: 0,
+            last_key: Vec::new(),
+            key: Vec::new(),
+        }
+    }
+
+    pub fn add<K: Encoding, V: Encoding>(&mut self, kv: &KVpair<K, V>) {
+        self.key.clear();
+        kv.key.as_ref().expect("entry without a key").encode(&mut self.key);
+        let shared = if self.entries.is_multiple_of(self.restart_interval) {
+            self.restarts.push(self.buf.len() as u32);
+            0
+        } else {
+            self.last_key.iter().zip(&self.key).take_while(|(a, b)| a == b).count()
+        };
+
+        self.buf.push(if kv.value.is_some() { ENTRY_VALUE } else { ENTRY_TOMBSTONE });
+        self.buf.extend_from_slice(&(shared as u32).to_le_bytes());
+        self.buf.extend_from_slice(&((self.key.len() - shared) as u32).to_le_bytes());
+        self.buf.extend_from_slice(&self.key[shared..]);
+        if let Some(ref value) = kv.value {
+            encode_prefixed(&mut self.buf, value);
+        }
+        mem::swap(&mut self.last_key, &mut self.key);
+        self.entries += 1;
+    }
+
+    /// Number of entries added since the last `finish`.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Append the block, closed by its restart array, to `buf` and start
+    /// the next one.
+    pub fn finish(&mut self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.buf);
+        for restart in &self.restarts {
+            buf.extend_from_slice(&restart.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        self.buf.clear();
+        self.restarts.clear();
+        self.entries = 0;
+        self.last_key.clear();
+    }
+}
+
+/// Compress the data block in `block`, unless that saves less than an
+/// eighth of its size or it is larger than `MAX_BLOCK_SIZE`, and append the
+/// trailer naming the codec used and closing the block with its checksum.
+pub fn compress_block(block: &mut Vec<u8>, compression: Compression) -> Result<()> {
+    let mut codec = Compression::None;
+    if compression != Compression::None && block.len() <= MAX_BLOCK_SIZE {
+        let compressed = compression.compress(block)?;
+        if compressed.len() < block.len() - block.len() / 8 {
+            *block = compressed;
+            codec = compression;
+        }
+    }
+    block.push(codec.id());
+    append_checksum(block, 0);
+    Ok(())
+}
+
+/// The contents of a data block written by `compress_block`, checked
+/// against its checksum unless `verify` is unset.
+pub fn decompress_block(data: &[u8], verify: bool) -> io::Result<Cow<'_, [u8]>> {
+    match strip_checksum(data, verify)?.split_last() {
+        Some((&codec, contents)) => Compression::decompress(codec, contents),
+        None => Err(corruption("truncated block")),
+    }
+}
+
+/// A data block written by `BlockBuilder`.
+pub struct Block<'a> {
+    entries: &'a [u8],
+    restarts: Vec<usize>,
+}
+
+impl<'a> Block<'a> {
+    pub fn new(data: &'a [u8]) -> io::Result<Self> {
+        if data.len() < 4 {
+            return Err(corruption("truncated block"));
+        }
+        let (data, num_restarts) = data.split_at(data.len() - 4);
+        let num_restarts = u32::from_le_bytes(num_restarts.try_into().unwrap()) as usize;
+        let restarts_len = num_restarts
+            .checked_mul(4)
+            .filter(|&len| len <= data.len())
+            .ok_or_else(|| corruption("bad restart array"))?;
+        let (entries, restarts) = data.split_at(data.len() - restarts_len);
+
+        let mut decoder = Decoder::new(restarts);
+        let restarts = (0..num_restarts)
+            .map(|_| decoder.u32().map(|offset| offset as usize))
+            .collect::<io::Result<Vec<_>>>()?;
+        let in_order = restarts.first().is_none_or(|&first| first == 0)
+            && restarts.windows(2).all(|w| w[0] < w[1])
+            && restarts.last().is_none_or(|&last| last < entries.len());
+        if !in_order || restarts.is_empty() != entries.is_empty() {
+            return Err(corruption("bad restart array"));
+        }
+        Ok(Block { entries, restarts })
+    }
+
+    /// Decode every pair of the block.
+    pub fn entries<K: Encoding, V: Encoding>(&self) -> io::Result<Vec<KVpair<K, V>>> {
+        let mut reader = EntryReader::new(self.entries);
+        let mut entries = Vec::new();
+        while !reader.decoder.is_empty() {
+            let (tombstone, key) = reader.key()?;
+            entries.push(KVpair {
+                key: Some(key),
+                value: reader.value(tombstone)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// The first pair whose key `order` does not find before the one looked
+    /// for, where `order` compares a key to it. The restart points are
+    /// binary searched for the last one not after the key, then the entries
+    /// are scanned from there.
+    pub fn seek<K, V, F>(&self, order: F) -> io::Result<Option<KVpair<K, V>>>
+    where
+        K: Encoding,
+        V: Encoding,
+        F: Fn(&K) -> Ordering,
+    {
+        let (mut lo, mut hi) = (0, self.restarts.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let (_, key) = EntryReader::new(&self.entries[self.restarts[mid]..]).key::<K>()?;
+            if order(&key) == Ordering::Greater {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+
+        let start = self.restarts.get(lo.saturating_sub(1)).copied().unwrap_or(0);
+        let mut reader = EntryReader::new(&self.entries[start..]);
+        while !reader.decoder.is_empty() {
+            let (tombstone, key) = reader.key::<K>()?;
+            if order(&key) == Ordering::Less {
+                reader.skip_value(tombstone)?;
+                continue;
+            }
+            return Ok(Some(KVpair {
+                key: Some(key),
+                value: reader.value(tombstone)?,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// Decodes the entries of a block one after the other, from a restart
+/// point, rebuilding each key from the one before it.
+struct EntryReader<'a> {
+    decoder: Decoder<'a>,
+    key: Vec<u8>,
+}
+
+impl<'a> EntryReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        EntryReader {
+            decoder: Decoder::new(data),
+            key: Vec::new(),
+        }
+    }
+
+    /// Decode the kind and the key of the next entry, returning whether it
+    /// is a tombstone.
+    fn key<K: Encoding>(&mut self) -> io::Result<(bool, K)> {
+        let tombstone = match self.decoder.u8()? {
+            ENTRY_VALUE => false,
+            ENTRY_TOMBSTONE => true,
+            _ => return Err(corruption("bad entry kind")),
+        };
+        let shared = self.decoder.u32()? as usize;
+        let unshared = self.decoder.u32()? as usize;
+        if shared > self.key.len() {
+            return Err(corruption("bad shared key prefix"));
+        }
+        self.key.truncate(shared);
+        self.key.extend_from_slice(self.decoder.take(unshared)?);
+        let key = K::decode(&self.key).ok_or_else(|| corruption("undecodable key or value"))?;
+        Ok((tombstone, key))
+    }
+
+    fn value<V: Encoding>(&mut self, tombstone: bool) -> io::Result<Option<V>> {
+        if tombstone {
+            return Ok(None);
+        }
+        self.decoder.prefixed().map(Some)
+    }
+
+    fn skip_value(&mut self, tombstone: bool) -> io::Result<()> {
+        if !tombstone {
+            let len = self.decoder.u32()? as usize;
+            self.decoder.take(len)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn encode_index<K: Encoding>(buf: &mut Vec<u8>, fence_pointers: &[K], blocks: &[BlockHandle]) {
+    buf.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+    for (key, block) in fence_pointers.iter().zip(blocks) {
+        buf.extend_from_slice(&block.offset.to_le_bytes());
+        buf.extend_from_slice(&block.len.to_le_bytes());
+        encode_prefixed(buf, key);
+    }
+}
+
+pub fn decode_index<K: Encoding>(data: &[u8]) -> io::Result<(Vec<K>, Vec<BlockHandle>)> {
+    let mut decoder = Decoder::new(data);
+    let pages = decoder.u32()? as usize;
+    let mut fence_pointers = Vec::with_capacity(pages);
+    let mut blocks = Vec::with_capacity(pages);
+    for _ in 0..pages {
+        let offset = decoder.u64()?;
+        let len = decoder.u32()?;
+        blocks.push(BlockHandle { offset, len });
+        fence_pointers.push(decoder.prefixed()?);
+    }
+    decoder.finish()?;
+    Ok((fence_pointers, blocks))
+}
+
+pub fn encode_bloom<K>(buf: &mut Vec<u8>, bf: &Bloom<K>) {
+    buf.extend_from_slice(&bf.number_of_bits().to_le_bytes());
+    buf.extend_from_slice(&bf.number_of_hash_functions().to_le_bytes());
+    for (k0, k1) in bf.sip_keys().iter() {
+        buf.extend_from_slice(&k0.to_le_bytes());
+        buf.extend_from_slice(&k1.to_le_bytes());
+    }
+    let bitmap = bf.bitmap();
+    buf.extend_from_slice(&(bitmap.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&bitmap);
+}
+
+pub fn decode_bloom<K>(data: &[u8]) -> io::Result<Bloom<K>> {
+    let mut decoder = Decoder::new(data);
+    let bits = decoder.u64()?;
+    let hash_functions = decoder.u32()?;
+    let sip_keys = [(decoder.u64()?, decoder.u64()?), (decoder.u64()?, decoder.u64()?)];
+    let bitmap_len = decoder.u32()? as usize;
+    let bitmap = decoder.take(bitmap_len)?;
+    decoder.finish()?;
+    if bits == 0 || bits > bitmap.len() as u64 * 8 || hash_functions == 0 {
+        return Err(corruption("bad bloom filter block"));
+    }
+    Ok(Bloom::from_existing(bitmap, bits, hash_functions, sip_keys))
+}
+
+pub fn encode_meta<K: Encoding>(buf: &mut Vec<u8>, meta: &Meta<K>) {
+    buf.extend_from_slice(&(meta.comparator.len() as u32).to_le_bytes());
+    buf.extend_from_slice(meta.comparator.as_bytes());
+    buf.extend_from_slice(&meta.entries.to_le_bytes());
+    buf.extend_from_slice(&(meta.range_tombstones.len() as u32).to_le_bytes());
+    for tombstone in meta.range_tombstones.iter() {
+        encode_prefixed(buf, &tombstone.start);
+        encode_prefixed(buf, &tombstone.end);
+    }
+    if let (Some(min_key), Some(max_key)) = (&meta.min_key, &meta.max_key) {
+        encode_prefixed(buf, min_key);
+        encode_prefixed(buf, max_key);
+    }
+}
+
+/// The comparator, entry count, range tombstones and key range of a run.
+pub fn decode_meta<K: Encoding>(data: &[u8]) -> io::Result<Meta<K>> {
+    let mut decoder = Decoder::new(data);
+    let name_len = decoder.u32()? as usize;
+    let comparator = String::from_utf8(decoder.take(name_len)?.to_vec())
+        .map_err(|_| corruption("comparator name is not UTF-8"))?;
+    let entries = decoder.u64()?;
+    let tombstones = decoder.u32()? as usize;
+    let mut range_tombstones = Vec::with_capacity(tombstones.min(data.len()));
+    for _ in 0..tombstones {
+        range_tombstones.push(RangeTombstone::new(decoder.prefixed()?, decoder.prefixed()?));
+    }
+    let (min_key, max_key) = if entries == 0 && range_tombstones.is_empty() {
+        (None, None)
+    } else {
+        (Some(decoder.prefixed()?), Some(decoder.prefixed()?))
+    };
+    decoder.finish()?;
+    Ok(Meta {
+        comparator,
+        entries,
+        range_tombstones,
+        min_key,
+        max_key,
+    })
+}
+
+impl Footer {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&self.index_offset.to_le_bytes());
+        buf.extend_from_slice(&self.bloom_offset.to_le_bytes());
+        buf.extend_from_slice(&self.meta_offset.to_le_bytes());
+        buf.extend_from_slice(&self.meta_len.to_le_bytes());
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&MAGIC);
+        let crc = crc32c::crc32c(&buf[start..]);
+        buf.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Decode the footer closing a file of `file_len` bytes, checking that
+    /// the blocks it points to are laid out in order.
+    pub fn decode(data: &[u8], file_len: u64) -> io::Result<Self> {
+        if data.len() as u64 != FOOTER_SIZE {
+            return Err(corruption("truncated footer"));
+        }
+        let body = &data[..data.len() - 4];
+        let crc = u32::from_le_bytes(data[body.len()..].try_into().unwrap());
+        if crc32c::crc32c(body) != crc {
+            return Err(corruption("footer checksum mismatch"));
+        }
+
+        let mut decoder = Decoder::new(body);
+        let footer = Footer {
+            index_offset: decoder.u64()?,
+            bloom_offset: decoder.u64()?,
+            meta_offset: decoder.u64()?,
+            meta_len: decoder.u64()?,
+        };
+        let version = decoder.u32()?;
+        if decoder.take(MAGIC.len())? != MAGIC {
+            return Err(corruption("bad magic number"));
+        }
+        check_version(version)?;
+
+        let in_order = HEADER_SIZE <= footer.index_offset
+            && footer.index_offset <= footer.bloom_offset
+            && footer.bloom_offset <= footer.meta_offset
+            && footer.meta_offset.checked_add(footer.meta_len) == Some(file_len - FOOTER_SIZE);
+        if !in_order {
+            return Err(corruption("footer points outside of the file"));
+        }
+        Ok(footer)
+    }
+}
+
+/// A cursor reading the fields of a block.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    #[inline]
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder { data }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(corruption("truncated block"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Decode a value written by `encode_prefixed`.
+    pub fn prefixed<T: Encoding>(&mut self) -> io::Result<T> {
+        let len = self.u32()? as usize;
+        T::decode(self.take(len)?).ok_or_else(|| corruption("undecodable key or value"))
+    }
+
+    /// Fail unless the whole block was read.
+    pub fn finish(&self) -> io::Result<()> {
+        if !self.is_empty() {
+            return Err(corruption("trailing bytes in block"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use skiplist::run::KVpair;
+
+    use super::{Block, BlockBuilder};
+
+    #[test]
+    fn block_restart_points() {
+        let data: Vec<KVpair<String, u32>> = (0..10u32)
+            .map(|k| match k {
+                4 => KVpair::tombstone(format!("key{:02}", k * 2)),
+                _ => KVpair::new(format!("key{:02}", k * 2), k),
+            })
+            .collect();
+        let mut builder = BlockBuilder::new(3);
+        for kv in &data {
+            builder.add(kv);
+        }
+        let mut buf = Vec::new();
+        builder.finish(&mut buf);
+        assert!(builder.is_empty());
+
+        let block = Block::new(&buf).unwrap();
+        assert_eq!(block.restarts.len(), 4);
+        assert_eq!(block.entries::<String, u32>().unwrap(), data);
+        for k in 0..21 {
+            let key = format!("key{:02}", k);
+            let expected = data.iter().find(|kv| kv.key.as_ref().unwrap() >= &key).cloned();
+            assert_eq!(block.seek(|found: &String| found.cmp(&key)).unwrap(), expected);
+        }
+
+        assert!(Block::new(&buf[..buf.len() - 1]).is_err());
+        assert!(Block::new(&[0, 0, 0]).is_err());
+        assert!(Block::new(&[]).is_err());
+    }
+}
@@ -1,6 +1,23 @@
+pub mod compression;
 pub mod disk_run;
 pub mod disk_level;
+pub mod encoding;
+pub mod env;
+pub mod fault_env;
+mod format;
+pub mod mem_env;
+pub mod ttl;
 
-pub use crate::skiplist::run::KVpair;
+pub use crate::skiplist::run::{KVpair, RangeTombstone};
+pub use crate::compression::Compression;
+pub use crate::disk_run::{DiskRun, DiskRunIter, DiskRunWriter};
+pub use crate::disk_level::{DiskLevel, MergeIter};
+pub use crate::encoding::Encoding;
+pub use crate::env::{Env, PosixEnv, RandomAccessFile, WritableFile};
+pub use crate::fault_env::FaultInjectionEnv;
+pub use crate::mem_env::MemEnv;
+pub use crate::ttl::{Clock, Expiring, Expiry, ManualClock, SystemClock};
+pub use error::{Corruption, Error, Result};
 
-extern crate skiplist;
\ No newline at end of file
+extern crate skiplist;
+extern crate bloomfilter;
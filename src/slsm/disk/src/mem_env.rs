@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::env::{Env, RandomAccessFile, WritableFile};
+
+type Contents = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Debug, Default)]
+struct Files {
+    files: BTreeMap<PathBuf, Contents>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+/// An environment keeping its files in memory, for tests that do not need
+/// the data to outlive the process.
+///
+/// Everything written is durable at once; wrap it in a `FaultInjectionEnv`
+/// to lose the data that is not synced.
+#[derive(Debug, Default)]
+pub struct MemEnv {
+    files: Mutex<Files>,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        MemEnv::default()
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, Files> {
+        self.files.lock().unwrap()
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl Files {
+    /// Fail unless the directory of `path` exists.
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !self.dirs.contains(dir) => Err(not_found(dir)),
+            _ => Ok(()),
+        }
+    }
+
+    fn get(&self, path: &Path) -> io::Result<&Contents> {
+        self.files.get(path).ok_or_else(|| not_found(path))
+    }
+}
+
+impl Env for MemEnv {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut files = self.lock();
+        files.check_parent(path)?;
+        // truncated in place, as readers of the old contents see it shrink.
+        let contents = Arc::clone(files.files.entry(path.to_path_buf()).or_default());
+        contents.write().unwrap().clear();
+        Ok(Box::new(MemFile(contents)))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut files = self.lock();
+        files.check_parent(path)?;
+        Ok(Box::new(MemFile(Arc::clone(files.files.entry(path.to_path_buf()).or_default()))))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(MemFile(Arc::clone(self.lock().get(path)?))))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.lock().files.remove(path).map(drop).ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.lock();
+        files.check_parent(to)?;
+        let contents = files.files.remove(from).ok_or_else(|| not_found(from))?;
+        files.files.insert(to.to_path_buf(), contents);
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.lock().get(path)?.write().unwrap().truncate(len as usize);
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let files = self.lock();
+        if !files.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        let names = files
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        Ok(names)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut files = self.lock();
+        for ancestor in dir.ancestors() {
+            files.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        match self.lock().dirs.contains(dir) {
+            true => Ok(()),
+            false => Err(not_found(dir)),
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Ok(self.lock().get(path)?.read().unwrap().clone())
+    }
+}
+
+/// A handle on the contents of a file of a `MemEnv`, kept alive by the
+/// handle once the file is removed.
+struct MemFile(Contents);
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RandomAccessFile for MemFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let contents = self.0.read().unwrap();
+        let start = offset as usize;
+        match contents.get(start..start + buf.len()) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past the end of the file")),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The time entries expire against, in seconds since the Unix epoch.
+/// Trees read it through a clock of their own, so tests can move time
+/// forward at will.
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> u64;
+}
+
+/// The clock of the system, the default clock of a tree.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock { now: AtomicU64::new(now) }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.now.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Values that may carry the time they expire at.
+pub trait Expiring {
+    /// The time, in seconds since the Unix epoch, from which the value is
+    /// gone, or `None` if it never expires.
+    fn expires_at(&self) -> Option<u64>;
+}
+
+/// Tells the expired values of a level apart, by the time of `clock`.
+pub struct Expiry<V> {
+    clock: Arc<dyn Clock>,
+    expires_at: fn(&V) -> Option<u64>,
+}
+
+impl<V: Expiring> Expiry<V> {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Expiry {
+            clock,
+            expires_at: V::expires_at,
+        }
+    }
+}
+
+impl<V> Expiry<V> {
+    #[inline]
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    #[inline]
+    pub fn is_expired(&self, value: &V) -> bool {
+        (self.expires_at)(value).is_some_and(|expires_at| expires_at <= self.clock.now())
+    }
+}
+
+impl<V> Clone for Expiry<V> {
+    fn clone(&self) -> Self {
+        Expiry {
+            clock: Arc::clone(&self.clock),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Clock, Expiring, Expiry, ManualClock, SystemClock};
+
+    struct Session(Option<u64>);
+
+    impl Expiring for Session {
+        fn expires_at(&self) -> Option<u64> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn expiry_follows_the_clock() {
+        let clock = Arc::new(ManualClock::new(100));
+        let expiry = Expiry::<Session>::new(clock.clone());
+        assert!(!expiry.is_expired(&Session(Some(110))));
+        assert!(!expiry.is_expired(&Session(None)));
+        clock.advance(10);
+        assert_eq!(expiry.clock().now(), 110);
+        assert!(expiry.is_expired(&Session(Some(110))));
+        clock.set(1 << 40);
+        assert!(!expiry.is_expired(&Session(None)));
+        assert!(SystemClock.now() > 0);
+    }
+}
@@ -0,0 +1,143 @@
+//! The error type returned by the fallible APIs of every crate of the tree.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A failed read or write of the file system, like a full disk.
+    Io(io::Error),
+    /// Stored data that fails its checksum or does not decode.
+    Corruption(Corruption),
+    /// A parameter or an option out of range, or a call that does not fit
+    /// the stored data, like opening a tree with another comparator than
+    /// the one it was written with.
+    InvalidArgument(String),
+    /// Something the call needs does not exist.
+    NotFound(String),
+    /// The call conflicts with another one in progress, and may succeed if
+    /// retried.
+    Busy(String),
+}
+
+/// Stored data that fails its checksum or does not decode, found at
+/// `offset` in the file at `path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub message: String,
+}
+
+impl Error {
+    #[inline]
+    pub fn invalid_argument(msg: impl Into<String>) -> Self {
+        Error::InvalidArgument(msg.into())
+    }
+
+    #[inline]
+    pub fn corruption(path: impl Into<PathBuf>, offset: u64, msg: impl Into<String>) -> Self {
+        Error::Corruption(Corruption {
+            path: path.into(),
+            offset,
+            message: msg.into(),
+        })
+    }
+
+    /// An error reading the data at `offset` of the file at `path`: data
+    /// that does not decode, reported by an `InvalidData` I/O error, is a
+    /// corruption of that place, anything else an I/O error.
+    pub fn locate(e: io::Error, path: &Path, offset: u64) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData => Error::corruption(path, offset, e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+
+    #[inline]
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, Error::Corruption(_))
+    }
+}
+
+impl Clone for Error {
+    /// Clone the error, an I/O error being copied as its kind and message.
+    fn clone(&self) -> Self {
+        match self {
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Corruption(c) => Error::Corruption(c.clone()),
+            Error::InvalidArgument(msg) => Error::InvalidArgument(msg.clone()),
+            Error::NotFound(msg) => Error::NotFound(msg.clone()),
+            Error::Busy(msg) => Error::Busy(msg.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption(c) => c.fmt(f),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::Busy(msg) => write!(f, "busy: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Corruption(c) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    #[inline]
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Corruption> for Error {
+    #[inline]
+    fn from(c: Corruption) -> Self {
+        Error::Corruption(c)
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "corruption in {} at offset {}: {}", self.path.display(), self.offset, self.message)
+    }
+}
+
+impl error::Error for Corruption {}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn display_and_clone() {
+        let e = Error::corruption("/db/C_0_1.txt", 12, "block checksum mismatch");
+        assert!(e.is_corruption());
+        assert_eq!(e.to_string(), "corruption in /db/C_0_1.txt at offset 12: block checksum mismatch");
+
+        let e: Error = io::Error::other("no space left on device").into();
+        match e.clone() {
+            Error::Io(copy) => assert_eq!(copy.to_string(), "no space left on device"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(Error::invalid_argument("p must be in (0, 1)").to_string(), "invalid argument: p must be in (0, 1)");
+    }
+}
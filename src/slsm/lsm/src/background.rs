@@ -0,0 +1,382 @@
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, MutexGuard};
+
+use disk::{DiskLevel, DiskRun, Encoding, Expiring, Expiry, MergeIter};
+use error::{Error, Result};
+use skiplist::run::{KVpair, RangeTombstone};
+use skiplist::{Comparator, Run};
+
+use crate::internal_key::{InternalKey, VisibleVersions};
+use crate::lsm::{LsmOptions, Shared, State};
+use crate::manifest::{Manifest, RunMeta, VersionEdit};
+use crate::merge_operator::{MergeOperands, MergeValue};
+use crate::wal::SyncPolicy;
+
+/// A sorted stream of pairs, one of the inputs of a merge.
+type MergeInput<'a, K, V> = Box<dyn Iterator<Item = Result<KVpair<InternalKey<K>, V>>> + 'a>;
+
+/// A disk run of a tree, shared by the levels that hold it.
+type SharedRun<K, V> = Arc<DiskRun<InternalKey<K>, V>>;
+
+/// The pairs and range tombstones of a sealed memory run being flushed.
+type MemoryInput<K, V> = (Vec<KVpair<InternalKey<K>, Option<V>>>, Vec<RangeTombstone<InternalKey<K>>>);
+
+enum Work {
+    /// Merge the oldest sealed memory runs into disk level 0.
+    Flush,
+    /// Merge the full disk level into the level below it.
+    Merge(usize),
+}
+
+/// The background thread of an `Lsm`, flushing sealed memory runs to disk
+/// level 0 and merging full disk levels down.
+///
+/// It is the only writer of the disk levels and of the manifest. It works
+/// on its own copy of the levels, and publishes a snapshot of them to the
+/// readers once a merge is logged in the manifest.
+///
+/// Merges drop the versions of a key that no snapshot can see, that is all
+/// but the newest one unless snapshots are live, and the pairs deleted by a
+/// range tombstone that every snapshot sees. Expired values turn into
+/// tombstones, and the merge operands every snapshot sees are combined.
+pub struct Compactor<K, V> {
+    shared: Arc<Shared<K, V>>,
+    manifest: Manifest<InternalKey<K>>,
+    levels: Vec<DiskLevel<InternalKey<K>, V>>,
+}
+
+impl<K, V> Compactor<K, V>
+where
+    K: Clone + Hash + Encoding + AsRef<[u8]> + Send + Sync + 'static,
+    V: Clone + Encoding + Expiring + MergeValue + Send + Sync + 'static,
+{
+    pub fn new(
+        shared: Arc<Shared<K, V>>,
+        manifest: Manifest<InternalKey<K>>,
+        levels: Vec<DiskLevel<InternalKey<K>, V>>,
+    ) -> Self {
+        Compactor {
+            shared,
+            manifest,
+            levels,
+        }
+    }
+
+    /// Reopen the runs listed in the manifest, checking each against its
+    /// entry and against `cmp`, and delete the run files it does not list.
+    /// Those are left behind when a merge is interrupted by a crash.
+    pub fn load_levels(
+        dir: &Path,
+        options: &LsmOptions,
+        cmp: &Arc<dyn Comparator<InternalKey<K>>>,
+        manifest: &Manifest<InternalKey<K>>,
+    ) -> Result<Vec<DiskLevel<InternalKey<K>, V>>> {
+        let mut levels = vec![Self::next_level(dir, options, cmp, &[])];
+        for meta in manifest.version().runs.values() {
+            while levels.len() <= meta.level {
+                levels.push(Self::next_level(dir, options, cmp, &levels));
+            }
+            let run = match DiskRun::open(Arc::clone(&options.env), dir, meta.level, meta.run_id, Arc::clone(cmp)) {
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    let name = DiskRun::<InternalKey<K>, V>::filename(meta.level, meta.run_id);
+                    return Err(Error::NotFound(format!("run {} listed in the manifest", name)));
+                }
+                run => run?,
+            };
+            meta.check(&run)?;
+            levels[meta.level].push_run(run);
+        }
+
+        for name in options.env.read_dir(dir)? {
+            if let Some(id) = DiskRun::<InternalKey<K>, V>::parse_filename(&name) {
+                if !manifest.version().runs.contains_key(&id) {
+                    options.env.remove_file(&dir.join(name))?;
+                }
+            }
+        }
+        Ok(levels)
+    }
+
+    /// An empty disk level to go below `levels`, whose runs are larger than
+    /// the runs of the level above by the size ratio of the compaction
+    /// policy.
+    fn next_level(
+        dir: &Path,
+        options: &LsmOptions,
+        cmp: &Arc<dyn Comparator<InternalKey<K>>>,
+        levels: &[DiskLevel<InternalKey<K>, V>],
+    ) -> DiskLevel<InternalKey<K>, V> {
+        let (run_size, merge_size) = match levels.last() {
+            Some(upper) => (
+                upper.run_size * options.compaction.size_ratio(upper.run_nums, upper.merge_size),
+                upper.merge_size,
+            ),
+            None => (
+                options.num_to_merge() * options.elts_per_run,
+                options.merge_count(options.disk_runs_per_level),
+            ),
+        };
+        let mut level = DiskLevel::new(
+            Arc::clone(&options.env),
+            dir,
+            options.page_size,
+            levels.len(),
+            run_size,
+            options.disk_runs_per_level,
+            merge_size,
+            options.bf_fp,
+            options.compression(levels.len()),
+            Arc::clone(cmp),
+        );
+        level.verify_checksums = options.verify_checksums;
+        level.expiry = Some(Expiry::new(Arc::clone(&options.clock)));
+        level
+    }
+
+    /// Wait for work until the tree is dropped. An error stops all further
+    /// work; it is reported to the writers.
+    ///
+    /// With an interval sync policy, the thread also wakes up once every
+    /// interval while idle to sync the write-ahead log, so the last writes
+    /// before a pause are not left unsynced.
+    pub fn run(mut self) {
+        let shared = Arc::clone(&self.shared);
+        let mut state = shared.lock();
+        while !state.shutting_down {
+            let work = match state.bg_error {
+                None if !state.paused => self.pick(&state),
+                _ => None,
+            };
+            let work = match work {
+                Some(work) => work,
+                None => {
+                    state.busy = false;
+                    shared.cv.notify_all();
+                    state = match shared.options.sync_policy {
+                        SyncPolicy::Interval(interval) => shared.cv.wait_timeout(state, interval).unwrap().0,
+                        _ => shared.cv.wait(state).unwrap(),
+                    };
+                    drop(state);
+                    let synced = shared.wal().sync_if_due();
+                    state = shared.lock();
+                    if let Err(e) = synced {
+                        state.bg_error.get_or_insert(e);
+                    }
+                    continue;
+                }
+            };
+
+            state.busy = true;
+            drop(state);
+            let result = match work {
+                Work::Flush => self.flush(),
+                Work::Merge(level) => self.merge_runs_to_level(level + 1),
+            };
+            state = shared.lock();
+            if let Err(e) = result {
+                state.bg_error = Some(e);
+            }
+            shared.cv.notify_all();
+        }
+        state.busy = false;
+        shared.cv.notify_all();
+    }
+
+    /// Flushes come first, unless level 0 is so crowded that writers are
+    /// being slowed down and it has to be merged down.
+    fn pick(&self, state: &State<K, V>) -> Option<Work> {
+        let options = &self.shared.options;
+        let needs_flush = state.needs_flush(options);
+        let full_level = (0..self.levels.len()).find(|&level| self.level_full(level));
+        let level0_crowded = self.levels[0].active_run >= options.level0_slowdown_runs;
+        match full_level {
+            Some(level) if !needs_flush || level0_crowded => Some(Work::Merge(level)),
+            _ if needs_flush => Some(Work::Flush),
+            _ => None,
+        }
+    }
+
+    fn publish(&self, state: &mut MutexGuard<State<K, V>>) {
+        state.disk_levels = Arc::new(self.levels.clone());
+    }
+
+    /// Whether disk level `level` has to be merged into the level below:
+    /// a tiered level is full once it holds `run_nums` runs, a leveled one
+    /// once it holds `run_nums` runs worth of pairs.
+    fn level_full(&self, level: usize) -> bool {
+        let disk_level = &self.levels[level];
+        let last_level = level + 1 == self.levels.len();
+        if self.shared.options.compaction.is_leveled(level, last_level) {
+            disk_level.num_elements() >= disk_level.run_size * disk_level.run_nums
+        } else {
+            disk_level.level_full()
+        }
+    }
+
+    /// Take the runs out of disk level `level` if it is leveled, for them to
+    /// be merged with the runs arriving at the level.
+    fn take_leveled_runs(&mut self, level: usize) -> Vec<SharedRun<K, V>> {
+        let last_level = level + 1 == self.levels.len();
+        if !self.shared.options.compaction.is_leveled(level, last_level) {
+            return Vec::new();
+        }
+        let n = self.levels[level].active_run;
+        self.levels[level].take_runs(n)
+    }
+
+    /// Merge the `disk` runs then the `memory` runs, oldest first, into a
+    /// new run of `level`, keeping the versions visible as of
+    /// `smallest_snapshot`. Tombstones are dropped too when `is_last`.
+    ///
+    /// The range tombstones of the inputs go to the new run, but the pairs
+    /// covered by those not newer than `smallest_snapshot` are dropped, and
+    /// so are those tombstones when `is_last`.
+    #[allow(clippy::too_many_arguments)]
+    fn add_merged<'l>(
+        shared: &Shared<K, V>,
+        level: &'l mut DiskLevel<InternalKey<K>, V>,
+        run_id: usize,
+        disk: &[SharedRun<K, V>],
+        memory: Vec<MemoryInput<K, V>>,
+        smallest_snapshot: u64,
+        is_last: bool,
+    ) -> Result<Option<&'l SharedRun<K, V>>> {
+        let comparator = &*shared.comparator;
+        let settled = |tombstone: &RangeTombstone<InternalKey<K>>| tombstone.start.seq <= smallest_snapshot;
+        let tombstones: Vec<Vec<_>> = disk
+            .iter()
+            .map(|run| run.range_tombstones().to_vec())
+            .chain(memory.iter().map(|(_, tombstones)| tombstones.clone()))
+            .collect();
+        let capacity = disk.iter().map(|run| run.get_capacity()).sum::<usize>()
+            + memory.iter().map(|(pairs, _)| pairs.len()).sum::<usize>();
+
+        let mut inputs: Vec<MergeInput<K, V>> = Vec::with_capacity(tombstones.len());
+        for (i, run) in disk.iter().enumerate() {
+            // every write of an input is older than those of the newer
+            // inputs, so their tombstones delete every version of the keys
+            // between their bounds and the pages within are skipped.
+            let newer: Vec<_> = tombstones[i + 1..].iter().flatten().filter(|t| settled(t)).cloned().collect();
+            let deletes = move |tombstone: &RangeTombstone<InternalKey<K>>, key: &InternalKey<K>| {
+                comparator.covers(tombstone, &InternalKey::new(key.user_key.clone(), 0))
+            };
+            let covered = move |first: &InternalKey<K>, last: &InternalKey<K>| {
+                newer.iter().any(|tombstone| deletes(tombstone, first) && deletes(tombstone, last))
+            };
+            inputs.push(Box::new(run.iter_uncovered(covered)));
+        }
+        for (pairs, _) in memory {
+            let pairs = pairs.into_iter().map(|kv| Ok(KVpair { key: kv.key, value: kv.value.unwrap() }));
+            inputs.push(Box::new(pairs));
+        }
+
+        let tombstones: Vec<_> = tombstones.into_iter().flatten().collect();
+        let deleting: Vec<_> = tombstones.iter().filter(|t| settled(t)).cloned().collect();
+        let merged = MergeIter::new(inputs, false, comparator).with_expiry(level.expiry.clone());
+        let merged = merged.filter(move |kv| match kv {
+            Ok(kv) => !deleting.iter().any(|tombstone| comparator.covers(tombstone, kv.key.as_ref().unwrap())),
+            Err(_) => true,
+        });
+        let operator = shared.options.merge_operator.as_deref();
+        let merged = MergeOperands::new(merged, &*shared.user_comparator, operator, smallest_snapshot, is_last);
+        let versions = VisibleVersions::new(merged, &*shared.user_comparator, smallest_snapshot, is_last);
+        // nothing is left below the bottom level for them to delete.
+        let kept = tombstones.into_iter().filter(|t| !(is_last && settled(t))).collect();
+        level.add_sorted(run_id, versions, kept, capacity)
+    }
+
+    /// Merge the oldest `num_to_merge` sealed memory runs into a new run of
+    /// disk level 0, along with the run of level 0 if it is leveled. The
+    /// memory runs and their write-ahead log segments are dropped once the
+    /// disk run is in the manifest.
+    fn flush(&mut self) -> Result<()> {
+        let n = self.shared.options.num_to_merge();
+        let (memory, log_number, last_sequence, smallest_snapshot) = {
+            let mut state = self.shared.lock();
+            let memory: Vec<_> =
+                state.runs[..n].iter_mut().map(|run| (run.get_all(), run.range_tombstones().to_vec())).collect();
+            (memory, self.shared.wal().segments()[n], state.last_sequence, state.smallest_snapshot())
+        };
+
+        let rewritten = self.take_leveled_runs(0);
+        // nothing older than the merged runs is left on disk, so the
+        // tombstones have nothing to hide.
+        let is_last = self.levels.len() == 1 && self.levels[0].level_empty();
+        let run_id = self.manifest.new_run_id();
+        let level0 = &mut self.levels[0];
+        let added = Self::add_merged(&self.shared, level0, run_id, &rewritten, memory, smallest_snapshot, is_last)?;
+        let edit = VersionEdit {
+            added: added.map(|run| RunMeta::of(run)).into_iter().collect(),
+            deleted: rewritten.iter().map(|run| (run.level(), run.run_id())).collect(),
+            log_number: Some(log_number),
+            last_sequence: Some(last_sequence),
+            ..VersionEdit::default()
+        };
+        self.manifest.log_edit(edit)?;
+
+        {
+            let mut state = self.shared.lock();
+            self.publish(&mut state);
+            self.shared.wal().drop_segments(n)?;
+            state.runs.drain(..n);
+            state.filters.drain(..n);
+            state.active_run -= n;
+            state.fill_memory_runs(&self.shared.options, &self.shared.comparator)?;
+        }
+        self.shared.cv.notify_all();
+        for run in rewritten {
+            run.remove()?;
+        }
+        Ok(())
+    }
+
+    /// Merge the oldest runs of disk level `level - 1`, or all of them if it
+    /// is leveled, into `level`, first making room in `level` by merging it
+    /// further down if it is full. The merged runs are deleted once the new
+    /// run is in the manifest.
+    fn merge_runs_to_level(&mut self, level: usize) -> Result<()> {
+        let options = &self.shared.options;
+        if level == self.levels.len() {
+            let new_level = Self::next_level(&self.shared.dir, options, &self.shared.comparator, &self.levels);
+            self.levels.push(new_level);
+        }
+
+        if self.level_full(level) {
+            self.merge_runs_to_level(level + 1)?;
+        }
+        let rewritten = self.take_leveled_runs(level);
+        let is_last = level + 1 == self.levels.len() && self.levels[level].level_empty();
+        let upper_leveled = self.shared.options.compaction.is_leveled(level - 1, false);
+        let smallest_snapshot = self.shared.lock().smallest_snapshot();
+
+        let run_id = self.manifest.new_run_id();
+        let (upper, lower) = self.levels.split_at_mut(level);
+        let upper = &mut upper[level - 1];
+        let n = if upper_leveled {
+            upper.active_run
+        } else {
+            upper.merge_size.min(upper.active_run)
+        };
+        let to_merge: Vec<_> = rewritten.iter().chain(&upper.runs[..n]).cloned().collect();
+        let lower = &mut lower[0];
+        let added = Self::add_merged(&self.shared, lower, run_id, &to_merge, Vec::new(), smallest_snapshot, is_last)?;
+        let mut edit = VersionEdit {
+            added: added.map(|run| RunMeta::of(run)).into_iter().collect(),
+            ..VersionEdit::default()
+        };
+
+        let merged = upper.take_runs(n);
+        let deleted: Vec<_> = rewritten.into_iter().chain(merged).collect();
+        edit.deleted = deleted.iter().map(|run| (run.level(), run.run_id())).collect();
+        self.manifest.log_edit(edit)?;
+
+        self.publish(&mut self.shared.lock());
+        self.shared.cv.notify_all();
+        for run in deleted {
+            run.remove()?;
+        }
+        Ok(())
+    }
+}
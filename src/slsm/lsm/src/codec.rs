@@ -0,0 +1,232 @@
+use std::convert::TryInto;
+
+/// Conversion of the keys and values of a typed `Lsm` to and from the bytes
+/// stored by the `Db` below it.
+///
+/// Encodings preserve order: two keys compare as their encodings compare
+/// bytewise, so the tree keeps typed keys in their natural order. Integers
+/// are stored big endian, with the sign bit of signed ones flipped, and
+/// strings and byte strings are escaped and terminated, so a tuple is the
+/// concatenation of the encodings of its fields.
+pub trait Codec: Sized {
+    /// Append the encoding of `self` to `buf`.
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    /// Decode a value from the front of `bytes`, advancing past it, or
+    /// `None` if they are malformed.
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self>;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    /// Decode a value from exactly `bytes`.
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let value = Self::decode_from(&mut bytes)?;
+        if !bytes.is_empty() {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if bytes.len() < n {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Some(head)
+}
+
+macro_rules! impl_unsigned_codec {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                #[inline]
+                fn encode_to(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+
+                #[inline]
+                fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+                    let bytes = take(bytes, std::mem::size_of::<$t>())?;
+                    Some(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned_codec!(u8, u16, u32, u64, u128);
+
+// flipping the sign bit puts the negative numbers before the positive ones.
+macro_rules! impl_signed_codec {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Codec for $t {
+                #[inline]
+                fn encode_to(&self, buf: &mut Vec<u8>) {
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_to(buf);
+                }
+
+                #[inline]
+                fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+                    Some((<$u>::decode_from(bytes)? ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    };
+}
+
+impl_signed_codec!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl Codec for bool {
+    #[inline]
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    #[inline]
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        match take(bytes, 1)?[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// Byte strings are terminated by `00 01`, and their zero bytes escaped as
+/// `00 ff`, so that a string sorts before every longer string it is a
+/// prefix of, whatever follows it in a tuple.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &b in bytes {
+        buf.push(b);
+        if b == ESCAPE {
+            buf.push(ESCAPED_ZERO);
+        }
+    }
+    buf.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+fn decode_bytes(bytes: &mut &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let b = take(bytes, 1)?[0];
+        if b != ESCAPE {
+            decoded.push(b);
+            continue;
+        }
+        match take(bytes, 1)?[0] {
+            ESCAPED_ZERO => decoded.push(ESCAPE),
+            TERMINATOR => return Some(decoded),
+            _ => return None,
+        }
+    }
+}
+
+impl Codec for Vec<u8> {
+    #[inline]
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self, buf);
+    }
+
+    #[inline]
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        decode_bytes(bytes)
+    }
+}
+
+impl Codec for String {
+    #[inline]
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), buf);
+    }
+
+    #[inline]
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(decode_bytes(bytes)?).ok()
+    }
+}
+
+macro_rules! impl_tuple_codec {
+    ($($name:ident),+) => {
+        impl<$($name: Codec),+> Codec for ($($name,)+) {
+            #[inline]
+            #[allow(non_snake_case)]
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let ($(ref $name,)+) = *self;
+                $($name.encode_to(buf);)+
+            }
+
+            #[inline]
+            fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+                Some(($($name::decode_from(bytes)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple_codec!(A);
+impl_tuple_codec!(A, B);
+impl_tuple_codec!(A, B, C);
+impl_tuple_codec!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::Codec;
+
+    fn check_order<T: Codec + Ord + Debug>(mut values: Vec<T>) {
+        values.sort();
+        for w in values.windows(2) {
+            assert!(w[0].encode() < w[1].encode(), "{:?} < {:?}", w[0], w[1]);
+        }
+        for value in values {
+            assert_eq!(T::decode(&value.encode()), Some(value));
+        }
+    }
+
+    #[test]
+    fn integers() {
+        check_order(vec![0u64, 1, 255, 256, u64::MAX]);
+        check_order(vec![i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        check_order(vec![i8::MIN, -1, 0, i8::MAX]);
+        assert_eq!(u32::decode(&[0, 0, 1]), None);
+        assert_eq!(u16::decode(&[0, 0, 1]), None);
+    }
+
+    #[test]
+    fn strings() {
+        let strings = ["", "\0", "\0\0", "a", "a\0", "a\0b", "ab", "b", "\u{e9}"];
+        check_order(strings.iter().map(|s| s.to_string()).collect());
+        check_order(vec![vec![], vec![0u8], vec![0, 0xff], vec![1], vec![0xff, 0]]);
+        assert_eq!(String::decode(b"abc"), None);
+        assert_eq!(String::decode(&[0xff, 0, 1]), None);
+    }
+
+    #[test]
+    fn tuples() {
+        check_order(vec![
+            ("".to_string(), 5i32),
+            ("a".to_string(), -1),
+            ("a".to_string(), 3),
+            ("a\0".to_string(), i32::MIN),
+            ("ab".to_string(), 0),
+        ]);
+        check_order(vec![
+            (1u8, true, "x".to_string(), -2i64),
+            (1, true, "x".to_string(), 7),
+            (2, false, String::new(), 0),
+        ]);
+        assert_eq!(<(u8, u8)>::decode(&[1]), None);
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+
+/// Decides how the runs of the disk levels are merged.
+///
+/// A *tiered* level collects runs until it holds `disk_runs_per_level` of
+/// them, then its oldest runs are merged into one new run of the level
+/// below. A *leveled* level holds a single run: every run arriving at it is
+/// merged with that run, and once the level holds more pairs than its run
+/// capacity times `disk_runs_per_level`, the whole run is merged into the
+/// level below.
+///
+/// Each level holds `size_ratio` times more pairs than the level above it.
+pub trait CompactionPolicy: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// How many times larger the runs of a level are than the runs of the
+    /// level above it, which holds up to `runs_per_level` runs and merges
+    /// `merge_size` of them at once. That is `merge_size` by default, as a
+    /// tiered level merges `merge_size` runs into one.
+    fn size_ratio(&self, _runs_per_level: usize, merge_size: usize) -> usize {
+        merge_size
+    }
+
+    /// Whether disk level `level` is leveled rather than tiered.
+    /// `last_level` is set for the bottom level of the tree.
+    fn is_leveled(&self, level: usize, last_level: bool) -> bool;
+
+    /// The number of times a pair is written to disk, on average, on its
+    /// way down to the last of `levels` disk levels.
+    fn write_amplification(&self, size_ratio: usize, levels: usize) -> f64;
+}
+
+/// Every level is tiered, as in the sLSM paper. Cheapest to write, but a
+/// lookup may have to check every run of every level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tiering;
+
+impl CompactionPolicy for Tiering {
+    fn name(&self) -> &str {
+        "tiering"
+    }
+
+    fn is_leveled(&self, _level: usize, _last_level: bool) -> bool {
+        false
+    }
+
+    fn write_amplification(&self, _size_ratio: usize, levels: usize) -> f64 {
+        levels as f64
+    }
+}
+
+/// Every level is leveled: one run per level, so lookups and scans are
+/// cheapest, at the cost of rewriting each level `(size_ratio + 1) / 2`
+/// times on average.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Leveling;
+
+impl CompactionPolicy for Leveling {
+    fn name(&self) -> &str {
+        "leveling"
+    }
+
+    /// A full level holds `runs_per_level` runs worth of pairs, and is
+    /// merged whole into the level below.
+    fn size_ratio(&self, runs_per_level: usize, _merge_size: usize) -> usize {
+        runs_per_level
+    }
+
+    fn is_leveled(&self, _level: usize, _last_level: bool) -> bool {
+        true
+    }
+
+    fn write_amplification(&self, size_ratio: usize, levels: usize) -> f64 {
+        levels as f64 * (size_ratio + 1) as f64 / 2.0
+    }
+}
+
+/// Tiering on every level but the last one, which is leveled. Since the
+/// last level holds most of the data, space and lookup costs stay close to
+/// leveling while writes stay close to tiering.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LazyLeveling;
+
+impl CompactionPolicy for LazyLeveling {
+    fn name(&self) -> &str {
+        "lazy leveling"
+    }
+
+    fn is_leveled(&self, _level: usize, last_level: bool) -> bool {
+        last_level
+    }
+
+    fn write_amplification(&self, size_ratio: usize, levels: usize) -> f64 {
+        match levels {
+            0 => 0.0,
+            _ => (levels - 1) as f64 + (size_ratio + 1) as f64 / 2.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompactionPolicy, LazyLeveling, Leveling, Tiering};
+
+    #[test]
+    fn write_amplification() {
+        assert_eq!(Tiering.write_amplification(4, 3), 3.0);
+        assert_eq!(Leveling.write_amplification(4, 3), 7.5);
+        assert_eq!(LazyLeveling.write_amplification(4, 3), 4.5);
+        assert_eq!(LazyLeveling.write_amplification(4, 0), 0.0);
+    }
+
+    #[test]
+    fn lazy_leveling_levels_the_last_level() {
+        assert!(!LazyLeveling.is_leveled(0, false));
+        assert!(LazyLeveling.is_leveled(2, true));
+        assert!(Leveling.is_leveled(0, false));
+        assert!(!Tiering.is_leveled(2, true));
+    }
+
+    #[test]
+    fn size_ratio() {
+        assert_eq!(Tiering.size_ratio(4, 2), 2);
+        assert_eq!(LazyLeveling.size_ratio(4, 2), 2);
+        assert_eq!(Leveling.size_ratio(4, 2), 4);
+    }
+}
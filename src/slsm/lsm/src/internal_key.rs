@@ -0,0 +1,212 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use disk::Encoding;
+use error::Result;
+use skiplist::run::{KVpair, RangeTombstone};
+use skiplist::Comparator;
+
+/// The largest sequence number. Paired with a user key, it makes the
+/// internal key ordered before every version of that key.
+pub const MAX_SEQUENCE: u64 = u64::MAX;
+
+/// A user key stamped with the sequence number of the write that stored
+/// it, so every version of a key is kept as a pair of its own.
+///
+/// Each write of a tree takes the next sequence number, the first one
+/// being 1. A snapshot taken at sequence number `s` sees the newest version
+/// of each key whose sequence number is at most `s`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InternalKey<K> {
+    pub user_key: K,
+    pub seq: u64,
+}
+
+impl<K> InternalKey<K> {
+    #[inline]
+    pub fn new(user_key: K, seq: u64) -> Self {
+        InternalKey { user_key, seq }
+    }
+}
+
+/// The encoding of the user key, followed by the sequence number.
+impl<K: Encoding> Encoding for InternalKey<K> {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.user_key.encode(buf);
+        self.seq.encode(buf);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let (user_key, seq) = bytes.split_at(bytes.len() - 8);
+        Some(InternalKey::new(K::decode(user_key)?, u64::decode(seq)?))
+    }
+}
+
+/// Orders internal keys by user key, as the user comparator does, then by
+/// decreasing sequence number, so the newest version of a key comes first.
+///
+/// Its name is derived from the name of the user comparator, and the bloom
+/// filters only hash the user key, so a lookup checks them once for every
+/// version of its key.
+pub struct InternalKeyComparator<K> {
+    user: Arc<dyn Comparator<K>>,
+    name: String,
+}
+
+impl<K> InternalKeyComparator<K> {
+    pub fn new(user: Arc<dyn Comparator<K>>) -> Self {
+        let name = format!("{}+seq", user.name());
+        InternalKeyComparator { user, name }
+    }
+
+    #[inline]
+    pub fn user_comparator(&self) -> &Arc<dyn Comparator<K>> {
+        &self.user
+    }
+}
+
+impl<K: Hash> Comparator<InternalKey<K>> for InternalKeyComparator<K> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn compare(&self, a: &InternalKey<K>, b: &InternalKey<K>) -> Ordering {
+        self.user.compare(&a.user_key, &b.user_key).then(b.seq.cmp(&a.seq))
+    }
+
+    fn hash_key(&self, key: &InternalKey<K>, state: &mut dyn Hasher) {
+        self.user.hash_key(&key.user_key, state)
+    }
+
+    /// Both bounds of a range tombstone are stamped with the sequence number
+    /// of the range delete, which deletes only the older versions of the
+    /// user keys between them.
+    fn covers(&self, tombstone: &RangeTombstone<InternalKey<K>>, key: &InternalKey<K>) -> bool {
+        key.seq < tombstone.start.seq
+            && self.user.compare(&tombstone.start.user_key, &key.user_key) != Ordering::Greater
+            && self.user.compare(&key.user_key, &tombstone.end.user_key) == Ordering::Less
+    }
+}
+
+/// Drops from a merged stream of pairs, in internal key order, the versions
+/// that no snapshot can see any more.
+///
+/// A version is hidden from every snapshot once a newer version of its key
+/// has a sequence number of at most `smallest_snapshot`, the sequence
+/// number of the oldest live snapshot, or the last sequence number when
+/// there is none. With `drop_tombstones`, when nothing older than the
+/// stream is left, such tombstones are dropped as well.
+pub struct VisibleVersions<'c, K, V, I> {
+    pairs: I,
+    cmp: &'c dyn Comparator<K>,
+    smallest_snapshot: u64,
+    drop_tombstones: bool,
+    /// The key of the last pair read, kept or not.
+    last: Option<InternalKey<K>>,
+    _marker: PhantomData<fn() -> V>,
+}
+
+impl<'c, K, V, I> VisibleVersions<'c, K, V, I>
+where
+    I: Iterator<Item = Result<KVpair<InternalKey<K>, V>>>,
+{
+    pub fn new(pairs: I, cmp: &'c dyn Comparator<K>, smallest_snapshot: u64, drop_tombstones: bool) -> Self {
+        VisibleVersions {
+            pairs,
+            cmp,
+            smallest_snapshot,
+            drop_tombstones,
+            last: None,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'c, K, V, I> Iterator for VisibleVersions<'c, K, V, I>
+where
+    K: Clone,
+    I: Iterator<Item = Result<KVpair<InternalKey<K>, V>>>,
+{
+    type Item = Result<KVpair<InternalKey<K>, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let kv = match self.pairs.next()? {
+                Ok(kv) => kv,
+                Err(e) => return Some(Err(e)),
+            };
+            let key = kv.key.as_ref().unwrap();
+            let shadowed = match &self.last {
+                Some(newer) => {
+                    self.cmp.compare(&newer.user_key, &key.user_key) == Ordering::Equal
+                        && newer.seq <= self.smallest_snapshot
+                }
+                None => false,
+            };
+            let obsolete = shadowed || (self.drop_tombstones && kv.is_tombstone() && key.seq <= self.smallest_snapshot);
+            self.last = Some(key.clone());
+            if !obsolete {
+                return Some(Ok(kv));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use disk::Encoding;
+    use skiplist::run::KVpair;
+    use skiplist::{Comparator, OrdComparator};
+
+    use super::{InternalKey, InternalKeyComparator, VisibleVersions};
+
+    fn versions(smallest_snapshot: u64, drop_tombstones: bool) -> Vec<(u32, u64)> {
+        let pairs: Vec<KVpair<InternalKey<u32>, u32>> = vec![
+            KVpair::new(InternalKey::new(1, 9), 0),
+            KVpair::new(InternalKey::new(1, 5), 0),
+            KVpair::new(InternalKey::new(1, 2), 0),
+            KVpair::tombstone(InternalKey::new(2, 7)),
+            KVpair::new(InternalKey::new(2, 3), 0),
+            KVpair::tombstone(InternalKey::new(3, 4)),
+        ];
+        VisibleVersions::new(pairs.into_iter().map(Ok), &OrdComparator, smallest_snapshot, drop_tombstones)
+            .map(|kv| kv.unwrap().key.unwrap())
+            .map(|key| (key.user_key, key.seq))
+            .collect()
+    }
+
+    #[test]
+    fn internal_key_order() {
+        let cmp = InternalKeyComparator::<u32>::new(Arc::new(OrdComparator));
+        assert_eq!(cmp.name(), "ord+seq");
+        let mut keys = vec![InternalKey::new(2, 1), InternalKey::new(1, 1), InternalKey::new(1, 3)];
+        keys.sort_by(|a, b| cmp.compare(a, b));
+        assert_eq!(keys, vec![InternalKey::new(1, 3), InternalKey::new(1, 1), InternalKey::new(2, 1)]);
+
+        let mut buf = Vec::new();
+        keys[0].encode(&mut buf);
+        assert_eq!(InternalKey::<u32>::decode(&buf), Some(keys[0].clone()));
+        assert_eq!(InternalKey::<u32>::decode(&buf[..7]), None);
+    }
+
+    #[test]
+    fn snapshots_keep_versions() {
+        // no snapshot: the newest version of each key.
+        assert_eq!(versions(10, false), vec![(1, 9), (2, 7), (3, 4)]);
+        assert_eq!(versions(10, true), vec![(1, 9)]);
+        // a snapshot at 5 still sees (1, 5) and (2, 3).
+        assert_eq!(versions(5, false), vec![(1, 9), (1, 5), (2, 7), (2, 3), (3, 4)]);
+        assert_eq!(versions(5, true), vec![(1, 9), (1, 5), (2, 7), (2, 3)]);
+        assert_eq!(versions(1, true), vec![(1, 9), (1, 5), (1, 2), (2, 7), (2, 3), (3, 4)]);
+    }
+}
@@ -1,3 +1,31 @@
+mod background;
+pub mod codec;
+pub mod compaction;
+pub mod internal_key;
+mod lock_manager;
+pub mod lsm;
+pub mod manifest;
+pub mod merge_operator;
+pub mod snapshot;
+pub mod transaction;
+pub mod value;
+pub mod wal;
+pub mod write_batch;
+
+pub use crate::codec::Codec;
+pub use crate::compaction::{CompactionPolicy, LazyLeveling, Leveling, Tiering};
+pub use crate::internal_key::InternalKey;
+pub use crate::lsm::{Db, DbLevel, Lsm, LsmOptions};
+pub use crate::merge_operator::MergeOperator;
+pub use crate::snapshot::{LsmSnapshot, Snapshot};
+pub use crate::transaction::{Concurrency, LsmTransaction, Transaction, TransactionOptions};
+pub use crate::value::StoredValue;
+pub use crate::wal::SyncPolicy;
+pub use crate::write_batch::{LsmWriteBatch, WriteBatch};
+pub use disk::{Clock, Compression, Env, FaultInjectionEnv, ManualClock, MemEnv, PosixEnv, SystemClock};
+pub use error::{Corruption, Error, Result};
+pub use skiplist::{Comparator, OrdComparator};
+
 #[cfg(test)]
 mod tests {
     #[test]
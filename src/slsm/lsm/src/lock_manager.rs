@@ -0,0 +1,75 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use error::{Error, Result};
+use skiplist::Comparator;
+
+/// The locks pessimistic transactions hold on keys until they end.
+///
+/// Keys are told apart by the comparator of the tree, so keys it finds
+/// equal share a lock. A transaction waits for a lock held by another one
+/// for at most its lock timeout, which also breaks deadlocks.
+pub(crate) struct LockManager {
+    comparator: Arc<dyn Comparator<Vec<u8>>>,
+    /// The locked keys, by their hash under the comparator.
+    locked: Mutex<HashMap<u64, Vec<Vec<u8>>>>,
+    /// Signalled whenever locks are released.
+    cv: Condvar,
+}
+
+impl LockManager {
+    pub fn new(comparator: Arc<dyn Comparator<Vec<u8>>>) -> Self {
+        LockManager {
+            comparator,
+            locked: Mutex::new(HashMap::new()),
+            cv: Condvar::new(),
+        }
+    }
+
+    fn bucket(&self, key: &Vec<u8>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.comparator.hash_key(key, &mut hasher);
+        hasher.finish()
+    }
+
+    /// Lock `key`, waiting at most `timeout` for its lock to be released,
+    /// after which it fails with `Busy`. The lock is not reentrant.
+    pub fn lock(&self, key: &Vec<u8>, timeout: Duration) -> Result<()> {
+        let bucket = self.bucket(key);
+        let deadline = Instant::now() + timeout;
+        let mut locked = self.locked.lock().unwrap();
+        loop {
+            let held = locked
+                .get(&bucket)
+                .is_some_and(|keys| keys.iter().any(|held| self.comparator.compare(held, key) == Ordering::Equal));
+            if !held {
+                locked.entry(bucket).or_default().push(key.clone());
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Busy(format!("timed out waiting for the lock of key {:?}", key)));
+            }
+            locked = self.cv.wait_timeout(locked, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Release the locks of `keys`, all locked by the caller.
+    pub fn unlock<'k>(&self, keys: impl IntoIterator<Item = &'k Vec<u8>>) {
+        let mut locked = self.locked.lock().unwrap();
+        for key in keys {
+            let bucket = self.bucket(key);
+            if let Some(held) = locked.get_mut(&bucket) {
+                held.retain(|held| self.comparator.compare(held, key) != Ordering::Equal);
+                if held.is_empty() {
+                    locked.remove(&bucket);
+                }
+            }
+        }
+        self.cv.notify_all();
+    }
+}
@@ -0,0 +1,2102 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bloomfilter::Bloom;
+use disk::{Clock, Compression, DiskLevel, Encoding, Env, PosixEnv, SystemClock};
+use error::{Error, Result};
+use skiplist::{Comparator, OrdComparator, RangeTombstone, Run, SkipList};
+
+use crate::background::Compactor;
+use crate::codec::Codec;
+use crate::compaction::{CompactionPolicy, Tiering};
+use crate::internal_key::{InternalKey, InternalKeyComparator, MAX_SEQUENCE};
+use crate::lock_manager::LockManager;
+use crate::manifest::Manifest;
+use crate::merge_operator::{self, MergeOperator};
+use crate::snapshot::{LsmSnapshot, Snapshot};
+use crate::transaction::{LsmTransaction, Transaction, TransactionOptions};
+use crate::value::StoredValue;
+use crate::wal::{Record, SyncPolicy, Wal};
+use crate::write_batch::{LsmWriteBatch, WriteBatch};
+
+/// A disk level of a `Db`, whose keys are the internal keys of the byte
+/// strings it stores.
+pub type DbLevel = DiskLevel<InternalKey<Vec<u8>>, StoredValue>;
+
+/// A version of a key read from a run: a value, or a tombstone if `None`.
+type KeyVersion = (InternalKey<Vec<u8>>, Option<StoredValue>);
+
+/// The locked state of a `Db`.
+type DbState<'a> = MutexGuard<'a, State<Vec<u8>, StoredValue>>;
+
+/// How long a write is delayed once the tree starts slowing writers down.
+const SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Tuning knobs of an `Lsm`, named after the parameters of the sLSM paper.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Number of pairs held by each memory run before it is sealed.
+    pub elts_per_run: usize,
+    /// Size in bytes of a memory run, its nodes along with the encoded keys
+    /// and values they hold, from which it is sealed even if it holds fewer
+    /// than `elts_per_run` pairs, or 0 for no limit.
+    pub bytes_per_run: usize,
+    /// Number of memory runs, *R*.
+    pub num_runs: usize,
+    /// Fraction of the memory runs (or of the runs of a disk level) merged
+    /// at once into the next level.
+    pub merged_frac: f64,
+    /// False positive rate of the bloom filters.
+    pub bf_fp: f64,
+    /// Number of pairs between two fence pointers of a disk run.
+    pub page_size: usize,
+    /// Number of runs on each disk level, *D*.
+    pub disk_runs_per_level: usize,
+    /// When the write-ahead log of the memory runs is synced.
+    pub sync_policy: SyncPolicy,
+    /// How the runs of the disk levels are merged.
+    pub compaction: Arc<dyn CompactionPolicy>,
+    /// Number of runs on disk level 0 from which every write is delayed,
+    /// giving the background thread time to merge them down.
+    pub level0_slowdown_runs: usize,
+    /// Number of runs on disk level 0 from which writes block until the
+    /// background thread merges them down.
+    pub level0_stop_runs: usize,
+    /// How the data blocks of each disk level are compressed, by level.
+    /// Deeper levels than listed use `Compression::default_for_level`.
+    pub compression_per_level: Vec<Compression>,
+    /// Whether reads verify the checksums of the data blocks of the disk
+    /// runs. Turning it off saves the checksum of every block read, at the
+    /// cost of returning corrupted pairs undetected.
+    pub verify_checksums: bool,
+    /// The file system every file of the tree is read from and written to.
+    pub env: Arc<dyn Env>,
+    /// The clock the values written with a time to live expire against.
+    pub clock: Arc<dyn Clock>,
+    /// Combines the operands written by `Db::merge`, which fails without
+    /// one. A tree holding operands needs the same one every time it is
+    /// opened.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            elts_per_run: 1024,
+            bytes_per_run: 0,
+            num_runs: 4,
+            merged_frac: 0.5,
+            bf_fp: 0.001,
+            page_size: 512,
+            disk_runs_per_level: 4,
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(100)),
+            compaction: Arc::new(Tiering),
+            level0_slowdown_runs: 8,
+            level0_stop_runs: 12,
+            compression_per_level: Vec::new(),
+            verify_checksums: true,
+            env: PosixEnv::shared(),
+            clock: SystemClock::shared(),
+            merge_operator: None,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Number of runs merged at once out of a level of `runs` runs.
+    #[inline]
+    pub(crate) fn merge_count(&self, runs: usize) -> usize {
+        ((runs as f64 * self.merged_frac).ceil() as usize).clamp(1, runs)
+    }
+
+    /// Number of sealed memory runs flushed at once to disk level 0.
+    #[inline]
+    pub(crate) fn num_to_merge(&self) -> usize {
+        self.merge_count(self.num_runs)
+    }
+
+    /// The compression of disk level `level`.
+    #[inline]
+    pub(crate) fn compression(&self, level: usize) -> Compression {
+        match self.compression_per_level.get(level) {
+            Some(&compression) => compression,
+            None => Compression::default_for_level(level),
+        }
+    }
+}
+
+/// The part of a `Db` shared with its background thread.
+pub(crate) struct Shared<K, V> {
+    state: Mutex<State<K, V>>,
+    /// Signalled whenever the state changes: a run is sealed, a flush or a
+    /// merge is done, or the tree is dropped.
+    pub cv: Condvar,
+    pub options: LsmOptions,
+    /// The order of the internal keys, by `user_comparator` first.
+    pub comparator: Arc<dyn Comparator<InternalKey<K>>>,
+    pub user_comparator: Arc<dyn Comparator<K>>,
+    pub dir: PathBuf,
+    /// The write-ahead log, written by the leader of a group commit without
+    /// holding the state lock. Locked after the state when both are.
+    wal: Mutex<Wal>,
+}
+
+impl<K, V> Shared<K, V> {
+    #[inline]
+    pub fn lock(&self) -> MutexGuard<'_, State<K, V>> {
+        self.state.lock().unwrap()
+    }
+
+    #[inline]
+    pub fn wal(&self) -> MutexGuard<'_, Wal> {
+        self.wal.lock().unwrap()
+    }
+}
+
+/// A batch of writes waiting in the write queue.
+pub(crate) struct PendingWrite<K, V> {
+    id: u64,
+    writes: Vec<Record<K, V>>,
+    /// Keys that must not have a version newer than sequence number `since`
+    /// for the batch to be written.
+    conflicts: Vec<K>,
+    since: u64,
+}
+
+pub(crate) struct State<K, V> {
+    /// The memory runs, oldest first. The runs before `active_run` are
+    /// sealed and wait to be flushed.
+    pub runs: Vec<SkipList<InternalKey<K>, Option<V>>>,
+    pub filters: Vec<Bloom<InternalKey<K>>>,
+    pub active_run: usize,
+    /// The disk levels as of the last flush or merge. The background thread
+    /// replaces them as a whole, so readers can go on using an older
+    /// snapshot without holding the lock.
+    pub disk_levels: Arc<Vec<DiskLevel<InternalKey<K>, V>>>,
+    /// Sequence number of the last write readers see.
+    pub last_sequence: u64,
+    /// The batches waiting to be written, oldest first. The writer of the
+    /// first one leads the group commit of the batches in front.
+    pub writers: VecDeque<PendingWrite<K, V>>,
+    /// The outcome of the batches written by the group commit of another
+    /// writer, by writer id.
+    pub written: HashMap<u64, Result<()>>,
+    pub next_writer: u64,
+    /// Number of live snapshots taken at each sequence number.
+    pub snapshots: BTreeMap<u64, usize>,
+    /// Set while the background thread is flushing or merging.
+    pub busy: bool,
+    pub paused: bool,
+    pub shutting_down: bool,
+    /// The error that stopped the background thread, if any.
+    pub bg_error: Option<Error>,
+}
+
+impl<K, V> State<K, V>
+where
+    K: Clone + Hash,
+    V: Clone,
+{
+    /// Whether enough memory runs are sealed to be flushed.
+    #[inline]
+    pub fn needs_flush(&self, options: &LsmOptions) -> bool {
+        self.active_run >= options.num_to_merge()
+    }
+
+    /// The sequence number of the oldest live snapshot, or the last one if
+    /// there is none. Merges keep every version a snapshot can still see.
+    #[inline]
+    pub fn smallest_snapshot(&self) -> u64 {
+        self.snapshots.keys().next().copied().unwrap_or(self.last_sequence)
+    }
+
+    /// Fail if the background thread stopped on an error. The error stays,
+    /// as the memory runs can no longer be flushed.
+    fn check_bg_error(&self) -> Result<()> {
+        match self.bg_error {
+            Some(Error::Io(ref e)) => {
+                let msg = format!("background compaction failed: {}", e);
+                Err(Error::Io(io::Error::new(e.kind(), msg)))
+            }
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn push_memory_run(&mut self, options: &LsmOptions, cmp: &Arc<dyn Comparator<InternalKey<K>>>) -> Result<()> {
+        let filter = Bloom::new_for_fp_rate(options.elts_per_run, options.bf_fp)?;
+        let mut run = SkipList::with_comparator(Arc::clone(cmp));
+        run.set_size(options.elts_per_run);
+        run.set_max_bytes(options.bytes_per_run);
+        self.runs.push(run);
+        self.filters.push(filter);
+        Ok(())
+    }
+
+    pub fn fill_memory_runs(&mut self, options: &LsmOptions, cmp: &Arc<dyn Comparator<InternalKey<K>>>) -> Result<()> {
+        while self.runs.len() < options.num_runs {
+            self.push_memory_run(options, cmp)?;
+        }
+        Ok(())
+    }
+
+    /// Add the write of `record` to memory run `run`: a range delete as a
+    /// range tombstone of the run, any other write as a pair. The run counts
+    /// the encoded length of the keys and values as the memory they own.
+    fn apply(&mut self, run: usize, record: Record<InternalKey<K>, V>, cmp: &dyn Comparator<InternalKey<K>>)
+    where
+        K: Encoding,
+        V: Encoding,
+    {
+        self.runs[run].add_heap_bytes(encoded_len(&record));
+        let (key, value) = match record {
+            Record::Put(key, value) => (key, Some(value)),
+            Record::Delete(key) => (key, None),
+            Record::DeleteRange(start, end) => return self.runs[run].delete_range(start, end),
+        };
+        self.filters[run].set_with(|h| cmp.hash_key(&key, h));
+        self.runs[run].insert_key(key, value);
+    }
+}
+
+/// Length of the encoded keys and value of `record`, the memory it is taken
+/// to own in a memory run.
+fn encoded_len<K: Encoding, V: Encoding>(record: &Record<K, V>) -> usize {
+    let mut buf = Vec::new();
+    match record {
+        Record::Put(key, value) => {
+            key.encode(&mut buf);
+            value.encode(&mut buf);
+        }
+        Record::Delete(key) => key.encode(&mut buf),
+        Record::DeleteRange(start, end) => {
+            start.encode(&mut buf);
+            end.encode(&mut buf);
+        }
+    }
+    buf.len()
+}
+
+/// The sequence number `record` is stamped with.
+fn sequence<K, V>(record: &Record<InternalKey<K>, V>) -> u64 {
+    match record {
+        Record::Put(key, _) | Record::Delete(key) | Record::DeleteRange(key, _) => key.seq,
+    }
+}
+
+/// The skiplist-based LSM tree.
+///
+/// Writes go to the active memory run. Once it holds `elts_per_run` pairs
+/// it is sealed and the next run becomes active. A background thread
+/// merges the oldest sealed runs into a new run of disk level 0, and merges
+/// full disk levels into the level below, creating new levels as needed,
+/// as the `CompactionPolicy` of the options decides.
+///
+/// When the background thread falls behind, writers are first slowed down,
+/// once the last memory run is active or disk level 0 holds
+/// `level0_slowdown_runs` runs, then blocked, once every memory run is full
+/// or level 0 holds `level0_stop_runs` runs.
+///
+/// A delete writes a tombstone, stored as a `None` value in the memory runs
+/// and as a valueless `KVpair` on disk, which hides every older value of the
+/// key until a merge into the bottom level drops it. A range delete writes a
+/// single range tombstone, kept aside from the pairs of its run, which hides
+/// the older versions of every key between its bounds; merges drop the pairs
+/// it covers, and drop it in turn when merging into the bottom level.
+///
+/// A value put with a time to live is stored with the time it expires at,
+/// by the `clock` of the options. Once expired it reads as a tombstone, and
+/// merges turn it into one.
+///
+/// A merge writes an operand for the `merge_operator` of the options to
+/// apply to the value of its key. Operands stack up as versions of the key
+/// in the memory runs; reads apply them to the version below them, and
+/// merges combine them once every snapshot sees them.
+///
+/// Every write is stamped with the next sequence number, stored with its key
+/// as an `InternalKey`, so a write adds a version of its key rather than
+/// overwriting it. Reads see the newest version of each key, and a
+/// `Snapshot` sees the newest one as of the sequence number it was taken
+/// at. Merges drop the versions that neither the latest state nor a live
+/// snapshot can see. A `Transaction` reads from a snapshot and commits its
+/// writes as one update.
+///
+/// Every write is appended to a write-ahead log before it is applied to the
+/// active run, so the memory runs are rebuilt when the tree is reopened. A
+/// `WriteBatch` is logged as one record and becomes visible at once.
+/// Concurrent writers queue their batches, and the first one writes the
+/// batches queued behind it along with its own, so they share one sync of
+/// the log.
+/// The disk runs of every level are recorded in a manifest, from which the
+/// disk levels are rebuilt.
+///
+/// Keys and values are byte strings, the keys being ordered by the
+/// `Comparator` the tree is opened with, which has to be the same every
+/// time the tree is opened. `Lsm` wraps a `Db` to store typed keys and
+/// values.
+pub struct Db {
+    shared: Arc<Shared<Vec<u8>, StoredValue>>,
+    worker: Option<JoinHandle<()>>,
+    /// The key locks of the pessimistic transactions.
+    locks: LockManager,
+}
+
+impl Db {
+    /// Open the tree stored in `dir`, ordering the keys bytewise.
+    pub fn open(dir: &Path, options: LsmOptions) -> Result<Self> {
+        Self::open_with_comparator(dir, options, Arc::new(OrdComparator))
+    }
+
+    /// Open the tree stored in `dir`, creating the directory if needed,
+    /// rebuild the disk levels from the manifest, replay the write-ahead
+    /// log into the memory runs and start the background thread.
+    pub fn open_with_comparator(
+        dir: &Path,
+        options: LsmOptions,
+        comparator: Arc<dyn Comparator<Vec<u8>>>,
+    ) -> Result<Self> {
+        if options.elts_per_run == 0 || options.num_runs == 0 || options.disk_runs_per_level == 0 {
+            return Err(Error::invalid_argument("elts_per_run, num_runs and disk_runs_per_level must be positive"));
+        }
+        if !(options.merged_frac > 0.0 && options.merged_frac <= 1.0) {
+            return Err(Error::invalid_argument("merged_frac must be in ]0, 1]"));
+        }
+        if options.level0_slowdown_runs > options.level0_stop_runs {
+            return Err(Error::invalid_argument("level0_slowdown_runs must not exceed level0_stop_runs"));
+        }
+
+        let user_comparator = comparator;
+        let comparator: Arc<dyn Comparator<InternalKey<Vec<u8>>>> =
+            Arc::new(InternalKeyComparator::new(Arc::clone(&user_comparator)));
+        let env = Arc::clone(&options.env);
+        env.create_dir_all(dir)?;
+        let manifest = Manifest::open(&*env, dir)?;
+        let levels = Compactor::load_levels(dir, &options, &comparator, &manifest)?;
+        let log_number = manifest.version().log_number;
+        let (wal, replayed) =
+            Wal::open::<InternalKey<Vec<u8>>, StoredValue>(env, dir, log_number, options.sync_policy)?;
+        let mut state = State {
+            runs: Vec::with_capacity(options.num_runs),
+            filters: Vec::with_capacity(options.num_runs),
+            active_run: 0,
+            disk_levels: Arc::new(levels.clone()),
+            last_sequence: manifest.version().last_sequence,
+            writers: VecDeque::new(),
+            written: HashMap::new(),
+            next_writer: 0,
+            snapshots: BTreeMap::new(),
+            busy: false,
+            paused: false,
+            shutting_down: false,
+            bg_error: None,
+        };
+        state.fill_memory_runs(&options, &comparator)?;
+
+        // one segment per memory run, the last one being the active run.
+        // More segments than memory runs are left when the tree was closed
+        // before the background thread caught up; it flushes them first.
+        state.active_run = replayed.len().saturating_sub(1);
+        for (i, records) in replayed.into_iter().enumerate() {
+            if i == state.runs.len() {
+                state.push_memory_run(&options, &comparator)?;
+            }
+            for record in records {
+                state.last_sequence = state.last_sequence.max(sequence(&record));
+                state.apply(i, record, &*comparator);
+            }
+        }
+
+        let locks = LockManager::new(Arc::clone(&user_comparator));
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            cv: Condvar::new(),
+            options,
+            comparator,
+            user_comparator,
+            dir: dir.to_path_buf(),
+            wal: Mutex::new(wal),
+        });
+        let compactor = Compactor::new(Arc::clone(&shared), manifest, levels);
+        let worker = thread::Builder::new()
+            .name("lsm-compaction".to_string())
+            .spawn(move || compactor.run())?;
+        Ok(Db {
+            shared,
+            worker: Some(worker),
+            locks,
+        })
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_queued(vec![Record::Put(key.to_vec(), StoredValue::new(value.to_vec()))], Vec::new(), 0)
+    }
+
+    /// Put `value` for `ttl`, rounded up to whole seconds of the clock of
+    /// the tree: once it is over, reads find the key absent, and merges drop
+    /// the value. A zero `ttl` is an invalid argument.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        if ttl.is_zero() {
+            return Err(Error::invalid_argument("a time to live must not be zero"));
+        }
+        let secs = ttl.as_secs().saturating_add(u64::from(ttl.subsec_nanos() > 0));
+        let expires_at = self.shared.options.clock.now().saturating_add(secs);
+        let value = StoredValue::expiring(value.to_vec(), expires_at);
+        self.write_queued(vec![Record::Put(key.to_vec(), value)], Vec::new(), 0)
+    }
+
+    /// Write `operand` for the merge operator to apply to the value of
+    /// `key` when it is read, without reading it now.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.merge_operator()?;
+        self.write_queued(vec![Record::Put(key.to_vec(), StoredValue::operand(operand.to_vec()))], Vec::new(), 0)
+    }
+
+    /// Delete `key` by writing a tombstone for it.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write_queued(vec![Record::Delete(key.to_vec())], Vec::new(), 0)
+    }
+
+    /// Delete every key from `start` included to `end` excluded by writing a
+    /// single range tombstone.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.write_queued(vec![Record::DeleteRange(start.to_vec(), end.to_vec())], Vec::new(), 0)
+    }
+
+    /// Apply the writes of `batch` as one update, logged as one record of
+    /// the write-ahead log.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.has_operands() {
+            self.merge_operator()?;
+        }
+        self.write_queued(batch.into_writes(), Vec::new(), 0)
+    }
+
+    /// Apply `writes` as one update, unless a version of a key of
+    /// `conflicts` is newer than sequence number `since`, which fails with
+    /// `Busy`.
+    pub(crate) fn commit(
+        &self,
+        writes: Vec<Record<Vec<u8>, StoredValue>>,
+        conflicts: Vec<Vec<u8>>,
+        since: u64,
+    ) -> Result<()> {
+        self.write_queued(writes, conflicts, since)
+    }
+
+    /// Queue a batch and wait until it is written, by this writer if it
+    /// comes first, or by the writer leading the group commit it joins.
+    fn write_queued(
+        &self,
+        writes: Vec<Record<Vec<u8>, StoredValue>>,
+        conflicts: Vec<Vec<u8>>,
+        since: u64,
+    ) -> Result<()> {
+        let mut state = self.shared.lock();
+        let id = state.next_writer;
+        state.next_writer += 1;
+        state.writers.push_back(PendingWrite {
+            id,
+            writes,
+            conflicts,
+            since,
+        });
+        loop {
+            if let Some(result) = state.written.remove(&id) {
+                return result;
+            }
+            if state.writers.front().map(|writer| writer.id) == Some(id) {
+                return self.lead_group(state);
+            }
+            state = self.shared.cv.wait(state).unwrap();
+        }
+    }
+
+    /// Write the first queued batch along with the batches queued behind it,
+    /// as one record of the write-ahead log synced once, and hand the
+    /// outcome to their writers.
+    ///
+    /// A batch with conflicts to check is written alone, checked under the
+    /// lock. The group takes no batch that would overflow the room left in
+    /// the active run, beyond the first one. The batches of the group stay
+    /// queued until they are written, so that no other writer leads a group
+    /// meanwhile.
+    fn lead_group<'a>(&'a self, state: DbState<'a>) -> Result<()> {
+        let mut state = match self.make_room(state) {
+            Ok(state) => state,
+            Err(e) => {
+                self.shared.lock().writers.pop_front();
+                self.shared.cv.notify_all();
+                return Err(e);
+            }
+        };
+
+        let options = &self.shared.options;
+        let run = &state.runs[state.active_run];
+        let room = options.elts_per_run.saturating_sub(run.num_elements() as usize);
+        let bytes_room = match options.bytes_per_run {
+            0 => usize::MAX,
+            max_bytes => max_bytes.saturating_sub(run.memory_usage()),
+        };
+        let mut writes = Vec::new();
+        let mut bytes = 0;
+        let mut members = 0;
+        for writer in state.writers.iter_mut() {
+            let alone = !writer.conflicts.is_empty();
+            let batch_bytes: usize = writer.writes.iter().map(encoded_len).sum();
+            let fits = writes.len() + writer.writes.len() <= room && bytes + batch_bytes <= bytes_room;
+            if members > 0 && (alone || !fits) {
+                break;
+            }
+            writes.append(&mut writer.writes);
+            bytes += batch_bytes;
+            members += 1;
+            if alone {
+                break;
+            }
+        }
+
+        let leader = &state.writers[0];
+        let result = match self.check_conflicts(&state, &leader.conflicts, leader.since) {
+            Ok(()) => {
+                let (locked, result) = self.log_and_apply(state, writes);
+                state = locked;
+                result
+            }
+            Err(e) => Err(e),
+        };
+        let followers: Vec<u64> = state.writers.drain(..members).skip(1).map(|writer| writer.id).collect();
+        for id in followers {
+            state.written.insert(id, result.clone());
+        }
+        self.shared.cv.notify_all();
+        result
+    }
+
+    /// Fail with `Busy` if a version of a key of `conflicts` is newer than
+    /// sequence number `since`.
+    fn check_conflicts(&self, state: &State<Vec<u8>, StoredValue>, conflicts: &[Vec<u8>], since: u64) -> Result<()> {
+        for key in conflicts {
+            let target = InternalKey::new(key.clone(), MAX_SEQUENCE);
+            let newest = match self.memory_version(state, &target) {
+                Some(version) => Some(version),
+                None => self.disk_version(&state.disk_levels, &target)?,
+            };
+            if let Some((found, _)) = newest {
+                if found.seq > since {
+                    return Err(Error::Busy(format!("key {:?} was written since sequence {}", key, since)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Log `writes`, stamped with the next sequence numbers, then add them
+    /// to the active run and make them visible to readers at once.
+    ///
+    /// The lock is released while logging: the leader of the group commit
+    /// is the only writer of the log and of the active run until it is
+    /// done, and readers do not see the writes before the last sequence
+    /// number moves past them.
+    fn log_and_apply<'a>(
+        &'a self,
+        state: DbState<'a>,
+        writes: Vec<Record<Vec<u8>, StoredValue>>,
+    ) -> (DbState<'a>, Result<()>) {
+        if writes.is_empty() {
+            return (state, Ok(()));
+        }
+        let first = state.last_sequence + 1;
+        let records: Vec<_> = writes
+            .into_iter()
+            .zip(first..)
+            .map(|(write, seq)| match write {
+                Record::Put(key, value) => Record::Put(InternalKey::new(key, seq), value),
+                Record::Delete(key) => Record::Delete(InternalKey::new(key, seq)),
+                Record::DeleteRange(start, end) => {
+                    Record::DeleteRange(InternalKey::new(start, seq), InternalKey::new(end, seq))
+                }
+            })
+            .collect();
+        drop(state);
+        let logged = self.shared.wal().append(&records);
+
+        let mut state = self.shared.lock();
+        if let Err(e) = logged {
+            return (state, Err(e));
+        }
+        let active_run = state.active_run;
+        state.last_sequence = first + records.len() as u64 - 1;
+        for record in records {
+            state.apply(active_run, record, &*self.shared.comparator);
+        }
+        (state, Ok(()))
+    }
+
+    /// Wait until the active memory run has room for a write, sealing it if
+    /// it is full, and slow the writer down if the background thread falls
+    /// behind.
+    fn make_room<'a>(
+        &'a self,
+        mut state: DbState<'a>,
+    ) -> Result<DbState<'a>> {
+        let options = &self.shared.options;
+        let mut delayed = false;
+        loop {
+            state.check_bg_error()?;
+            let active_full = state.runs[state.active_run].is_full();
+            if active_full && state.active_run + 1 < state.runs.len() {
+                self.shared.wal().new_segment()?;
+                state.active_run += 1;
+                self.shared.cv.notify_all();
+                continue;
+            }
+
+            let level0_runs = state.disk_levels[0].active_run;
+            if active_full || level0_runs >= options.level0_stop_runs {
+                state = self.shared.cv.wait(state).unwrap();
+                continue;
+            }
+            let last_run = state.active_run > 0 && state.active_run + 1 >= options.num_runs;
+            if !delayed && (last_run || level0_runs >= options.level0_slowdown_runs) {
+                delayed = true;
+                drop(state);
+                thread::sleep(SLOWDOWN_DELAY);
+                state = self.shared.lock();
+                continue;
+            }
+            return Ok(state);
+        }
+    }
+
+    /// Flush the write-ahead log to stable storage, whatever the sync policy.
+    pub fn sync(&self) -> Result<()> {
+        self.shared.wal().sync()
+    }
+
+    /// Look `key` up in the memory runs, newest first, then in the disk
+    /// levels, top to bottom. The first run holding a version of the key
+    /// decides, so a tombstone hides the values of older runs, unless a
+    /// newer range tombstone deletes that version. Merge operands apply to
+    /// the version below them.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_at(key, None)
+    }
+
+    /// Look `key` up as of sequence number `snapshot`, or as of the last
+    /// write if `None`: the newest version of the key not after it decides.
+    pub(crate) fn get_at(&self, key: &[u8], snapshot: Option<u64>) -> Result<Option<Vec<u8>>> {
+        // the merge operands on top of the version that decides, newest
+        // first.
+        let mut operands = Vec::new();
+        let (target, deleted_at, disk_levels) = {
+            let state = self.shared.lock();
+            let target = InternalKey::new(key.to_vec(), snapshot.unwrap_or(state.last_sequence));
+            let tombstones = state.runs[..=state.active_run].iter().flat_map(|run| run.range_tombstones());
+            let deleted_at = self.deleted_at(tombstones, &target);
+            for version in self.memory_versions(&state, &target) {
+                if let Some(base) = self.stack_version(&mut operands, version, deleted_at) {
+                    return self.apply_operands(key, operands, base);
+                }
+            }
+            (target, deleted_at, Arc::clone(&state.disk_levels))
+        };
+        let runs = disk_levels.iter().flat_map(|level| level.runs.iter());
+        let deleted_at = deleted_at.max(self.deleted_at(runs.flat_map(|run| run.range_tombstones()), &target));
+        let comparator = &self.shared.comparator;
+        for run in disk_levels.iter().flat_map(|level| level.runs.iter().rev()) {
+            if !run.bf.check_with(|h| comparator.hash_key(&target, h)) {
+                continue;
+            }
+            // sequence number 0 is older than every write.
+            for kv in run.range(&target, &InternalKey::new(key.to_vec(), 0))? {
+                if let Some(base) = self.stack_version(&mut operands, (kv.key.unwrap(), kv.value), deleted_at) {
+                    return self.apply_operands(key, operands, base);
+                }
+            }
+        }
+        self.apply_operands(key, operands, None)
+    }
+
+    /// Push `version` on the merge `operands` of its key if it is one, and
+    /// return `None` to go on with the older versions. Otherwise return what
+    /// the operands apply to: the value of `version`, or `None` if it is a
+    /// tombstone, has expired, or a range tombstone at sequence number
+    /// `deleted_at` deletes it.
+    fn stack_version(
+        &self,
+        operands: &mut Vec<Vec<u8>>,
+        version: KeyVersion,
+        deleted_at: u64,
+    ) -> Option<Option<Vec<u8>>> {
+        match version {
+            (key, _) if key.seq <= deleted_at => Some(None),
+            (_, Some(value)) if value.operand => {
+                operands.push(value.value);
+                None
+            }
+            (_, value) => Some(self.live(value)),
+        }
+    }
+
+    /// The value of `key` once its merge `operands`, newest first, apply to
+    /// `base`.
+    fn apply_operands(&self, key: &[u8], mut operands: Vec<Vec<u8>>, base: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        if operands.is_empty() {
+            return Ok(base);
+        }
+        operands.reverse();
+        let operands: Vec<_> = operands.iter().map(Vec::as_slice).collect();
+        merge_operator::full_merge(self.merge_operator()?, key, base.as_deref(), &operands).map(Some)
+    }
+
+    fn merge_operator(&self) -> Result<&dyn MergeOperator> {
+        self.shared.options.merge_operator.as_deref().ok_or_else(|| Error::invalid_argument("no merge operator is set"))
+    }
+
+    /// The bytes of `value`, unless it is a tombstone or has expired.
+    fn live(&self, value: Option<StoredValue>) -> Option<Vec<u8>> {
+        let now = self.shared.options.clock.now();
+        value.filter(|value| !value.is_expired(now)).map(|value| value.value)
+    }
+
+    /// Sequence number of the newest of `tombstones` not newer than `target`
+    /// that deletes its user key, or 0 if there is none.
+    fn deleted_at<'t>(
+        &self,
+        tombstones: impl Iterator<Item = &'t RangeTombstone<InternalKey<Vec<u8>>>>,
+        target: &InternalKey<Vec<u8>>,
+    ) -> u64 {
+        // sequence number 0 is older than every write.
+        let oldest = InternalKey::new(target.user_key.clone(), 0);
+        tombstones
+            .filter(|tombstone| tombstone.start.seq <= target.seq && self.shared.comparator.covers(tombstone, &oldest))
+            .map(|tombstone| tombstone.start.seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The versions of the key of `target` in the memory runs of `state`
+    /// that are not newer than the sequence number of `target`, newest
+    /// first.
+    fn memory_versions<'s>(
+        &'s self,
+        state: &'s State<Vec<u8>, StoredValue>,
+        target: &'s InternalKey<Vec<u8>>,
+    ) -> impl Iterator<Item = KeyVersion> + 's {
+        let comparator = &self.shared.comparator;
+        let user_comparator = &self.shared.user_comparator;
+        let same_key = move |version: &KeyVersion| {
+            user_comparator.compare(&version.0.user_key, &target.user_key) == Ordering::Equal
+        };
+        (0..=state.active_run)
+            .rev()
+            .filter(move |&i| state.filters[i].check_with(|h| comparator.hash_key(target, h)))
+            .flat_map(move |i| state.runs[i].range(Included(target), Unbounded).take_while(same_key))
+    }
+
+    /// The newest version of the key of `target` in the memory runs of
+    /// `state` that is not newer than the sequence number of `target`.
+    fn memory_version(&self, state: &State<Vec<u8>, StoredValue>, target: &InternalKey<Vec<u8>>) -> Option<KeyVersion> {
+        let comparator = &self.shared.comparator;
+        let user_comparator = &self.shared.user_comparator;
+        for i in (0..=state.active_run).rev() {
+            if !state.filters[i].check_with(|h| comparator.hash_key(target, h)) {
+                continue;
+            }
+            // the first version at or after the target is the newest one
+            // visible, if it has the key looked for.
+            match state.runs[i].range(Included(target), Unbounded).next() {
+                Some(version) if user_comparator.compare(&version.0.user_key, &target.user_key) == Ordering::Equal => {
+                    return Some(version)
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// The newest version of the key of `target` in the runs of `disk_levels`
+    /// that is not newer than the sequence number of `target`.
+    fn disk_version(&self, disk_levels: &[DbLevel], target: &InternalKey<Vec<u8>>) -> Result<Option<KeyVersion>> {
+        let comparator = &self.shared.comparator;
+        let user_comparator = &self.shared.user_comparator;
+        for run in disk_levels.iter().flat_map(|level| level.runs.iter().rev()) {
+            if !run.bf.check_with(|h| comparator.hash_key(target, h)) {
+                continue;
+            }
+            if let Some(kv) = run.seek(target)? {
+                let found = kv.key.unwrap();
+                if user_comparator.compare(&found.user_key, &target.user_key) == Ordering::Equal {
+                    return Ok(Some((found, kv.value)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// All the pairs with `key1 <= key < key2`, in key order.
+    pub fn range(&self, key1: &[u8], key2: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.range_at(key1, key2, None)
+    }
+
+    /// All the pairs with `key1 <= key < key2` as of sequence number
+    /// `snapshot`, or as of the last write if `None`, in key order.
+    pub(crate) fn range_at(
+        &self,
+        key1: &[u8],
+        key2: &[u8],
+        snapshot: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let comparator = &self.shared.comparator;
+        let user_comparator = &self.shared.user_comparator;
+        if user_comparator.compare(&key1.to_vec(), &key2.to_vec()) != Ordering::Less {
+            return Ok(Vec::new());
+        }
+        // every version of a key sorts after the key with the largest
+        // sequence number.
+        let lower = &InternalKey::new(key1.to_vec(), MAX_SEQUENCE);
+        let upper = &InternalKey::new(key2.to_vec(), MAX_SEQUENCE);
+
+        // every version of every key, and every range tombstone.
+        let mut ranged: Vec<KeyVersion> = Vec::new();
+        let mut tombstones = Vec::new();
+        let (seq, disk_levels) = {
+            let state = self.shared.lock();
+            for i in (0..=state.active_run).rev() {
+                ranged.extend(state.runs[i].range(Included(lower), Excluded(upper)));
+                tombstones.extend_from_slice(state.runs[i].range_tombstones());
+            }
+            (snapshot.unwrap_or(state.last_sequence), Arc::clone(&state.disk_levels))
+        };
+        for level in disk_levels.iter() {
+            for kv in level.get_elements_in_range(lower, upper)? {
+                ranged.push((kv.key.unwrap(), kv.value));
+            }
+            for run in level.runs.iter() {
+                tombstones.extend_from_slice(run.range_tombstones());
+            }
+        }
+
+        // the newest visible version of a key sorts first and decides, along
+        // with the merge operands on top of it, unless a visible range
+        // tombstone deletes it.
+        ranged.retain(|(key, _)| key.seq <= seq);
+        tombstones.retain(|tombstone| tombstone.start.seq <= seq);
+        ranged.sort_by(|a, b| comparator.compare(&a.0, &b.0));
+        let mut pairs = Vec::new();
+        let mut versions = ranged.into_iter().peekable();
+        while let Some(newest) = versions.next() {
+            let target = InternalKey::new(newest.0.user_key.clone(), seq);
+            let deleted_at = self.deleted_at(tombstones.iter(), &target);
+            let mut operands = Vec::new();
+            let mut base = self.stack_version(&mut operands, newest, deleted_at);
+            let same_key = |version: &KeyVersion| {
+                user_comparator.compare(&version.0.user_key, &target.user_key) == Ordering::Equal
+            };
+            while let Some(version) = versions.next_if(same_key) {
+                if base.is_none() {
+                    base = self.stack_version(&mut operands, version, deleted_at);
+                }
+            }
+            if let Some(value) = self.apply_operands(&target.user_key, operands, base.flatten())? {
+                pairs.push((target.user_key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// A snapshot of the tree as of the last write. Until it is dropped,
+    /// merges keep the versions it sees.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        let mut state = self.shared.lock();
+        let seq = state.last_sequence;
+        *state.snapshots.entry(seq).or_insert(0) += 1;
+        Snapshot::new(self, seq)
+    }
+
+    /// Forget a snapshot taken at sequence number `seq`.
+    pub(crate) fn release_snapshot(&self, seq: u64) {
+        let mut state = self.shared.lock();
+        if let Some(count) = state.snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                state.snapshots.remove(&seq);
+            }
+        }
+    }
+
+    /// Begin a transaction, reading the tree as of now.
+    pub fn transaction(&self, options: TransactionOptions) -> Transaction<'_> {
+        Transaction::new(self, options)
+    }
+
+    #[inline]
+    pub(crate) fn locks(&self) -> &LockManager {
+        &self.locks
+    }
+
+    /// The comparator ordering the keys of the tree.
+    #[inline]
+    pub fn user_comparator(&self) -> &Arc<dyn Comparator<Vec<u8>>> {
+        &self.shared.user_comparator
+    }
+
+    /// Sequence number of the last write, 0 for an empty tree.
+    pub fn latest_sequence(&self) -> u64 {
+        self.shared.lock().last_sequence
+    }
+
+    /// Number of pairs stored in the memory runs and on disk, counting every
+    /// version of a key and every tombstone.
+    pub fn num_elements(&self) -> usize {
+        let state = self.shared.lock();
+        let in_memory: i64 = state.runs.iter().map(|run| run.num_elements()).sum();
+        in_memory as usize + state.disk_levels.iter().map(|level| level.num_elements()).sum::<usize>()
+    }
+
+    /// Number of bytes taken by the memory runs.
+    pub fn memory_usage(&self) -> usize {
+        self.shared.lock().runs.iter().map(|run| run.memory_usage()).sum()
+    }
+
+    /// The disk levels as of the last flush or merge.
+    pub fn disk_levels(&self) -> Arc<Vec<DbLevel>> {
+        Arc::clone(&self.shared.lock().disk_levels)
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.shared.dir
+    }
+
+    #[inline]
+    pub fn options(&self) -> &LsmOptions {
+        &self.shared.options
+    }
+
+    /// The write amplification caused by the compaction policy with the
+    /// current number of disk levels.
+    pub fn write_amplification(&self) -> f64 {
+        let disk_levels = self.disk_levels();
+        let level0 = &disk_levels[0];
+        let compaction = &self.shared.options.compaction;
+        let size_ratio = compaction.size_ratio(level0.run_nums, level0.merge_size);
+        compaction.write_amplification(size_ratio, disk_levels.len())
+    }
+
+    /// Block until the background thread has flushed the sealed memory runs
+    /// and merged the full disk levels, or until it is paused. Returns the
+    /// error that stopped it, if any.
+    pub fn wait_for_merges(&self) -> Result<()> {
+        let mut state = self.shared.lock();
+        loop {
+            state.check_bg_error()?;
+            if state.paused || (!state.busy && !state.needs_flush(&self.shared.options)) {
+                return Ok(());
+            }
+            state = self.shared.cv.wait(state).unwrap();
+        }
+    }
+
+    /// Stop starting new flushes and merges, waiting for the running one to
+    /// finish. Writers stall once the memory runs are full.
+    pub fn pause_background_work(&self) {
+        let mut state = self.shared.lock();
+        state.paused = true;
+        while state.busy {
+            state = self.shared.cv.wait(state).unwrap();
+        }
+    }
+
+    pub fn continue_background_work(&self) {
+        self.shared.lock().paused = false;
+        self.shared.cv.notify_all();
+    }
+}
+
+impl Drop for Db {
+    /// Stop the background thread once it is done with its running flush or
+    /// merge, and sync the write-ahead log unless the sync policy leaves it
+    /// to the OS. The sealed memory runs left are replayed from the log on
+    /// the next open.
+    fn drop(&mut self) {
+        self.shared.lock().shutting_down = true;
+        self.shared.cv.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        if self.shared.options.sync_policy != SyncPolicy::Never {
+            let _ = self.shared.wal().sync();
+        }
+    }
+}
+
+/// A `Db` storing keys of type `K` and values of type `V`, converted to and
+/// from bytes by their `Codec`.
+///
+/// The codecs preserve order, so with the default bytewise comparator the
+/// keys are ordered as `K` orders them. A key or a value which does not
+/// decode, as when the tree was written with other types, is reported as an
+/// `InvalidArgument` error.
+pub struct Lsm<K, V> {
+    db: Db,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> Lsm<K, V> {
+    /// Open the tree stored in `dir`, ordering the keys by their encodings.
+    pub fn open(dir: &Path, options: LsmOptions) -> Result<Self> {
+        Db::open(dir, options).map(Self::from_db)
+    }
+
+    /// Open the tree stored in `dir`, ordering the encoded keys by
+    /// `comparator`.
+    pub fn open_with_comparator(
+        dir: &Path,
+        options: LsmOptions,
+        comparator: Arc<dyn Comparator<Vec<u8>>>,
+    ) -> Result<Self> {
+        Db::open_with_comparator(dir, options, comparator).map(Self::from_db)
+    }
+
+    pub fn from_db(db: Db) -> Self {
+        Lsm {
+            db,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The byte-oriented tree below.
+    #[inline]
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn put(&self, key: K, value: V) -> Result<()> {
+        self.db.put(&key.encode(), &value.encode())
+    }
+
+    /// Put `value` until `ttl` is over.
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Duration) -> Result<()> {
+        self.db.put_with_ttl(&key.encode(), &value.encode(), ttl)
+    }
+
+    /// Apply the writes of `batch` as one update.
+    pub fn write(&self, batch: LsmWriteBatch<K, V>) -> Result<()> {
+        self.db.write(batch.into_batch())
+    }
+
+    /// Delete every key from `start` included to `end` excluded.
+    pub fn delete_range(&self, start: &K, end: &K) -> Result<()> {
+        self.db.delete_range(&start.encode(), &end.encode())
+    }
+
+    /// Write `operand` for the merge operator to apply to the value of
+    /// `key`. The merge operator sees them encoded by their `Codec`.
+    pub fn merge(&self, key: &K, operand: V) -> Result<()> {
+        self.db.merge(&key.encode(), &operand.encode())
+    }
+
+    /// Delete `key` by writing a tombstone for it.
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.db.delete(&key.encode())
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.db.get(&key.encode())? {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// All the pairs with `key1 <= key < key2`, in key order.
+    pub fn range(&self, key1: &K, key2: &K) -> Result<Vec<(K, V)>> {
+        self.db
+            .range(&key1.encode(), &key2.encode())?
+            .into_iter()
+            .map(|(key, value)| Ok((decode(&key)?, decode(&value)?)))
+            .collect()
+    }
+
+    /// A snapshot of the tree as of the last write, reading typed pairs.
+    pub fn snapshot(&self) -> LsmSnapshot<'_, K, V> {
+        LsmSnapshot::new(self.db.snapshot())
+    }
+
+    /// Begin a transaction reading and writing typed pairs.
+    pub fn transaction(&self, options: TransactionOptions) -> LsmTransaction<'_, K, V> {
+        LsmTransaction::new(self.db.transaction(options))
+    }
+
+    pub fn latest_sequence(&self) -> u64 {
+        self.db.latest_sequence()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.db.sync()
+    }
+
+    pub fn num_elements(&self) -> usize {
+        self.db.num_elements()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.db.memory_usage()
+    }
+
+    pub fn disk_levels(&self) -> Arc<Vec<DbLevel>> {
+        self.db.disk_levels()
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        self.db.dir()
+    }
+
+    #[inline]
+    pub fn options(&self) -> &LsmOptions {
+        self.db.options()
+    }
+
+    pub fn write_amplification(&self) -> f64 {
+        self.db.write_amplification()
+    }
+
+    pub fn wait_for_merges(&self) -> Result<()> {
+        self.db.wait_for_merges()
+    }
+
+    pub fn pause_background_work(&self) {
+        self.db.pause_background_work()
+    }
+
+    pub fn continue_background_work(&self) {
+        self.db.continue_background_work()
+    }
+}
+
+pub(crate) fn decode<T: Codec>(bytes: &[u8]) -> Result<T> {
+    T::decode(bytes).ok_or_else(|| Error::invalid_argument("undecodable key or value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::hash::{Hash, Hasher};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use disk::{Compression, FaultInjectionEnv, ManualClock, MemEnv, PosixEnv, SystemClock};
+    use error::Error;
+    use skiplist::{Comparator, Run};
+
+    use super::{Db, Lsm, LsmOptions, PendingWrite};
+    use crate::codec::Codec;
+    use crate::compaction::{CompactionPolicy, LazyLeveling, Leveling, Tiering};
+    use crate::merge_operator::MergeOperator;
+    use crate::value::StoredValue;
+    use crate::wal::{Record, SyncPolicy};
+    use crate::write_batch::LsmWriteBatch;
+
+    fn options() -> LsmOptions {
+        LsmOptions {
+            elts_per_run: 10,
+            bytes_per_run: 0,
+            num_runs: 4,
+            merged_frac: 0.5,
+            bf_fp: 0.01,
+            page_size: 4,
+            disk_runs_per_level: 2,
+            sync_policy: SyncPolicy::Never,
+            compaction: Arc::new(Tiering),
+            level0_slowdown_runs: 8,
+            level0_stop_runs: 12,
+            compression_per_level: Vec::new(),
+            verify_checksums: true,
+            env: PosixEnv::shared(),
+            clock: SystemClock::shared(),
+            merge_operator: None,
+        }
+    }
+
+    fn active_run(lsm: &Lsm<i64, i64>) -> usize {
+        lsm.db.shared.lock().active_run
+    }
+
+    fn check_policy(compaction: Arc<dyn CompactionPolicy>) -> Lsm<i64, i64> {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            compaction,
+            disk_runs_per_level: 3,
+            ..options()
+        };
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options.clone()).unwrap();
+        for round in 0..3 {
+            for i in 0..500 {
+                lsm.put(i, i + round).unwrap();
+            }
+        }
+        for i in (0..500).step_by(5) {
+            lsm.delete(&i).unwrap();
+        }
+        drop(lsm);
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options).unwrap();
+        lsm.wait_for_merges().unwrap();
+        for i in 0..500 {
+            let expected = if i % 5 == 0 { None } else { Some(i + 2) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+        assert_eq!(lsm.range(&0, &6).unwrap(), vec![(1, 3), (2, 4), (3, 5), (4, 6)]);
+        lsm
+    }
+
+    #[test]
+    fn put_get_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..15 {
+            lsm.put(i, i * 2).unwrap();
+        }
+        assert!(lsm.disk_levels()[0].level_empty());
+        for i in 0..15 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i * 2));
+        }
+        assert_eq!(lsm.get(&15).unwrap(), None);
+    }
+
+    #[test]
+    fn flush_and_cascade() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..1000 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        assert!(lsm.disk_levels().len() > 2);
+        for i in 0..1000 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+        assert_eq!(lsm.num_elements(), 1000);
+    }
+
+    #[test]
+    fn newest_value_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for round in 0..5 {
+            for i in 0..100 {
+                lsm.put(i, i + round * 1000).unwrap();
+            }
+        }
+        for i in 0..100 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i + 4000));
+        }
+        let all = lsm.range(&10, &20).unwrap();
+        assert_eq!(all, (10..20).map(|i| (i, i + 4000)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshots_see_older_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..100 {
+            lsm.put(i, i).unwrap();
+        }
+        let snapshot = lsm.snapshot();
+        assert_eq!(snapshot.sequence(), 100);
+        for i in 0..100 {
+            lsm.put(i, i + 1000).unwrap();
+        }
+        for i in (0..100).step_by(2) {
+            lsm.delete(&i).unwrap();
+        }
+        lsm.put(500, 500).unwrap();
+        // the versions the snapshot sees go through flushes and merges.
+        lsm.wait_for_merges().unwrap();
+        assert!(lsm.disk_levels().len() > 1);
+
+        for i in 0..100 {
+            assert_eq!(snapshot.get(&i).unwrap(), Some(i));
+            let expected = if i % 2 == 0 { None } else { Some(i + 1000) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+        assert_eq!(snapshot.get(&500).unwrap(), None);
+        assert_eq!(snapshot.range(&10, &14).unwrap(), vec![(10, 10), (11, 11), (12, 12), (13, 13)]);
+        assert_eq!(lsm.range(&10, &14).unwrap(), vec![(11, 1011), (13, 1013)]);
+        drop(snapshot);
+        assert!(lsm.db.shared.lock().snapshots.is_empty());
+    }
+
+    #[test]
+    fn sequence_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+            for i in 0..45 {
+                lsm.put(i % 10, i).unwrap();
+            }
+            lsm.wait_for_merges().unwrap();
+            assert!(!lsm.disk_levels()[0].level_empty());
+            assert_eq!(lsm.latest_sequence(), 45);
+        }
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        assert_eq!(lsm.latest_sequence(), 45);
+        let snapshot = lsm.snapshot();
+        lsm.put(3, 0).unwrap();
+        assert_eq!(lsm.latest_sequence(), 46);
+        assert_eq!(snapshot.get(&3).unwrap(), Some(43));
+        assert_eq!(lsm.get(&3).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn write_batches_apply_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+            lsm.put(1, 1).unwrap();
+            let mut batch = LsmWriteBatch::new();
+            for i in 0..25 {
+                batch.put(i, i + 100);
+            }
+            batch.delete(&1);
+            batch.put(2, 2);
+            assert_eq!(batch.len(), 27);
+            let snapshot = lsm.snapshot();
+            lsm.write(batch).unwrap();
+            assert_eq!(lsm.latest_sequence(), 28);
+            assert_eq!(snapshot.get(&1).unwrap(), Some(1));
+            assert_eq!(snapshot.get(&0).unwrap(), None);
+            drop(snapshot);
+            lsm.write(LsmWriteBatch::new()).unwrap();
+            assert_eq!(lsm.latest_sequence(), 28);
+            lsm.sync().unwrap();
+        }
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        assert_eq!(lsm.get(&1).unwrap(), None);
+        assert_eq!(lsm.get(&2).unwrap(), Some(2));
+        for i in 3..25 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i + 100));
+        }
+    }
+
+    #[test]
+    fn concurrent_batches_are_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            sync_policy: SyncPolicy::Always,
+            ..options()
+        };
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options).unwrap();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let lsm = &lsm;
+                s.spawn(move || {
+                    for j in 1..=50 {
+                        let mut batch = LsmWriteBatch::new();
+                        batch.put(2 * t, j);
+                        batch.put(2 * t + 1, j);
+                        lsm.write(batch).unwrap();
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..200 {
+                    let snapshot = lsm.snapshot();
+                    for t in 0..4 {
+                        assert_eq!(snapshot.get(&(2 * t)).unwrap(), snapshot.get(&(2 * t + 1)).unwrap());
+                    }
+                }
+            });
+        });
+        assert_eq!(lsm.latest_sequence(), 400);
+        for key in 0..8 {
+            assert_eq!(lsm.get(&key).unwrap(), Some(50));
+        }
+    }
+
+    #[test]
+    fn groups_do_not_overflow_the_active_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let mut state = db.shared.lock();
+        for id in 0..3 {
+            let writes = (0..4u8).map(|i| Record::Put(vec![id, i], StoredValue::new(Vec::new()))).collect();
+            state.writers.push_back(PendingWrite {
+                id: u64::from(id),
+                writes,
+                conflicts: Vec::new(),
+                since: 0,
+            });
+        }
+        state.next_writer = 3;
+        db.lead_group(state).unwrap();
+
+        // the third batch would take the run past its 10 pairs.
+        let state = db.shared.lock();
+        assert_eq!(state.runs[state.active_run].num_elements(), 8);
+        assert_eq!(state.writers.iter().map(|writer| writer.id).collect::<Vec<_>>(), vec![2]);
+        assert!(state.written[&1].is_ok());
+    }
+
+    #[test]
+    fn runs_sealed_by_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            elts_per_run: 1000,
+            bytes_per_run: 2048,
+            ..options()
+        };
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options).unwrap();
+        for i in 0..200 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        assert!(!lsm.disk_levels()[0].level_empty());
+        assert!(lsm.memory_usage() < 4 * 2048 + 1024);
+        for i in 0..200 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn large_values_seal_runs_sooner() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            elts_per_run: 1000,
+            bytes_per_run: 16 * 1024,
+            ..options()
+        };
+        let lsm: Lsm<i64, Vec<u8>> = Lsm::open(dir.path(), options.clone()).unwrap();
+        for i in 0..8 {
+            lsm.put(i, vec![0; 8 * 1024]).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        assert!(!lsm.disk_levels()[0].level_empty());
+        assert!(lsm.memory_usage() >= 8 * 1024);
+
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, Vec<u8>> = Lsm::open(dir.path(), options).unwrap();
+        for i in 0..8 {
+            lsm.put(i, vec![0; 8]).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        assert!(lsm.disk_levels().iter().all(|level| level.level_empty()));
+    }
+
+    #[test]
+    fn delete_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        lsm.put(1, 1).unwrap();
+        lsm.put(2, 2).unwrap();
+        lsm.delete(&1).unwrap();
+        assert_eq!(lsm.get(&1).unwrap(), None);
+        assert_eq!(lsm.get(&2).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn delete_hides_flushed_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..200 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        assert!(!lsm.disk_levels()[0].level_empty());
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            lsm.delete(&i).unwrap();
+        }
+        for i in 0..200 {
+            let expected = if i % 3 == 0 { None } else { Some(i) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+        assert_eq!(lsm.range(&0, &10).unwrap(), vec![(1, 1), (2, 2), (4, 4), (5, 5), (7, 7), (8, 8)]);
+
+        // push the tombstones down through every level.
+        for i in 1000..3000 {
+            lsm.put(i, i).unwrap();
+        }
+        for i in 0..200 {
+            let expected = if i % 3 == 0 { None } else { Some(i) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn delete_then_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..100 {
+            lsm.put(i, i).unwrap();
+        }
+        for i in 0..100 {
+            lsm.delete(&i).unwrap();
+        }
+        for i in 0..50 {
+            lsm.put(i, -i).unwrap();
+        }
+        for i in 200..400 {
+            lsm.put(i, i).unwrap();
+        }
+        for i in 0..100 {
+            let expected = if i < 50 { Some(-i) } else { None };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn delete_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..200 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        assert!(!lsm.disk_levels()[0].level_empty());
+        lsm.put(300, 300).unwrap();
+        let snapshot = lsm.snapshot();
+        lsm.delete_range(&50, &150).unwrap();
+        lsm.delete_range(&290, &310).unwrap();
+        lsm.put(100, -100).unwrap();
+
+        let check = |lsm: &Lsm<i64, i64>| {
+            for i in 0..200 {
+                let expected = match i {
+                    100 => Some(-100),
+                    50..=149 => None,
+                    _ => Some(i),
+                };
+                assert_eq!(lsm.get(&i).unwrap(), expected);
+            }
+            assert_eq!(lsm.get(&300).unwrap(), None);
+            assert_eq!(lsm.range(&48, &152).unwrap(), vec![(48, 48), (49, 49), (100, -100), (150, 150), (151, 151)]);
+        };
+        check(&lsm);
+        assert_eq!(snapshot.get(&60).unwrap(), Some(60));
+        assert_eq!(snapshot.get(&300).unwrap(), Some(300));
+        assert_eq!(snapshot.range(&49, &52).unwrap(), vec![(49, 49), (50, 50), (51, 51)]);
+
+        // the tombstones go through flushes and merges, and keep hiding the
+        // older versions from the latest state but not from the snapshot.
+        for i in 1000..2000 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        check(&lsm);
+        assert_eq!(snapshot.get(&60).unwrap(), Some(60));
+        drop(snapshot);
+
+        for i in 2000..3000 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        check(&lsm);
+        lsm.put(60, 60).unwrap();
+        assert_eq!(lsm.get(&60).unwrap(), Some(60));
+    }
+
+    #[test]
+    fn delete_range_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+            for i in 0..25 {
+                lsm.put(i, i).unwrap();
+            }
+            let mut batch = LsmWriteBatch::new();
+            batch.delete_range(&5, &10);
+            batch.put(7, -7);
+            lsm.write(batch).unwrap();
+            lsm.delete_range(&20, &40).unwrap();
+            lsm.sync().unwrap();
+        }
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        assert_eq!(lsm.latest_sequence(), 28);
+        for i in 0..25 {
+            let expected = match i {
+                7 => Some(-7),
+                5..=9 | 20..=24 => None,
+                _ => Some(i),
+            };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn values_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(1000));
+        let options = LsmOptions { clock: clock.clone(), ..options() };
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options).unwrap();
+        for i in 0..50 {
+            lsm.put_with_ttl(i, i, Duration::from_secs(10)).unwrap();
+        }
+        lsm.put_with_ttl(50, 50, Duration::from_secs(100)).unwrap();
+        lsm.put(51, 51).unwrap();
+        lsm.put(52, 52).unwrap();
+        lsm.put_with_ttl(52, -52, Duration::from_secs(5)).unwrap();
+        assert_eq!(lsm.get(&0).unwrap(), Some(0));
+        assert_eq!(lsm.get(&52).unwrap(), Some(-52));
+
+        // a time to live under a second lasts until the next one.
+        lsm.put_with_ttl(53, 53, Duration::from_millis(500)).unwrap();
+        assert_eq!(lsm.get(&53).unwrap(), Some(53));
+        clock.advance(1);
+        assert_eq!(lsm.get(&53).unwrap(), None);
+        assert!(matches!(lsm.put_with_ttl(54, 54, Duration::ZERO), Err(Error::InvalidArgument(_))));
+        lsm.put_with_ttl(55, 55, Duration::MAX).unwrap();
+
+        clock.advance(10);
+        let check = |lsm: &Lsm<i64, i64>| {
+            for i in 0..50 {
+                assert_eq!(lsm.get(&i).unwrap(), None);
+            }
+            // an expired value still hides the older ones.
+            assert_eq!(lsm.get(&52).unwrap(), None);
+            assert_eq!(lsm.range(&0, &100).unwrap(), vec![(50, 50), (51, 51), (55, 55)]);
+        };
+        check(&lsm);
+
+        // merges turn the expired values into tombstones.
+        for i in 1000..3000 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        check(&lsm);
+        for level in lsm.disk_levels().iter() {
+            for run in level.runs.iter() {
+                for kv in run.iter() {
+                    let kv = kv.unwrap();
+                    let key = i64::decode(&kv.key.unwrap().user_key).unwrap();
+                    assert!(!(0..50).contains(&key) || kv.value.is_none());
+                }
+            }
+        }
+        clock.advance(100);
+        assert_eq!(lsm.get(&50).unwrap(), None);
+        assert_eq!(lsm.get(&55).unwrap(), Some(55));
+    }
+
+    /// Adds its operands to the value, as `i64` counters.
+    #[derive(Debug)]
+    struct Counter;
+
+    impl MergeOperator for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+            let mut sum = existing.map_or(Some(0), i64::decode)?;
+            for operand in operands {
+                sum += i64::decode(operand)?;
+            }
+            Some(sum.encode())
+        }
+
+        fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+            Some((i64::decode(older)? + i64::decode(newer)?).encode())
+        }
+    }
+
+    #[test]
+    fn merge_operands() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions { merge_operator: Some(Arc::new(Counter)), ..options() };
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options.clone()).unwrap();
+            lsm.put(1, 100).unwrap();
+            for _ in 0..100 {
+                lsm.merge(&1, 1).unwrap();
+            }
+            let snapshot = lsm.snapshot();
+            lsm.merge(&1, 1000).unwrap();
+            lsm.merge(&2, 7).unwrap();
+            lsm.put(3, 3).unwrap();
+            lsm.delete(&3).unwrap();
+            lsm.merge(&3, -3).unwrap();
+            let mut batch = LsmWriteBatch::new();
+            batch.merge(&4, 4);
+            batch.merge(&4, 4);
+            lsm.write(batch).unwrap();
+
+            let check = |lsm: &Lsm<i64, i64>| {
+                assert_eq!(lsm.get(&1).unwrap(), Some(1200));
+                assert_eq!(lsm.get(&2).unwrap(), Some(7));
+                assert_eq!(lsm.get(&3).unwrap(), Some(-3));
+                assert_eq!(lsm.range(&0, &10).unwrap(), vec![(1, 1200), (2, 7), (3, -3), (4, 8)]);
+            };
+            check(&lsm);
+            assert_eq!(snapshot.get(&1).unwrap(), Some(200));
+            assert_eq!(snapshot.range(&0, &10).unwrap(), vec![(1, 200)]);
+
+            // merges combine the operands no snapshot tells apart.
+            for i in 1000..2000 {
+                lsm.put(i, i).unwrap();
+            }
+            lsm.wait_for_merges().unwrap();
+            check(&lsm);
+            assert_eq!(snapshot.get(&1).unwrap(), Some(200));
+            drop(snapshot);
+            for i in 2000..3000 {
+                lsm.put(i, i).unwrap();
+            }
+            lsm.wait_for_merges().unwrap();
+            check(&lsm);
+            let levels = lsm.disk_levels();
+            let runs: Vec<_> = levels.iter().flat_map(|level| level.runs.iter()).collect();
+            let versions = runs
+                .iter()
+                .flat_map(|run| run.iter().map(|kv| kv.unwrap().key.unwrap()))
+                .filter(|key| key.user_key == 1i64.encode())
+                .count();
+            assert!(versions <= runs.len());
+            lsm.merge(&1, 1).unwrap();
+            lsm.sync().unwrap();
+        }
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options.clone()).unwrap();
+        assert_eq!(lsm.get(&1).unwrap(), Some(1201));
+        assert_eq!(lsm.get(&4).unwrap(), Some(8));
+        drop(lsm);
+        let options = LsmOptions { merge_operator: None, ..options };
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options).unwrap();
+        assert!(matches!(lsm.get(&1), Err(Error::InvalidArgument(_))));
+        assert!(matches!(lsm.merge(&1, 1), Err(Error::InvalidArgument(_))));
+        let mut batch = LsmWriteBatch::new();
+        batch.merge(&1, 1);
+        assert!(matches!(lsm.write(batch), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn reopen_replays_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+            for i in 0..35 {
+                lsm.put(i, i).unwrap();
+            }
+            lsm.delete(&3).unwrap();
+            lsm.put(40, 40).unwrap();
+            lsm.sync().unwrap();
+        }
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..35 {
+            let expected = if i == 3 { None } else { Some(i) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+        assert_eq!(lsm.get(&40).unwrap(), Some(40));
+    }
+
+    #[test]
+    fn reopen_rebuilds_disk_levels() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape: Vec<usize> = {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+            for i in 0..1000 {
+                lsm.put(i, i).unwrap();
+            }
+            for i in (0..1000).step_by(7) {
+                lsm.delete(&i).unwrap();
+            }
+            lsm.sync().unwrap();
+            lsm.wait_for_merges().unwrap();
+            lsm.disk_levels().iter().map(|level| level.active_run).collect()
+        };
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        assert_eq!(lsm.disk_levels().iter().map(|level| level.active_run).collect::<Vec<_>>(), shape);
+        for i in 0..1000 {
+            let expected = if i % 7 == 0 { None } else { Some(i) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn unlisted_runs_are_deleted_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+            for i in 0..100 {
+                lsm.put(i, i).unwrap();
+            }
+        }
+        // a run written by a merge that crashed before logging it.
+        let orphan = dir.path().join("C_3_99.txt");
+        std::fs::write(&orphan, b"partial").unwrap();
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        assert!(!orphan.exists());
+        assert_eq!(lsm.get(&42).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn tiering() {
+        let lsm = check_policy(Arc::new(Tiering));
+        assert!(lsm.disk_levels().iter().any(|level| level.active_run > 1));
+        assert_eq!(lsm.write_amplification(), lsm.disk_levels().len() as f64);
+    }
+
+    #[test]
+    fn leveling() {
+        let lsm = check_policy(Arc::new(Leveling));
+        assert!(lsm.disk_levels().iter().all(|level| level.active_run <= 1));
+        assert!(lsm.write_amplification() > lsm.disk_levels().len() as f64);
+    }
+
+    #[test]
+    fn lazy_leveling() {
+        let lsm = check_policy(Arc::new(LazyLeveling));
+        assert_eq!(lsm.disk_levels().last().unwrap().active_run, 1);
+        assert!(lsm.disk_levels().len() > 1);
+    }
+
+    #[test]
+    fn compression_per_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            compression_per_level: vec![Compression::None, Compression::Zstd(1)],
+            ..options()
+        };
+        let lsm: Lsm<i64, String> = Lsm::open(dir.path(), options.clone()).unwrap();
+        for i in 0..1000 {
+            lsm.put(i, format!("value of {}", i % 10)).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        let compressions: Vec<Compression> = lsm.disk_levels().iter().map(|level| level.compression).collect();
+        assert!(compressions.len() > 2);
+        assert_eq!(compressions[..3], [Compression::None, Compression::Zstd(1), Compression::default_for_level(2)]);
+        drop(lsm);
+
+        let lsm: Lsm<i64, String> = Lsm::open(dir.path(), options).unwrap();
+        for i in (0..1000).step_by(13) {
+            assert_eq!(lsm.get(&i).unwrap(), Some(format!("value of {}", i % 10)));
+        }
+    }
+
+    #[test]
+    fn flushed_segments_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        for i in 0..41 {
+            lsm.put(i, i).unwrap();
+        }
+        lsm.wait_for_merges().unwrap();
+        // the segments of the flushed runs are gone, one is left per memory
+        // run still in use.
+        assert!(!dir.path().join("W_0.log").exists());
+        assert!(!dir.path().join("W_1.log").exists());
+        let state = lsm.db.shared.lock();
+        assert_eq!(lsm.db.shared.wal().segments().len(), state.active_run + 1);
+    }
+
+    #[test]
+    fn writes_stall_until_runs_are_flushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm: Lsm<i64, i64> = Lsm::open(dir.path(), options()).unwrap();
+        lsm.pause_background_work();
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                for i in 0..100 {
+                    lsm.put(i, i).unwrap();
+                }
+            });
+            // every memory run fills up, then the writer blocks.
+            while lsm.num_elements() < 40 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            thread::sleep(Duration::from_millis(20));
+            assert!(!writer.is_finished());
+            assert_eq!(lsm.num_elements(), 40);
+            assert_eq!(active_run(&lsm), 3);
+
+            lsm.continue_background_work();
+            writer.join().unwrap();
+        });
+        lsm.wait_for_merges().unwrap();
+        assert!(!lsm.disk_levels()[0].level_empty());
+        for i in 0..100 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn bytes_and_tuple_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm: Lsm<(String, i32), String> = Lsm::open(dir.path(), options()).unwrap();
+            for user in ["bob", "al", "alice"].iter() {
+                for i in -20..20 {
+                    lsm.put((user.to_string(), i), format!("{}{}", user, i)).unwrap();
+                }
+            }
+            lsm.wait_for_merges().unwrap();
+            let al: Vec<(String, i32)> = lsm
+                .range(&("al".to_owned(), i32::MIN), &("al\0".to_owned(), i32::MIN))
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(al, (-20..20).map(|i| ("al".to_owned(), i)).collect::<Vec<_>>());
+            assert_eq!(lsm.get(&("alice".to_owned(), -3)).unwrap(), Some("alice-3".to_owned()));
+        }
+
+        // the same tree seen as bytes, and as the wrong types.
+        let db = Db::open(dir.path(), options()).unwrap();
+        let key = ("bob".to_owned(), 7).encode();
+        assert_eq!(db.get(&key).unwrap(), Some("bob7".to_owned().encode()));
+        db.delete(&key).unwrap();
+        db.put(b"raw", b"").unwrap();
+        assert_eq!(db.get(&key).unwrap(), None);
+        assert_eq!(db.get(b"raw").unwrap(), Some(Vec::new()));
+
+        let lsm: Lsm<u64, String> = Lsm::from_db(db);
+        assert_eq!(lsm.get(&0).unwrap(), None);
+        assert!(matches!(lsm.range(&0, &u64::MAX), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn crash_drops_unsynced_writes() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let options = LsmOptions {
+            sync_policy: SyncPolicy::Interval(Duration::from_secs(3600)),
+            env: env.clone(),
+            ..options()
+        };
+        let dir = Path::new("/db");
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir, options.clone()).unwrap();
+            for i in 0..100 {
+                lsm.put(i, i).unwrap();
+            }
+            lsm.wait_for_merges().unwrap();
+            lsm.sync().unwrap();
+            for i in 100..105 {
+                lsm.put(i, i).unwrap();
+            }
+            // the machine crashes before the tree is dropped, which syncs.
+            env.drop_unsynced_data().unwrap();
+        }
+
+        let lsm: Lsm<i64, i64> = Lsm::open(dir, options.clone()).unwrap();
+        assert!(lsm.disk_levels().iter().any(|level| !level.level_empty()));
+        assert_eq!(lsm.range(&0, &200).unwrap(), (0..100).map(|i| (i, i)).collect::<Vec<_>>());
+
+        // a write torn by a full disk is not replayed, nor are the writes
+        // after it lost.
+        env.set_write_budget(Some(3));
+        assert!(matches!(lsm.put(201, 201), Err(Error::Io(_))));
+        env.set_write_budget(None);
+        lsm.put(202, 202).unwrap();
+        // a write that can not be cut from the log fails the writes after it.
+        env.set_fail_writes(true);
+        assert!(matches!(lsm.put(200, 200), Err(Error::Io(_))));
+        env.set_fail_writes(false);
+        assert!(lsm.put(203, 203).is_err());
+        drop(lsm);
+        let lsm: Lsm<i64, i64> = Lsm::open(dir, options).unwrap();
+        assert_eq!(lsm.get(&99).unwrap(), Some(99));
+        assert_eq!(lsm.get(&201).unwrap(), None);
+        assert_eq!(lsm.get(&202).unwrap(), Some(202));
+    }
+
+    #[test]
+    fn idle_log_is_synced_after_the_interval() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let options = LsmOptions {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(20)),
+            env: env.clone(),
+            ..options()
+        };
+        let dir = Path::new("/db");
+        {
+            let lsm: Lsm<i64, i64> = Lsm::open(dir, options.clone()).unwrap();
+            lsm.put(1, 1).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            env.drop_unsynced_data().unwrap();
+        }
+        let lsm: Lsm<i64, i64> = Lsm::open(dir, options).unwrap();
+        assert_eq!(lsm.get(&1).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn invalid_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let bad = [
+            LsmOptions { num_runs: 0, ..options() },
+            LsmOptions { merged_frac: 1.5, ..options() },
+            LsmOptions { bf_fp: 0.0, ..options() },
+            LsmOptions { level0_slowdown_runs: 9, level0_stop_runs: 2, ..options() },
+        ];
+        for options in bad.iter() {
+            let err = Db::open(dir.path(), options.clone()).err().unwrap();
+            assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+        }
+    }
+
+    struct CaseInsensitive;
+
+    // compares encoded strings, whose escapes are not letters.
+    impl Comparator<Vec<u8>> for CaseInsensitive {
+        fn name(&self) -> &str {
+            "case-insensitive"
+        }
+
+        fn compare(&self, a: &Vec<u8>, b: &Vec<u8>) -> Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+
+        fn hash_key(&self, key: &Vec<u8>, mut state: &mut dyn Hasher) {
+            key.to_ascii_lowercase().hash(&mut state)
+        }
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let dir = tempfile::tempdir().unwrap();
+        let comparator: Arc<dyn Comparator<Vec<u8>>> = Arc::new(CaseInsensitive);
+        let check = |lsm: &Lsm<String, i64>| {
+            assert_eq!(lsm.get(&"kEy005".to_owned()).unwrap(), Some(-5));
+            assert_eq!(lsm.get(&"KEY007".to_owned()).unwrap(), None);
+            assert_eq!(lsm.get(&"key150".to_owned()).unwrap(), Some(150));
+            let values: Vec<i64> = lsm
+                .range(&"KEY004".to_owned(), &"key009".to_owned())
+                .unwrap()
+                .into_iter()
+                .map(|(_, value)| value)
+                .collect();
+            assert_eq!(values, vec![4, -5, 6, 8]);
+        };
+        {
+            let lsm = Lsm::open_with_comparator(dir.path(), options(), Arc::clone(&comparator)).unwrap();
+            for i in 0..200 {
+                lsm.put(format!("Key{:03}", i), i).unwrap();
+            }
+            lsm.put("KEY005".to_owned(), -5).unwrap();
+            lsm.delete(&"key007".to_owned()).unwrap();
+            lsm.wait_for_merges().unwrap();
+            assert!(lsm.disk_levels().iter().any(|level| !level.level_empty()));
+            check(&lsm);
+        }
+
+        // the disk runs were sorted by the other comparator.
+        let err = Lsm::<String, i64>::open(dir.path(), options()).err().unwrap();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        let lsm = Lsm::open_with_comparator(dir.path(), options(), comparator).unwrap();
+        check(&lsm);
+    }
+}
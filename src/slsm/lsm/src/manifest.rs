@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use disk::{DiskRun, Encoding, Env, WritableFile};
+use error::{Error, Result};
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+const TAG_ADD_RUN: u8 = 1;
+const TAG_DELETE_RUN: u8 = 2;
+const TAG_LOG_NUMBER: u8 = 3;
+const TAG_NEXT_RUN_ID: u8 = 4;
+const TAG_LAST_SEQUENCE: u8 = 5;
+// crc32c of the record, then length of the record body.
+const RECORD_HEADER: usize = 8;
+
+/// What the manifest knows of a live disk run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunMeta<K> {
+    pub level: usize,
+    pub run_id: usize,
+    pub min_key: K,
+    pub max_key: K,
+    pub entries: u64,
+    pub bloom_bits: u64,
+    pub bloom_hashes: u32,
+}
+
+impl<K> RunMeta<K>
+where
+    K: Clone + Hash + Encoding,
+{
+    /// Describe a non-empty run.
+    pub fn of<V: Clone + Encoding>(run: &DiskRun<K, V>) -> Self {
+        RunMeta {
+            level: run.level(),
+            run_id: run.run_id(),
+            min_key: run.min_key.clone().expect("empty disk run"),
+            max_key: run.max_key.clone().expect("empty disk run"),
+            entries: run.get_capacity() as u64,
+            bloom_bits: run.bf.number_of_bits(),
+            bloom_hashes: run.bf.number_of_hash_functions(),
+        }
+    }
+
+    /// Fail unless `run` is the run described. Keys are compared by their
+    /// encoding, as they need not implement `Eq`.
+    pub fn check<V: Clone + Encoding>(&self, run: &DiskRun<K, V>) -> Result<()> {
+        let matches = run.min_key.is_some() && {
+            let found = RunMeta::of(run);
+            let encoded = |key: &K| {
+                let mut buf = Vec::new();
+                key.encode(&mut buf);
+                buf
+            };
+            (found.level, found.run_id, found.entries, found.bloom_bits, found.bloom_hashes)
+                == (self.level, self.run_id, self.entries, self.bloom_bits, self.bloom_hashes)
+                && encoded(&found.min_key) == encoded(&self.min_key)
+                && encoded(&found.max_key) == encoded(&self.max_key)
+        };
+        if !matches {
+            return Err(Error::corruption(run.path(), 0, "run does not match the manifest"));
+        }
+        Ok(())
+    }
+}
+
+/// A set of changes to the live runs, logged and applied atomically.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionEdit<K> {
+    pub added: Vec<RunMeta<K>>,
+    /// `(level, run_id)` of the runs deleted.
+    pub deleted: Vec<(usize, usize)>,
+    /// Id of the oldest write-ahead log segment still to replay.
+    pub log_number: Option<u64>,
+    pub next_run_id: Option<usize>,
+    /// Sequence number of the last write whose run is on disk.
+    pub last_sequence: Option<u64>,
+}
+
+impl<K> Default for VersionEdit<K> {
+    fn default() -> Self {
+        VersionEdit {
+            added: Vec::new(),
+            deleted: Vec::new(),
+            log_number: None,
+            next_run_id: None,
+            last_sequence: None,
+        }
+    }
+}
+
+/// The live runs, log position and last sequence number, as rebuilt from
+/// the manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version<K> {
+    /// Keyed by `(level, run_id)`, so the runs of a level are oldest first.
+    pub runs: BTreeMap<(usize, usize), RunMeta<K>>,
+    pub log_number: u64,
+    pub next_run_id: usize,
+    pub last_sequence: u64,
+}
+
+impl<K: Clone> Version<K> {
+    fn new() -> Self {
+        Version {
+            runs: BTreeMap::new(),
+            log_number: 0,
+            next_run_id: 0,
+            last_sequence: 0,
+        }
+    }
+
+    /// Describe why `edit` does not apply, if it deletes a run that is not
+    /// live or adds one that is.
+    fn check(&self, edit: &VersionEdit<K>) -> std::result::Result<(), String> {
+        for id in edit.deleted.iter() {
+            if !self.runs.contains_key(id) {
+                return Err(format!("edit deletes unknown run {:?}", id));
+            }
+        }
+        for meta in edit.added.iter() {
+            let id = (meta.level, meta.run_id);
+            if self.runs.contains_key(&id) && !edit.deleted.contains(&id) {
+                return Err(format!("edit adds run {:?} twice", id));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, edit: VersionEdit<K>) -> std::result::Result<(), String> {
+        self.check(&edit)?;
+        for id in edit.deleted {
+            self.runs.remove(&id);
+        }
+        for meta in edit.added {
+            self.runs.insert((meta.level, meta.run_id), meta);
+        }
+        if let Some(log_number) = edit.log_number {
+            self.log_number = log_number;
+        }
+        if let Some(next_run_id) = edit.next_run_id {
+            self.next_run_id = next_run_id;
+        }
+        if let Some(last_sequence) = edit.last_sequence {
+            self.last_sequence = last_sequence;
+        }
+        Ok(())
+    }
+
+    /// A single edit rebuilding this version from scratch.
+    fn snapshot(&self) -> VersionEdit<K> {
+        VersionEdit {
+            added: self.runs.values().cloned().collect(),
+            deleted: Vec::new(),
+            log_number: Some(self.log_number),
+            next_run_id: Some(self.next_run_id),
+            last_sequence: Some(self.last_sequence),
+        }
+    }
+}
+
+/// The log of the version edits of a database, in a file named `MANIFEST`.
+///
+/// Each edit is one record, laid out as
+///
+/// ```text
+/// crc32c: u32 | len: u32 | (tag: u8 | fields)*
+/// ```
+///
+/// so an edit torn by a crash is ignored as a whole. The manifest is
+/// rewritten with a single snapshot edit every time it is opened.
+pub struct Manifest<K> {
+    dir: PathBuf,
+    file: Box<dyn WritableFile>,
+    version: Version<K>,
+    buf: Vec<u8>,
+}
+
+impl<K: Clone + Encoding> Manifest<K> {
+    /// Replay the manifest of `dir`, an empty one if there is none, and start
+    /// a new manifest holding the resulting version.
+    pub fn open(env: &dyn Env, dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST);
+        let mut version = Version::new();
+        match env.read(&path) {
+            Ok(data) => {
+                let mut offset = 0;
+                while let Some((body, len)) = Self::next_record(&data[offset..]) {
+                    let edit = Self::decode_edit(body).map_err(|e| Error::locate(e, &path, offset as u64))?;
+                    version.apply(edit).map_err(|msg| Error::corruption(&path, offset as u64, msg))?;
+                    offset += len;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut buf = Vec::new();
+        Self::encode_record(&mut buf, &version.snapshot());
+        let tmp = dir.join(MANIFEST_TMP);
+        let mut file = env.create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync()?;
+        env.rename(&tmp, &path)?;
+        env.sync_dir(dir)?;
+
+        Ok(Manifest {
+            dir: dir.to_path_buf(),
+            file: env.open_append(&path)?,
+            version,
+            buf,
+        })
+    }
+
+    #[inline]
+    pub fn version(&self) -> &Version<K> {
+        &self.version
+    }
+
+    #[inline]
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(MANIFEST)
+    }
+
+    /// Reserve the id of a new run. The reservation is persisted by the next
+    /// logged edit.
+    pub fn new_run_id(&mut self) -> usize {
+        self.version.next_run_id += 1;
+        self.version.next_run_id - 1
+    }
+
+    /// Durably log `edit` and apply it to the current version.
+    pub fn log_edit(&mut self, mut edit: VersionEdit<K>) -> Result<()> {
+        edit.next_run_id = Some(self.version.next_run_id);
+        self.version.check(&edit).map_err(Error::InvalidArgument)?;
+        self.buf.clear();
+        Self::encode_record(&mut self.buf, &edit);
+        self.file.write_all(&self.buf)?;
+        self.file.sync()?;
+        self.version.apply(edit).map_err(Error::InvalidArgument)
+    }
+
+    fn encode_record(buf: &mut Vec<u8>, edit: &VersionEdit<K>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; RECORD_HEADER]);
+        for (level, run_id) in edit.deleted.iter() {
+            buf.push(TAG_DELETE_RUN);
+            buf.extend_from_slice(&(*level as u32).to_le_bytes());
+            buf.extend_from_slice(&(*run_id as u64).to_le_bytes());
+        }
+        for meta in edit.added.iter() {
+            buf.push(TAG_ADD_RUN);
+            buf.extend_from_slice(&(meta.level as u32).to_le_bytes());
+            buf.extend_from_slice(&(meta.run_id as u64).to_le_bytes());
+            buf.extend_from_slice(&meta.entries.to_le_bytes());
+            buf.extend_from_slice(&meta.bloom_bits.to_le_bytes());
+            buf.extend_from_slice(&meta.bloom_hashes.to_le_bytes());
+            Self::encode_key(buf, &meta.min_key);
+            Self::encode_key(buf, &meta.max_key);
+        }
+        if let Some(log_number) = edit.log_number {
+            buf.push(TAG_LOG_NUMBER);
+            buf.extend_from_slice(&log_number.to_le_bytes());
+        }
+        if let Some(next_run_id) = edit.next_run_id {
+            buf.push(TAG_NEXT_RUN_ID);
+            buf.extend_from_slice(&(next_run_id as u64).to_le_bytes());
+        }
+        if let Some(last_sequence) = edit.last_sequence {
+            buf.push(TAG_LAST_SEQUENCE);
+            buf.extend_from_slice(&last_sequence.to_le_bytes());
+        }
+
+        let len = (buf.len() - start - RECORD_HEADER) as u32;
+        buf[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32c::crc32c(&buf[start + 4..]);
+        buf[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn encode_key(buf: &mut Vec<u8>, key: &K) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        key.encode(buf);
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// The body of the first record of `data` and the length of the whole
+    /// record, or `None` if it is torn or corrupt.
+    fn next_record(data: &[u8]) -> Option<(&[u8], usize)> {
+        if data.len() < RECORD_HEADER {
+            return None;
+        }
+        let crc = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if data.len() < RECORD_HEADER + len || crc32c::crc32c(&data[4..RECORD_HEADER + len]) != crc {
+            return None;
+        }
+        Some((&data[RECORD_HEADER..RECORD_HEADER + len], RECORD_HEADER + len))
+    }
+
+    fn decode_edit(mut body: &[u8]) -> io::Result<VersionEdit<K>> {
+        let mut edit = VersionEdit::default();
+        while !body.is_empty() {
+            match take(&mut body, 1)?[0] {
+                TAG_ADD_RUN => edit.added.push(RunMeta {
+                    level: take_u32(&mut body)? as usize,
+                    run_id: take_u64(&mut body)? as usize,
+                    entries: take_u64(&mut body)?,
+                    bloom_bits: take_u64(&mut body)?,
+                    bloom_hashes: take_u32(&mut body)?,
+                    min_key: take_key(&mut body)?,
+                    max_key: take_key(&mut body)?,
+                }),
+                TAG_DELETE_RUN => {
+                    let level = take_u32(&mut body)? as usize;
+                    edit.deleted.push((level, take_u64(&mut body)? as usize));
+                }
+                TAG_LOG_NUMBER => edit.log_number = Some(take_u64(&mut body)?),
+                TAG_NEXT_RUN_ID => edit.next_run_id = Some(take_u64(&mut body)? as usize),
+                TAG_LAST_SEQUENCE => edit.last_sequence = Some(take_u64(&mut body)?),
+                tag => return Err(corruption(&format!("unknown manifest tag {}", tag))),
+            }
+        }
+        Ok(edit)
+    }
+}
+
+#[inline]
+fn corruption(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(corruption("truncated manifest edit"));
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+fn take_u32(data: &mut &[u8]) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(take(data, 4)?.try_into().unwrap()))
+}
+
+fn take_u64(data: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(take(data, 8)?.try_into().unwrap()))
+}
+
+fn take_key<K: Encoding>(data: &mut &[u8]) -> io::Result<K> {
+    let len = take_u32(data)? as usize;
+    K::decode(take(data, len)?).ok_or_else(|| corruption("undecodable key in manifest"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use disk::PosixEnv;
+    use error::Error;
+
+    use super::{Manifest, RunMeta, VersionEdit};
+
+    fn meta(level: usize, run_id: usize) -> RunMeta<u32> {
+        RunMeta {
+            level,
+            run_id,
+            min_key: 1,
+            max_key: 9,
+            entries: 5,
+            bloom_bits: 64,
+            bloom_hashes: 3,
+        }
+    }
+
+    #[test]
+    fn edits_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut manifest = Manifest::<u32>::open(&PosixEnv, dir.path()).unwrap();
+            assert!(manifest.version().runs.is_empty());
+            let (a, b) = (manifest.new_run_id(), manifest.new_run_id());
+            manifest
+                .log_edit(VersionEdit {
+                    added: vec![meta(0, a), meta(0, b)],
+                    log_number: Some(3),
+                    last_sequence: Some(40),
+                    ..VersionEdit::default()
+                })
+                .unwrap();
+            let c = manifest.new_run_id();
+            manifest
+                .log_edit(VersionEdit {
+                    added: vec![meta(1, c)],
+                    deleted: vec![(0, a)],
+                    ..VersionEdit::default()
+                })
+                .unwrap();
+        }
+
+        let manifest = Manifest::<u32>::open(&PosixEnv, dir.path()).unwrap();
+        let version = manifest.version();
+        assert_eq!(version.runs.keys().copied().collect::<Vec<_>>(), vec![(0, 1), (1, 2)]);
+        assert_eq!(version.runs[&(1, 2)], meta(1, 2));
+        assert_eq!(version.log_number, 3);
+        assert_eq!(version.next_run_id, 3);
+        assert_eq!(version.last_sequence, 40);
+    }
+
+    #[test]
+    fn torn_edit_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut manifest = Manifest::<u32>::open(&PosixEnv, dir.path()).unwrap();
+            let id = manifest.new_run_id();
+            manifest
+                .log_edit(VersionEdit {
+                    added: vec![meta(0, id)],
+                    ..VersionEdit::default()
+                })
+                .unwrap();
+        }
+        let mut file = OpenOptions::new().append(true).open(dir.path().join("MANIFEST")).unwrap();
+        file.write_all(&[1, 2, 3, 4, 200, 0, 0, 0, 2]).unwrap();
+
+        let manifest = Manifest::<u32>::open(&PosixEnv, dir.path()).unwrap();
+        assert_eq!(manifest.version().runs.len(), 1);
+        assert_eq!(manifest.version().next_run_id, 1);
+    }
+
+    #[test]
+    fn bad_edit_is_not_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::<u32>::open(&PosixEnv, dir.path()).unwrap();
+        let edit = VersionEdit {
+            deleted: vec![(0, 7)],
+            ..VersionEdit::default()
+        };
+        assert!(matches!(manifest.log_edit(edit), Err(Error::InvalidArgument(_))));
+        drop(manifest);
+        assert!(Manifest::<u32>::open(&PosixEnv, dir.path()).unwrap().version().runs.is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter::Peekable;
+
+use error::{Error, Result};
+use skiplist::run::KVpair;
+use skiplist::Comparator;
+
+use crate::internal_key::InternalKey;
+
+/// Combines the operands written by `Db::merge` with the value they apply
+/// to, for read-modify-write updates, like incrementing a counter or
+/// appending to a list, that need no read.
+///
+/// Operands stack up as versions of their key. Reads combine them lazily
+/// with `full_merge`, on top of the newest value or tombstone below them.
+/// Merges combine them eagerly once no snapshot can tell them apart: with
+/// `full_merge` when the value they apply to is merged along, with
+/// `partial_merge` otherwise.
+///
+/// Values and operands are the bytes stored by the `Db`, so an `Lsm`
+/// expects the values `full_merge` returns to be encoded by its `Codec`.
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The value of `key` once `operands`, oldest first, apply to `existing`,
+    /// its value before them, or `None` if it had none. `None` if they do
+    /// not apply, which fails the read or the merge.
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>>;
+
+    /// One operand doing what `older` then `newer` do, or `None`, the
+    /// default, if they only combine with the value they apply to.
+    fn partial_merge(&self, _key: &[u8], _older: &[u8], _newer: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Stored values that may be merge operands.
+pub trait MergeValue: Sized {
+    fn is_operand(&self) -> bool;
+
+    fn bytes(&self) -> &[u8];
+
+    /// A value holding `bytes`.
+    fn value(bytes: Vec<u8>) -> Self;
+
+    /// An operand holding `bytes`.
+    fn operand(bytes: Vec<u8>) -> Self;
+}
+
+/// `MergeOperator::full_merge`, failing if the operands do not apply.
+pub(crate) fn full_merge(
+    operator: &dyn MergeOperator,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &[&[u8]],
+) -> Result<Vec<u8>> {
+    operator
+        .full_merge(key, existing, operands)
+        .ok_or_else(|| Error::invalid_argument(format!("merge operator {} failed on its operands", operator.name())))
+}
+
+/// Combines, in a merged stream of pairs in internal key order, the merge
+/// operands on top of the newest version of each key not newer than
+/// `smallest_snapshot`, which every reader sees the same.
+///
+/// They are combined with the value or tombstone below them into a value
+/// by `full_merge`, or with nothing when `is_last`, as nothing older is
+/// left. Otherwise `partial_merge` combines what it can of them. The newer
+/// versions, which snapshots may tell apart, are left alone, and so are
+/// the older ones, left to `VisibleVersions` to drop.
+pub struct MergeOperands<'c, K, V, I: Iterator> {
+    pairs: Peekable<I>,
+    cmp: &'c dyn Comparator<K>,
+    operator: Option<&'c dyn MergeOperator>,
+    smallest_snapshot: u64,
+    is_last: bool,
+    /// The key whose versions are combined already.
+    done: Option<K>,
+    /// The pairs combined, newest first, to be returned.
+    combined: VecDeque<KVpair<InternalKey<K>, V>>,
+}
+
+impl<'c, K, V, I> MergeOperands<'c, K, V, I>
+where
+    I: Iterator<Item = Result<KVpair<InternalKey<K>, V>>>,
+{
+    pub fn new(
+        pairs: I,
+        cmp: &'c dyn Comparator<K>,
+        operator: Option<&'c dyn MergeOperator>,
+        smallest_snapshot: u64,
+        is_last: bool,
+    ) -> Self {
+        MergeOperands {
+            pairs: pairs.peekable(),
+            cmp,
+            operator,
+            smallest_snapshot,
+            is_last,
+            done: None,
+            combined: VecDeque::new(),
+        }
+    }
+}
+
+impl<'c, K, V, I> MergeOperands<'c, K, V, I>
+where
+    K: Clone + AsRef<[u8]>,
+    V: MergeValue,
+    I: Iterator<Item = Result<KVpair<InternalKey<K>, V>>>,
+{
+    /// Combine `newest`, an operand, with the older versions of its key.
+    fn combine(&mut self, operator: &dyn MergeOperator, newest: KVpair<InternalKey<K>, V>) -> Result<()> {
+        let cmp = self.cmp;
+        let user_key = newest.key.as_ref().unwrap().user_key.clone();
+        // the operands, newest first, and what they apply to once found.
+        let mut operands = vec![newest];
+        let mut base = None;
+        while let Some(Ok(older)) = self.pairs.peek() {
+            if cmp.compare(&older.key.as_ref().unwrap().user_key, &user_key) != Ordering::Equal {
+                break;
+            }
+            let older = self.pairs.next().unwrap()?;
+            match older.value {
+                Some(ref value) if value.is_operand() => operands.push(older),
+                value => {
+                    base = Some(value);
+                    break;
+                }
+            }
+        }
+
+        let key = user_key.as_ref();
+        if base.is_some() || self.is_last {
+            let existing = base.flatten();
+            let bytes: Vec<_> = operands.iter().rev().map(|kv| kv.value.as_ref().unwrap().bytes()).collect();
+            let value = full_merge(operator, key, existing.as_ref().map(V::bytes), &bytes)?;
+            let newest = operands.swap_remove(0);
+            self.combined.push_back(KVpair::new(newest.key.unwrap(), V::value(value)));
+            return Ok(());
+        }
+
+        // the operands left, oldest first, each one taking the key of the
+        // newest operand it stands for.
+        let mut left: Vec<KVpair<InternalKey<K>, V>> = Vec::with_capacity(operands.len());
+        for newer in operands.into_iter().rev() {
+            let partial = match left.last() {
+                Some(older) => {
+                    let older = older.value.as_ref().unwrap().bytes();
+                    operator.partial_merge(key, older, newer.value.as_ref().unwrap().bytes())
+                }
+                None => None,
+            };
+            match partial {
+                Some(operand) => *left.last_mut().unwrap() = KVpair::new(newer.key.unwrap(), V::operand(operand)),
+                None => left.push(newer),
+            }
+        }
+        self.combined.extend(left.into_iter().rev());
+        Ok(())
+    }
+}
+
+impl<'c, K, V, I> Iterator for MergeOperands<'c, K, V, I>
+where
+    K: Clone + AsRef<[u8]>,
+    V: MergeValue,
+    I: Iterator<Item = Result<KVpair<InternalKey<K>, V>>>,
+{
+    type Item = Result<KVpair<InternalKey<K>, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(kv) = self.combined.pop_front() {
+            return Some(Ok(kv));
+        }
+        let kv = match self.pairs.next()? {
+            Ok(kv) => kv,
+            Err(e) => return Some(Err(e)),
+        };
+        let operator = match self.operator {
+            Some(operator) => operator,
+            None => return Some(Ok(kv)),
+        };
+        let key = kv.key.as_ref().unwrap();
+        let done = match &self.done {
+            Some(done) => self.cmp.compare(done, &key.user_key) == Ordering::Equal,
+            None => false,
+        };
+        if done || key.seq > self.smallest_snapshot {
+            return Some(Ok(kv));
+        }
+        self.done = Some(key.user_key.clone());
+        match kv.value {
+            Some(ref value) if value.is_operand() => {}
+            _ => return Some(Ok(kv)),
+        }
+        match self.combine(operator, kv) {
+            Ok(()) => self.combined.pop_front().map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use skiplist::run::KVpair;
+    use skiplist::OrdComparator;
+
+    use super::{MergeOperands, MergeOperator, MergeValue};
+    use crate::internal_key::InternalKey;
+
+    /// Appends its operands to the value, each one in parentheses when
+    /// partially merged.
+    #[derive(Debug)]
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn name(&self) -> &str {
+            "append"
+        }
+
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+            let mut value = existing.unwrap_or(b"").to_vec();
+            operands.iter().for_each(|operand| value.extend_from_slice(operand));
+            Some(value)
+        }
+
+        fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+            Some([b"(", older, newer, b")"].concat())
+        }
+    }
+
+    /// A value, or an operand when it starts with `+`.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Op(Vec<u8>);
+
+    impl MergeValue for Op {
+        fn is_operand(&self) -> bool {
+            self.0.starts_with(b"+")
+        }
+
+        fn bytes(&self) -> &[u8] {
+            if self.is_operand() {
+                &self.0[1..]
+            } else {
+                &self.0
+            }
+        }
+
+        fn value(bytes: Vec<u8>) -> Self {
+            Op(bytes)
+        }
+
+        fn operand(bytes: Vec<u8>) -> Self {
+            Op([b"+", &bytes[..]].concat())
+        }
+    }
+
+    fn pair(key: &str, seq: u64, value: Option<&str>) -> KVpair<InternalKey<Vec<u8>>, Op> {
+        KVpair {
+            key: Some(InternalKey::new(key.as_bytes().to_vec(), seq)),
+            value: value.map(|value| Op(value.as_bytes().to_vec())),
+        }
+    }
+
+    fn combine(smallest_snapshot: u64, is_last: bool) -> Vec<(String, u64, Option<String>)> {
+        let pairs = vec![
+            pair("a", 9, Some("+c")),
+            pair("a", 8, Some("+b")),
+            pair("a", 7, Some("x")),
+            pair("a", 6, Some("+old")),
+            pair("b", 5, Some("+e")),
+            pair("b", 4, Some("+d")),
+            pair("b", 3, None),
+            pair("c", 2, Some("+g")),
+            pair("c", 1, Some("+f")),
+        ];
+        MergeOperands::new(pairs.into_iter().map(Ok), &OrdComparator, Some(&Append), smallest_snapshot, is_last)
+            .map(|kv| kv.unwrap())
+            .map(|kv| {
+                let key = kv.key.unwrap();
+                let value = kv.value.map(|value| String::from_utf8(value.0).unwrap());
+                (String::from_utf8(key.user_key).unwrap(), key.seq, value)
+            })
+            .collect()
+    }
+
+    fn expected(pairs: &[(&str, u64, Option<&str>)]) -> Vec<(String, u64, Option<String>)> {
+        pairs.iter().map(|&(key, seq, value)| (key.to_string(), seq, value.map(str::to_string))).collect()
+    }
+
+    #[test]
+    fn operands_combine_below_the_smallest_snapshot() {
+        assert_eq!(
+            combine(9, false),
+            expected(&[
+                ("a", 9, Some("xbc")),
+                ("a", 6, Some("+old")),
+                ("b", 5, Some("de")),
+                ("c", 2, Some("+(fg)")),
+            ])
+        );
+        assert_eq!(combine(9, true)[3], ("c".to_string(), 2, Some("fg".to_string())));
+
+        // the versions a snapshot may see are left alone.
+        assert_eq!(
+            combine(8, false)[..4],
+            expected(&[("a", 9, Some("+c")), ("a", 8, Some("xb")), ("a", 6, Some("+old")), ("b", 5, Some("de"))])[..]
+        );
+        assert_eq!(combine(0, false).len(), 9);
+    }
+}
@@ -0,0 +1,78 @@
+use std::marker::PhantomData;
+
+use error::Result;
+
+use crate::codec::Codec;
+use crate::lsm::{decode, Db};
+
+/// A read-only view of a `Db` as of the sequence number it was taken at,
+/// returned by `Db::snapshot`. Later writes are invisible to it, and merges
+/// keep the versions it sees until it is dropped.
+pub struct Snapshot<'a> {
+    db: &'a Db,
+    seq: u64,
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn new(db: &'a Db, seq: u64) -> Self {
+        Snapshot { db, seq }
+    }
+
+    /// Sequence number of the last write the snapshot sees.
+    #[inline]
+    pub fn sequence(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_at(key, Some(self.seq))
+    }
+
+    /// All the pairs with `key1 <= key < key2`, in key order.
+    pub fn range(&self, key1: &[u8], key2: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.db.range_at(key1, key2, Some(self.seq))
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        self.db.release_snapshot(self.seq);
+    }
+}
+
+/// A `Snapshot` reading keys of type `K` and values of type `V`, returned
+/// by `Lsm::snapshot`.
+pub struct LsmSnapshot<'a, K, V> {
+    snapshot: Snapshot<'a>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K: Codec, V: Codec> LsmSnapshot<'a, K, V> {
+    pub(crate) fn new(snapshot: Snapshot<'a>) -> Self {
+        LsmSnapshot {
+            snapshot,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.snapshot.get(&key.encode())? {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// All the pairs with `key1 <= key < key2`, in key order.
+    pub fn range(&self, key1: &K, key2: &K) -> Result<Vec<(K, V)>> {
+        self.snapshot
+            .range(&key1.encode(), &key2.encode())?
+            .into_iter()
+            .map(|(key, value)| Ok((decode(&key)?, decode(&value)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,311 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use error::Result;
+use skiplist::{Run, SkipList};
+
+use crate::codec::Codec;
+use crate::lsm::{decode, Db};
+use crate::snapshot::Snapshot;
+use crate::value::StoredValue;
+use crate::wal::Record;
+
+/// How a transaction keeps concurrent writers from changing the keys it
+/// reads for update and writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concurrency {
+    /// Track the keys, and fail the commit with `Busy` if another writer
+    /// wrote one of them since the transaction began.
+    Optimistic,
+    /// Lock the keys until the transaction ends, so that other pessimistic
+    /// transactions wait for them. Taking a lock fails with `Busy` once the
+    /// lock timeout elapses, but commits never fail on conflicts. Writes
+    /// made outside of transactions do not take the locks.
+    Pessimistic,
+}
+
+/// The options of a transaction, given to `Db::transaction`.
+#[derive(Debug, Clone)]
+pub struct TransactionOptions {
+    pub concurrency: Concurrency,
+    /// How long a pessimistic transaction waits for the lock of a key.
+    pub lock_timeout: Duration,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        TransactionOptions {
+            concurrency: Concurrency::Optimistic,
+            lock_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A set of reads and writes of a `Db` committed as a whole, returned by
+/// `Db::transaction`.
+///
+/// Writes are buffered in the transaction and applied to the tree at once
+/// by `commit`; reads see them, over the tree as of the start of the
+/// transaction. Dropping a transaction without committing it rolls it back.
+pub struct Transaction<'a> {
+    db: &'a Db,
+    snapshot: Snapshot<'a>,
+    options: TransactionOptions,
+    /// The writes of the transaction, a `None` value being a delete.
+    writes: SkipList<Vec<u8>, Option<Vec<u8>>>,
+    /// The keys read for update or written: checked for conflicts by the
+    /// commit of an optimistic transaction, locked by a pessimistic one.
+    tracked: SkipList<Vec<u8>, ()>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a Db, options: TransactionOptions) -> Self {
+        let comparator = db.user_comparator();
+        Transaction {
+            db,
+            snapshot: db.snapshot(),
+            options,
+            writes: SkipList::with_comparator(Arc::clone(comparator)),
+            tracked: SkipList::with_comparator(Arc::clone(comparator)),
+        }
+    }
+
+    /// Read `key` as of the start of the transaction, or as the transaction
+    /// last wrote it.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.lookup(&key.to_vec()) {
+            Some(value) => Ok(value),
+            None => self.snapshot.get(key),
+        }
+    }
+
+    /// Read `key` to write it back. An optimistic transaction reads it as
+    /// `get` does, and fails to commit if another writer writes it in the
+    /// meantime. A pessimistic one locks it first, then reads its latest
+    /// value.
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.track(key)?;
+        if let Some(value) = self.writes.lookup(&key.to_vec()) {
+            return Ok(value);
+        }
+        match self.options.concurrency {
+            Concurrency::Optimistic => self.snapshot.get(key),
+            Concurrency::Pessimistic => self.db.get(key),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.track(key)?;
+        self.writes.insert_key(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.track(key)?;
+        self.writes.insert_key(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Track `key`, locking it in a pessimistic transaction.
+    fn track(&mut self, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        if self.tracked.contains_key(&key) {
+            return Ok(());
+        }
+        if self.options.concurrency == Concurrency::Pessimistic {
+            self.db.locks().lock(&key, self.options.lock_timeout)?;
+        }
+        self.tracked.insert_key(key, ());
+        Ok(())
+    }
+
+    /// Apply the writes of the transaction to the tree as one update. The
+    /// commit of an optimistic transaction fails with `Busy`, writing
+    /// nothing, if another writer wrote a key it tracks since it began.
+    pub fn commit(mut self) -> Result<()> {
+        let writes = self
+            .writes
+            .get_all()
+            .into_iter()
+            .map(|kv| match kv.value.unwrap() {
+                Some(value) => Record::Put(kv.key.unwrap(), StoredValue::new(value)),
+                None => Record::Delete(kv.key.unwrap()),
+            })
+            .collect();
+        let conflicts: Vec<Vec<u8>> = match self.options.concurrency {
+            Concurrency::Optimistic => self.tracked.get_all().into_iter().map(|kv| kv.key.unwrap()).collect(),
+            Concurrency::Pessimistic => Vec::new(),
+        };
+        self.db.commit(writes, conflicts, self.snapshot.sequence())
+    }
+
+    /// Discard the writes of the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<'a> Drop for Transaction<'a> {
+    /// Release the locks of a pessimistic transaction.
+    fn drop(&mut self) {
+        if self.options.concurrency == Concurrency::Pessimistic {
+            let locked: Vec<Vec<u8>> = self.tracked.get_all().into_iter().map(|kv| kv.key.unwrap()).collect();
+            self.db.locks().unlock(&locked);
+        }
+    }
+}
+
+/// A `Transaction` reading and writing keys of type `K` and values of type
+/// `V`, returned by `Lsm::transaction`.
+pub struct LsmTransaction<'a, K, V> {
+    transaction: Transaction<'a>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K: Codec, V: Codec> LsmTransaction<'a, K, V> {
+    pub(crate) fn new(transaction: Transaction<'a>) -> Self {
+        LsmTransaction {
+            transaction,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.transaction.get(&key.encode())? {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_for_update(&mut self, key: &K) -> Result<Option<V>> {
+        match self.transaction.get_for_update(&key.encode())? {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&mut self, key: K, value: V) -> Result<()> {
+        self.transaction.put(&key.encode(), &value.encode())
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        self.transaction.delete(&key.encode())
+    }
+
+    pub fn commit(self) -> Result<()> {
+        self.transaction.commit()
+    }
+
+    pub fn rollback(self) {
+        self.transaction.rollback()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use disk::MemEnv;
+    use error::Error;
+
+    use super::{Concurrency, TransactionOptions};
+    use crate::lsm::{Db, Lsm, LsmOptions};
+
+    fn options() -> LsmOptions {
+        LsmOptions {
+            elts_per_run: 10,
+            page_size: 4,
+            env: Arc::new(MemEnv::new()),
+            ..LsmOptions::default()
+        }
+    }
+
+    fn pessimistic(lock_timeout: Duration) -> TransactionOptions {
+        TransactionOptions {
+            concurrency: Concurrency::Pessimistic,
+            lock_timeout,
+        }
+    }
+
+    #[test]
+    fn reads_own_writes() {
+        let db = Db::open(Path::new("/db"), options()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+
+        let mut txn = db.transaction(TransactionOptions::default());
+        txn.put(b"a", b"10").unwrap();
+        txn.delete(b"b").unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(txn.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        txn.rollback();
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        let mut txn = db.transaction(TransactionOptions::default());
+        txn.put(b"a", b"10").unwrap();
+        txn.delete(b"b").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn optimistic_conflicts() {
+        let db = Db::open(Path::new("/db"), options()).unwrap();
+        db.put(b"stock", b"5").unwrap();
+
+        let mut txn = db.transaction(TransactionOptions::default());
+        assert_eq!(txn.get_for_update(b"stock").unwrap(), Some(b"5".to_vec()));
+        db.put(b"stock", b"4").unwrap();
+        txn.put(b"stock", b"6").unwrap();
+        txn.put(b"other", b"1").unwrap();
+        assert!(matches!(txn.commit(), Err(Error::Busy(_))));
+        assert_eq!(db.get(b"stock").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(db.get(b"other").unwrap(), None);
+
+        // a key only read by `get` is not checked.
+        let mut txn = db.transaction(TransactionOptions::default());
+        assert_eq!(txn.get(b"stock").unwrap(), Some(b"4".to_vec()));
+        txn.put(b"other", b"1").unwrap();
+        db.put(b"stock", b"3").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"other").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn pessimistic_read_modify_write() {
+        let lsm: Lsm<i64, i64> = Lsm::open(Path::new("/db"), options()).unwrap();
+        lsm.put(0, 0).unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        let mut txn = lsm.transaction(pessimistic(Duration::from_secs(10)));
+                        let count = txn.get_for_update(&0).unwrap().unwrap();
+                        txn.put(0, count + 1).unwrap();
+                        txn.commit().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(lsm.get(&0).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let db = Db::open(Path::new("/db"), options()).unwrap();
+        let mut holder = db.transaction(pessimistic(Duration::from_secs(1)));
+        holder.put(b"a", b"1").unwrap();
+
+        let mut waiter = db.transaction(pessimistic(Duration::from_millis(20)));
+        assert!(matches!(waiter.get_for_update(b"a"), Err(Error::Busy(_))));
+        waiter.put(b"b", b"2").unwrap();
+        holder.commit().unwrap();
+        assert_eq!(waiter.get_for_update(b"a").unwrap(), Some(b"1".to_vec()));
+        waiter.commit().unwrap();
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,142 @@
+use std::convert::TryInto;
+
+use disk::{Encoding, Expiring};
+
+use crate::merge_operator::MergeValue;
+
+/// Tag of a value that never expires.
+const PERSISTENT: u8 = 0;
+/// Tag of a value followed by the time it expires at.
+const EXPIRING: u8 = 1;
+/// Tag of a merge operand.
+const OPERAND: u8 = 2;
+
+/// A value as a `Db` stores it, along with the time it expires at, in
+/// seconds since the Unix epoch, if it was written with a time to live.
+/// It may also be a merge operand, which never expires.
+///
+/// Encoded as a tag byte, the expiry time as 8 little endian bytes when
+/// tagged `EXPIRING`, then the value itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredValue {
+    pub value: Vec<u8>,
+    pub expires_at: Option<u64>,
+    pub operand: bool,
+}
+
+impl StoredValue {
+    #[inline]
+    pub fn new(value: Vec<u8>) -> Self {
+        StoredValue {
+            value,
+            expires_at: None,
+            operand: false,
+        }
+    }
+
+    #[inline]
+    pub fn expiring(value: Vec<u8>, expires_at: u64) -> Self {
+        StoredValue {
+            value,
+            expires_at: Some(expires_at),
+            operand: false,
+        }
+    }
+
+    #[inline]
+    pub fn operand(value: Vec<u8>) -> Self {
+        StoredValue {
+            value,
+            expires_at: None,
+            operand: true,
+        }
+    }
+
+    /// Whether the value is gone at time `now`.
+    #[inline]
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl Expiring for StoredValue {
+    #[inline]
+    fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+}
+
+impl MergeValue for StoredValue {
+    #[inline]
+    fn is_operand(&self) -> bool {
+        self.operand
+    }
+
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        &self.value
+    }
+
+    #[inline]
+    fn value(bytes: Vec<u8>) -> Self {
+        StoredValue::new(bytes)
+    }
+
+    #[inline]
+    fn operand(bytes: Vec<u8>) -> Self {
+        StoredValue::operand(bytes)
+    }
+}
+
+impl Encoding for StoredValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self.expires_at {
+            _ if self.operand => buf.push(OPERAND),
+            None => buf.push(PERSISTENT),
+            Some(expires_at) => {
+                buf.push(EXPIRING);
+                buf.extend_from_slice(&expires_at.to_le_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.value);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_first()? {
+            (&PERSISTENT, value) => Some(StoredValue::new(value.to_vec())),
+            (&OPERAND, operand) => Some(StoredValue::operand(operand.to_vec())),
+            (&EXPIRING, rest) if rest.len() >= 8 => {
+                let (expires_at, value) = rest.split_at(8);
+                Some(StoredValue::expiring(value.to_vec(), u64::from_le_bytes(expires_at.try_into().unwrap())))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use disk::Encoding;
+
+    use super::StoredValue;
+
+    #[test]
+    fn encoding_round_trips() {
+        let values = [
+            StoredValue::new(b"abc".to_vec()),
+            StoredValue::expiring(Vec::new(), 1 << 33),
+            StoredValue::operand(b"+1".to_vec()),
+        ];
+        for value in values.iter() {
+            let mut buf = Vec::new();
+            value.encode(&mut buf);
+            assert_eq!(StoredValue::decode(&buf).as_ref(), Some(value));
+        }
+        assert_eq!(StoredValue::decode(&[]), None);
+        assert_eq!(StoredValue::decode(&[1, 0, 0]), None);
+        assert_eq!(StoredValue::decode(&[3]), None);
+        assert!(StoredValue::expiring(Vec::new(), 10).is_expired(10));
+        assert!(!StoredValue::expiring(Vec::new(), 10).is_expired(9));
+        assert!(!StoredValue::new(Vec::new()).is_expired(u64::MAX));
+    }
+}
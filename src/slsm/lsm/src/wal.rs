@@ -0,0 +1,495 @@
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use disk::{Encoding, Env, WritableFile};
+use error::{Error, Result};
+
+/// When the write-ahead log is flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// `fsync` after every record; nothing acknowledged is ever lost.
+    Always,
+    /// `fsync` on the first write once the interval has elapsed since the
+    /// last sync, or from the background thread once it elapses with writes
+    /// left unsynced; a crash loses at most the writes of one interval.
+    Interval(Duration),
+    /// Leave flushing to the OS. Writes survive a process crash, but not
+    /// a machine crash.
+    Never,
+}
+
+const RECORD_PUT: u8 = 1;
+const RECORD_DELETE: u8 = 2;
+const RECORD_DELETE_RANGE: u8 = 3;
+// crc32c of the record, then length of the record body.
+const RECORD_HEADER: usize = 8;
+
+/// One logged write.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record<K, V> {
+    Put(K, V),
+    Delete(K),
+    /// The delete of every key from the first one included to the second
+    /// one excluded.
+    DeleteRange(K, K),
+}
+
+/// The records replayed from one segment, in write order, the batches they
+/// were logged in flattened.
+pub type Segment<K, V> = Vec<Record<K, V>>;
+
+/// The write-ahead log of the memory runs.
+///
+/// The log is split in segments named `W_<id>.log`, one per memory run: the
+/// active run appends to the newest segment, and a segment is deleted once
+/// its run has been merged into a disk run. Each log record holds a batch
+/// of records, laid out as
+///
+/// ```text
+/// crc32c: u32 | len: u32 | count: u32 | (kind: u8 | key_len: u32 | key [| len: u32 | value or end key])*
+/// ```
+///
+/// with the checksum covering everything after itself, so a batch is
+/// replayed whole or not at all. Replay stops at the first torn or corrupt
+/// log record of a segment.
+///
+/// A put is followed by its value, and a range delete by the end of its
+/// range.
+///
+/// A log record that fails to be written or synced is cut from its segment,
+/// so that it is not replayed and records after it are not lost behind it.
+/// If that fails too, the log refuses every later append.
+pub struct Wal {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+    sync_policy: SyncPolicy,
+    segments: Vec<u64>,
+    file: Box<dyn WritableFile>,
+    /// Length of the current segment up to its last whole log record.
+    len: u64,
+    last_sync: Instant,
+    /// Whether records were appended since the last sync.
+    unsynced: bool,
+    /// The error that left the current segment torn, failing every append.
+    failed: Option<Error>,
+    buf: Vec<u8>,
+}
+
+impl Wal {
+    /// Open the log in `dir`, returning it along with the records of every
+    /// segment from `first_segment` on, oldest segment first. Older segments
+    /// are deleted.
+    pub fn open<K, V>(
+        env: Arc<dyn Env>,
+        dir: &Path,
+        first_segment: u64,
+        sync_policy: SyncPolicy,
+    ) -> Result<(Wal, Vec<Segment<K, V>>)>
+    where
+        K: Encoding,
+        V: Encoding,
+    {
+        let mut segments = Vec::new();
+        for name in env.read_dir(dir)? {
+            match Self::parse_filename(&name) {
+                Some(id) if id < first_segment => env.remove_file(&dir.join(name))?,
+                Some(id) => segments.push(id),
+                None => {}
+            }
+        }
+        segments.sort_unstable();
+
+        let mut replayed = Vec::with_capacity(segments.len());
+        let mut len = 0;
+        for &id in segments.iter() {
+            let path = dir.join(Self::filename(id));
+            let data = env.read(&path)?;
+            let (records, valid_len) = Self::read_segment(&data);
+            // cut a torn tail, so new records are not appended after garbage.
+            if data.len() as u64 != valid_len {
+                env.truncate(&path, valid_len)?;
+            }
+            replayed.push(records);
+            len = valid_len;
+        }
+
+        if segments.is_empty() {
+            segments.push(first_segment);
+        }
+        let file = Self::open_segment(&*env, dir, *segments.last().unwrap())?;
+        let wal = Wal {
+            env,
+            dir: dir.to_path_buf(),
+            sync_policy,
+            segments,
+            file,
+            len,
+            last_sync: Instant::now(),
+            unsynced: false,
+            failed: None,
+            buf: Vec::new(),
+        };
+        Ok((wal, replayed))
+    }
+
+    #[inline]
+    pub fn filename(id: u64) -> String {
+        "W_".to_owned() + &id.to_string() + ".log"
+    }
+
+    fn parse_filename(name: &str) -> Option<u64> {
+        name.strip_prefix("W_")?.strip_suffix(".log")?.parse().ok()
+    }
+
+    fn open_segment(env: &dyn Env, dir: &Path, id: u64) -> io::Result<Box<dyn WritableFile>> {
+        env.open_append(&dir.join(Self::filename(id)))
+    }
+
+    /// Ids of the live segments, oldest first.
+    #[inline]
+    pub fn segments(&self) -> &[u64] {
+        &self.segments
+    }
+
+    /// Log `batch` as one record, flushed to stable storage as the sync
+    /// policy says. The batch is not logged if it fails.
+    pub fn append<K, V>(&mut self, batch: &[Record<K, V>]) -> Result<()>
+    where
+        K: Encoding,
+        V: Encoding,
+    {
+        if let Some(e) = &self.failed {
+            return Err(e.clone());
+        }
+        self.buf.clear();
+        self.buf.extend_from_slice(&[0; RECORD_HEADER]);
+        self.buf.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+        for record in batch {
+            match record {
+                Record::Put(key, value) => {
+                    self.buf.push(RECORD_PUT);
+                    encode_prefixed(&mut self.buf, key);
+                    encode_prefixed(&mut self.buf, value);
+                }
+                Record::Delete(key) => {
+                    self.buf.push(RECORD_DELETE);
+                    encode_prefixed(&mut self.buf, key);
+                }
+                Record::DeleteRange(start, end) => {
+                    self.buf.push(RECORD_DELETE_RANGE);
+                    encode_prefixed(&mut self.buf, start);
+                    encode_prefixed(&mut self.buf, end);
+                }
+            }
+        }
+
+        let len = (self.buf.len() - RECORD_HEADER) as u32;
+        self.buf[4..8].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32c::crc32c(&self.buf[4..]);
+        self.buf[0..4].copy_from_slice(&crc.to_le_bytes());
+
+        let written = self.write_buf();
+        match written {
+            Ok(()) => self.len += self.buf.len() as u64,
+            Err(_) => {
+                if let Err(e) = self.cut_torn_record() {
+                    self.failed = Some(e.into());
+                }
+            }
+        }
+        written
+    }
+
+    fn write_buf(&mut self) -> Result<()> {
+        self.file.write_all(&self.buf)?;
+        self.file.flush()?;
+        self.unsynced = true;
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Cut the current segment back to its last whole log record, and append
+    /// to it from there.
+    fn cut_torn_record(&mut self) -> io::Result<()> {
+        let id = *self.segments.last().unwrap();
+        self.env.truncate(&self.dir.join(Self::filename(id)), self.len)?;
+        self.file = Self::open_segment(&*self.env, &self.dir, id)?;
+        Ok(())
+    }
+
+    /// Flush the current segment to stable storage.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync()?;
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Flush the current segment to stable storage if the sync policy is an
+    /// interval, which has elapsed since the last sync with records appended
+    /// meanwhile.
+    pub fn sync_if_due(&mut self) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Interval(interval) if self.unsynced && self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Seal the current segment and start logging to a new one, for the
+    /// next memory run.
+    pub fn new_segment(&mut self) -> Result<()> {
+        if self.sync_policy != SyncPolicy::Never {
+            self.sync()?;
+        }
+        let id = self.segments.last().map_or(0, |id| id + 1);
+        self.file = Self::open_segment(&*self.env, &self.dir, id)?;
+        self.segments.push(id);
+        self.len = 0;
+        self.failed = None;
+        Ok(())
+    }
+
+    /// Delete the `n` oldest segments, whose runs are now safely on disk.
+    pub fn drop_segments(&mut self, n: usize) -> Result<()> {
+        if n >= self.segments.len() {
+            return Err(Error::invalid_argument("can not drop the segment being written"));
+        }
+        for id in self.segments.drain(..n) {
+            self.env.remove_file(&self.dir.join(Self::filename(id)))?;
+        }
+        Ok(())
+    }
+
+    /// The valid records of the segment holding `data`, and the length of
+    /// the data they span.
+    fn read_segment<K, V>(data: &[u8]) -> (Segment<K, V>, u64)
+    where
+        K: Encoding,
+        V: Encoding,
+    {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some((batch, len)) = Self::decode_batch(&data[offset..]) {
+            records.extend(batch);
+            offset += len;
+        }
+        (records, offset as u64)
+    }
+
+    /// The batch of the log record starting `data` and the length of the
+    /// log record, or `None` if it is torn or corrupt.
+    fn decode_batch<K, V>(data: &[u8]) -> Option<(Vec<Record<K, V>>, usize)>
+    where
+        K: Encoding,
+        V: Encoding,
+    {
+        if data.len() < RECORD_HEADER {
+            return None;
+        }
+        let crc = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if data.len() < RECORD_HEADER + len || crc32c::crc32c(&data[4..RECORD_HEADER + len]) != crc {
+            return None;
+        }
+
+        let mut body = &data[RECORD_HEADER..RECORD_HEADER + len];
+        let count = take_u32(&mut body)? as usize;
+        let mut batch = Vec::with_capacity(count.min(body.len()));
+        for _ in 0..count {
+            let kind = take(&mut body, 1)?[0];
+            let key = K::decode(take_prefixed(&mut body)?)?;
+            batch.push(match kind {
+                RECORD_PUT => Record::Put(key, V::decode(take_prefixed(&mut body)?)?),
+                RECORD_DELETE => Record::Delete(key),
+                RECORD_DELETE_RANGE => Record::DeleteRange(key, K::decode(take_prefixed(&mut body)?)?),
+                _ => return None,
+            });
+        }
+        if !body.is_empty() {
+            return None;
+        }
+        Some((batch, RECORD_HEADER + len))
+    }
+}
+
+/// Append `value` to `buf`, preceded by the length of its encoding.
+fn encode_prefixed<T: Encoding>(buf: &mut Vec<u8>, value: &T) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    value.encode(buf);
+    let len = (buf.len() - start - 4) as u32;
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if data.len() < n {
+        return None;
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Some(head)
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    take(data, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn take_prefixed<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u32(data)? as usize;
+    take(data, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use disk::{Env, FaultInjectionEnv, MemEnv, PosixEnv};
+
+    use super::{Record, SyncPolicy, Wal};
+
+    #[test]
+    fn append_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut wal, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Always).unwrap();
+            assert!(replayed.is_empty());
+            wal.append(&[Record::<u32, u32>::Put(1, 10)]).unwrap();
+            wal.new_segment().unwrap();
+            wal.append(&[Record::Put(2, 20)]).unwrap();
+            wal.append(&[Record::<u32, u32>::Delete(1)]).unwrap();
+        }
+
+        let (wal, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+        assert_eq!(wal.segments(), &[0, 1]);
+        assert_eq!(
+            replayed,
+            vec![
+                vec![Record::Put(1, 10)],
+                vec![Record::Put(2, 20), Record::Delete(1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn torn_tail_is_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut wal, _) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+            wal.append(&[Record::Put(1, 1)]).unwrap();
+            wal.append(&[Record::Put(2, 2)]).unwrap();
+        }
+        let path = dir.path().join(Wal::filename(0));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xde, 0xad, 0xbe]).unwrap();
+
+        {
+            let (mut wal, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+            assert_eq!(replayed[0].len(), 2);
+            wal.append(&[Record::Put(3, 3)]).unwrap();
+        }
+        let (_, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+        assert_eq!(replayed[0].len(), 3);
+    }
+
+    #[test]
+    fn failed_appends_are_cut() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let dir = Path::new("/db");
+        env.create_dir_all(dir).unwrap();
+        {
+            let (mut wal, _) = Wal::open::<u32, u32>(env.clone(), dir, 0, SyncPolicy::Always).unwrap();
+            wal.append(&[Record::Put(1, 1)]).unwrap();
+
+            // a torn record, as when the disk fills up.
+            env.set_write_budget(Some(5));
+            assert!(wal.append(&[Record::Put(2, 2)]).is_err());
+            env.set_write_budget(None);
+            wal.append(&[Record::Put(3, 3)]).unwrap();
+
+            // a whole record that is not synced.
+            env.set_fail_syncs(true);
+            assert!(wal.append(&[Record::Put(4, 4)]).is_err());
+            env.set_fail_syncs(false);
+            wal.append(&[Record::Put(5, 5)]).unwrap();
+        }
+        let (mut wal, replayed) = Wal::open::<u32, u32>(env.clone(), dir, 0, SyncPolicy::Always).unwrap();
+        assert_eq!(replayed[0], vec![Record::Put(1, 1), Record::Put(3, 3), Record::Put(5, 5)]);
+
+        // a record that can not be cut fails every later append.
+        env.set_fail_writes(true);
+        assert!(wal.append(&[Record::Put(6, 6)]).is_err());
+        env.set_fail_writes(false);
+        assert!(wal.append(&[Record::Put(7, 7)]).is_err());
+    }
+
+    #[test]
+    fn corrupt_record_stops_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut wal, _) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+            for i in 0..3u32 {
+                wal.append(&[Record::Put(i, i)]).unwrap();
+            }
+        }
+        let path = dir.path().join(Wal::filename(0));
+        let mut data = std::fs::read(&path).unwrap();
+        let record_len = data.len() / 3;
+        data[record_len + 10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let (_, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+        assert_eq!(replayed[0], vec![Record::Put(0, 0)]);
+    }
+
+    #[test]
+    fn batches_replay_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut wal, _) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+            wal.append(&[Record::Put(1, 1), Record::Delete(2), Record::DeleteRange(3, 9)]).unwrap();
+            wal.append(&[Record::Put(4, 4), Record::Put(5, 5)]).unwrap();
+        }
+        let path = dir.path().join(Wal::filename(0));
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+        let (_, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+        assert_eq!(replayed[0], vec![Record::Put(1, 1), Record::Delete(2), Record::DeleteRange(3, 9)]);
+    }
+
+    #[test]
+    fn drop_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, _) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+        wal.new_segment().unwrap();
+        wal.new_segment().unwrap();
+        wal.drop_segments(2).unwrap();
+        assert_eq!(wal.segments(), &[2]);
+        assert!(!dir.path().join(Wal::filename(0)).exists());
+        assert!(dir.path().join(Wal::filename(2)).exists());
+    }
+
+    #[test]
+    fn segments_before_first_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut wal, _) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 0, SyncPolicy::Never).unwrap();
+            wal.append(&[Record::Put(1, 1)]).unwrap();
+            wal.new_segment().unwrap();
+            wal.append(&[Record::Put(2, 2)]).unwrap();
+        }
+        let (wal, replayed) = Wal::open::<u32, u32>(PosixEnv::shared(), dir.path(), 1, SyncPolicy::Never).unwrap();
+        assert_eq!(wal.segments(), &[1]);
+        assert_eq!(replayed, vec![vec![Record::Put(2, 2)]]);
+        assert!(!dir.path().join(Wal::filename(0)).exists());
+    }
+}
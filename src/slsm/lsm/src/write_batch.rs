@@ -0,0 +1,126 @@
+use std::marker::PhantomData;
+
+use crate::codec::Codec;
+use crate::value::StoredValue;
+use crate::wal::Record;
+
+/// Writes applied to a `Db` as one update by `Db::write`: they are logged
+/// as one write-ahead log record, and readers see all of them or none.
+/// Range deletes join the batch as range tombstones.
+///
+/// The writes are applied in the order they were added, so the last write
+/// of a key wins.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    writes: Vec<Record<Vec<u8>, StoredValue>>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.push(Record::Put(key.to_vec(), StoredValue::new(value.to_vec())));
+    }
+
+    /// Write `operand` for the merge operator of the `Db` to apply to the
+    /// value of `key`.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.writes.push(Record::Put(key.to_vec(), StoredValue::operand(operand.to_vec())));
+    }
+
+    /// Delete `key` by writing a tombstone for it.
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.push(Record::Delete(key.to_vec()));
+    }
+
+    /// Delete every key from `start` included to `end` excluded by writing a
+    /// single range tombstone.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.writes.push(Record::DeleteRange(start.to_vec(), end.to_vec()));
+    }
+
+    /// Number of writes in the batch.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    /// Whether the batch holds merge operands.
+    pub(crate) fn has_operands(&self) -> bool {
+        self.writes.iter().any(|write| matches!(write, Record::Put(_, value) if value.operand))
+    }
+
+    #[inline]
+    pub(crate) fn into_writes(self) -> Vec<Record<Vec<u8>, StoredValue>> {
+        self.writes
+    }
+}
+
+/// A `WriteBatch` of keys of type `K` and values of type `V`, applied by
+/// `Lsm::write`.
+pub struct LsmWriteBatch<K, V> {
+    batch: WriteBatch,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> LsmWriteBatch<K, V> {
+    pub fn new() -> Self {
+        LsmWriteBatch {
+            batch: WriteBatch::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        self.batch.put(&key.encode(), &value.encode())
+    }
+
+    pub fn merge(&mut self, key: &K, operand: V) {
+        self.batch.merge(&key.encode(), &operand.encode())
+    }
+
+    pub fn delete(&mut self, key: &K) {
+        self.batch.delete(&key.encode())
+    }
+
+    pub fn delete_range(&mut self, start: &K, end: &K) {
+        self.batch.delete_range(&start.encode(), &end.encode())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.batch.clear()
+    }
+
+    /// The byte-oriented batch below.
+    #[inline]
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+}
+
+impl<K: Codec, V: Codec> Default for LsmWriteBatch<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
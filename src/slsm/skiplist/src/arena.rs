@@ -0,0 +1,149 @@
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::ptr::{self, NonNull};
+
+/// Size of the chunks the arena takes from the system allocator.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Allocations larger than this get a chunk of their own, so they do not
+/// waste the rest of the current chunk.
+const LARGE_ALLOC: usize = CHUNK_SIZE / 4;
+
+/// Alignment of every chunk.
+const CHUNK_ALIGN: usize = 16;
+
+/// A bump allocator, handing out memory from large chunks that are all
+/// freed at once when the arena is dropped.
+///
+/// The arena never runs destructors: whoever allocates a value in it has to
+/// drop it in place before the arena goes away.
+pub struct Arena {
+    chunks: Vec<(NonNull<u8>, Layout)>,
+    /// The chunk being filled, and how much of it is used.
+    current: *mut u8,
+    offset: usize,
+    capacity: usize,
+    allocated: usize,
+    used: usize,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena {
+            chunks: Vec::new(),
+            current: ptr::null_mut(),
+            offset: 0,
+            capacity: 0,
+            allocated: 0,
+            used: 0,
+        }
+    }
+
+    /// Number of bytes handed out, alignment padding included.
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.used
+    }
+
+    /// Number of bytes taken from the system allocator.
+    #[inline]
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> NonNull<u8> {
+        if layout.size() > LARGE_ALLOC || layout.align() > CHUNK_ALIGN {
+            self.used += layout.size();
+            return self.new_chunk(layout.size(), layout.align());
+        }
+
+        let mut start = self.offset + self.current.wrapping_add(self.offset).align_offset(layout.align());
+        if self.current.is_null() || start + layout.size() > self.capacity {
+            self.current = self.new_chunk(CHUNK_SIZE, CHUNK_ALIGN).as_ptr();
+            self.offset = 0;
+            self.capacity = CHUNK_SIZE;
+            start = 0;
+        }
+        self.used += start + layout.size() - self.offset;
+        self.offset = start + layout.size();
+        unsafe { NonNull::new_unchecked(self.current.add(start)) }
+    }
+
+    fn new_chunk(&mut self, size: usize, align: usize) -> NonNull<u8> {
+        let layout = Layout::from_size_align(cmp::max(size, 1), cmp::max(align, CHUNK_ALIGN)).unwrap();
+        let chunk = match NonNull::new(unsafe { alloc::alloc(layout) }) {
+            Some(chunk) => chunk,
+            None => alloc::handle_alloc_error(layout),
+        };
+        self.chunks.push((chunk, layout));
+        self.allocated += layout.size();
+        chunk
+    }
+
+    /// Move `value` into the arena.
+    pub fn alloc_value<T>(&mut self, value: T) -> *mut T {
+        let ptr = self.alloc(Layout::new::<T>()).as_ptr() as *mut T;
+        unsafe { ptr.write(value) };
+        ptr
+    }
+
+    /// A slice of `len` copies of `value`.
+    pub fn alloc_slice<T: Copy>(&mut self, len: usize, value: T) -> *mut [T] {
+        let ptr = self.alloc(Layout::array::<T>(len).unwrap()).as_ptr() as *mut T;
+        unsafe {
+            for i in 0..len {
+                ptr.add(i).write(value);
+            }
+        }
+        ptr::slice_from_raw_parts_mut(ptr, len)
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Arena::new()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for &(chunk, layout) in self.chunks.iter() {
+            unsafe { alloc::dealloc(chunk.as_ptr(), layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::{Arena, CHUNK_SIZE};
+
+    #[test]
+    fn alignment_and_usage() {
+        let mut arena = Arena::new();
+        let a = arena.alloc(Layout::new::<u8>());
+        let b = arena.alloc(Layout::new::<u64>());
+        assert_eq!(b.as_ptr() as usize % 8, 0);
+        assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, 8);
+        // one byte, seven of padding and the u64.
+        assert_eq!(arena.memory_usage(), 16);
+        assert_eq!(arena.allocated_bytes(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn chunks() {
+        let mut arena = Arena::new();
+        let slice = arena.alloc_slice(10, 7u32);
+        assert_eq!(unsafe { &*slice }, &[7; 10]);
+        for _ in 0..CHUNK_SIZE / 64 {
+            arena.alloc(Layout::new::<[u64; 8]>());
+        }
+        assert_eq!(arena.allocated_bytes(), 2 * CHUNK_SIZE);
+
+        // large allocations get a chunk of their own.
+        arena.alloc(Layout::array::<u8>(CHUNK_SIZE).unwrap());
+        assert_eq!(arena.allocated_bytes(), 3 * CHUNK_SIZE);
+        assert_eq!(arena.memory_usage(), 40 + CHUNK_SIZE + CHUNK_SIZE);
+    }
+}
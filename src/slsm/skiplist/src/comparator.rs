@@ -0,0 +1,51 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+use crate::run::RangeTombstone;
+
+/// The order of the keys of a tree, used by the memory runs, the disk runs,
+/// their fence pointers, merges and range queries alike.
+///
+/// The name is stored in every disk run, and a run is only opened with the
+/// comparator it was sorted by: changing the order of an existing tree
+/// would make its runs unreadable.
+pub trait Comparator<K: ?Sized>: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+
+    /// Feed `key` to a bloom filter hasher. Keys that compare equal must
+    /// hash alike, so a comparator under which distinct keys are equal,
+    /// like a case-insensitive one, has to hash what it compares.
+    fn hash_key(&self, key: &K, mut state: &mut dyn Hasher)
+    where
+        K: Hash,
+    {
+        key.hash(&mut state)
+    }
+
+    /// Whether `tombstone` deletes `key`, that is whether `key` lies between
+    /// its bounds unless the comparator knows better.
+    fn covers(&self, tombstone: &RangeTombstone<K>, key: &K) -> bool
+    where
+        K: Sized,
+    {
+        self.compare(&tombstone.start, key) != Ordering::Greater && self.compare(key, &tombstone.end) == Ordering::Less
+    }
+}
+
+/// The `Ord` order of the keys, used unless a tree is opened with another
+/// comparator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrdComparator;
+
+impl<K: Ord + ?Sized> Comparator<K> for OrdComparator {
+    fn name(&self) -> &str {
+        "ord"
+    }
+
+    #[inline]
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
@@ -0,0 +1,683 @@
+use std::cmp;
+use std::ops::Bound;
+use std::ops::Bound::Included;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned, Shared};
+
+pub use crossbeam_epoch::{pin, Guard};
+
+use crate::comparator::{Comparator, OrdComparator};
+use crate::run::{KVpair, Run};
+
+const MAX_HEIGHT: usize = 16;
+
+/// The tag marking a link of a node being removed.
+const REMOVED: usize = 1;
+
+struct Node<K, V> {
+    key: K,
+    /// Null once the node is removed.
+    value: Atomic<V>,
+    /// The links to the next node on each level the node reaches. A node is
+    /// removed from a level by tagging its link on that level, which stops
+    /// any other thread from inserting after it there.
+    tower: Box<[Atomic<Node<K, V>>]>,
+    /// One reference is held by the thread inserting the node until it is
+    /// linked on every level of its tower, the other one by the list until
+    /// the node is removed. The last one dropped retires the node.
+    refs: AtomicUsize,
+}
+
+impl<K, V> Drop for Node<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let value = self.value.load(Ordering::Relaxed, epoch::unprotected());
+            if !value.is_null() {
+                drop(value.into_owned());
+            }
+        }
+    }
+}
+
+/// Where a key belongs on every level: `succs[lvl]` is the first node not
+/// before the key on level `lvl`, and `preds[lvl]` the link pointing to it.
+struct Position<'g, K, V> {
+    preds: [&'g Atomic<Node<K, V>>; MAX_HEIGHT],
+    succs: [Shared<'g, Node<K, V>>; MAX_HEIGHT],
+    /// The node holding the key, if any.
+    found: Option<&'g Node<K, V>>,
+}
+
+/// A lock-free skiplist, which many threads can read and write at once.
+///
+/// Nodes are linked with atomic forward pointers, and inserted and removed
+/// with compare-and-swap as in the lock-free skiplist of Herlihy and
+/// Shavit. Removed nodes and overwritten values are reclaimed with
+/// epoch-based reclamation: they are freed once every thread that could
+/// still be reading them is unpinned.
+///
+/// The methods taking a `Guard` are the concurrent interface, the `Run`
+/// methods pin the current thread for the duration of the call.
+///
+/// Keys are ordered by the comparator of the list, `OrdComparator` unless
+/// it is built by `with_comparator`.
+pub struct ConcurrentSkipList<K, V> {
+    head: Box<[Atomic<Node<K, V>>]>,
+    len: AtomicUsize,
+    seed: AtomicU64,
+    pub max_size: usize,
+    cmp: Arc<dyn Comparator<K>>,
+}
+
+impl<K, V> ConcurrentSkipList<K, V>
+where
+    K: Ord + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    #[inline]
+    pub fn new() -> Self {
+        ConcurrentSkipList::with_comparator(Arc::new(OrdComparator))
+    }
+}
+
+impl<K, V> ConcurrentSkipList<K, V>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// An empty list ordering its keys by `cmp`.
+    pub fn with_comparator(cmp: Arc<dyn Comparator<K>>) -> Self {
+        ConcurrentSkipList {
+            head: (0..MAX_HEIGHT).map(|_| Atomic::null()).collect(),
+            len: AtomicUsize::new(0),
+            seed: AtomicU64::new(rand::random::<u64>() | 1),
+            max_size: 0,
+            cmp,
+        }
+    }
+
+    #[inline]
+    pub fn comparator(&self) -> &Arc<dyn Comparator<K>> {
+        &self.cmp
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the run has reached the size set by `set_size`.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.max_size > 0 && self.len() >= self.max_size
+    }
+
+    /// A random tower height, each level being kept with probability 1/2 as
+    /// with `GeoLevelGenerator`. The seed is shared without synchronization:
+    /// a race only makes the heights less random.
+    fn random_height(&self) -> usize {
+        let mut x = self.seed.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed.store(x, Ordering::Relaxed);
+        (x.trailing_ones() as usize + 1).min(MAX_HEIGHT)
+    }
+
+    /// Find where `key` belongs, unlinking the removed nodes met on the way.
+    fn search<'g>(&'g self, key: &K, guard: &'g Guard) -> Position<'g, K, V> {
+        'retry: loop {
+            let mut preds = [&self.head[0]; MAX_HEIGHT];
+            let mut succs = [Shared::null(); MAX_HEIGHT];
+            let mut tower: &'g [Atomic<Node<K, V>>] = &self.head;
+
+            let mut lvl = MAX_HEIGHT;
+            while lvl > 0 {
+                lvl -= 1;
+
+                let mut curr = tower[lvl].load(Ordering::Acquire, guard);
+                // the predecessor itself is being removed.
+                if curr.tag() == REMOVED {
+                    continue 'retry;
+                }
+                while let Some(node) = unsafe { curr.as_ref() } {
+                    let succ = node.tower[lvl].load(Ordering::Acquire, guard);
+                    if succ.tag() == REMOVED {
+                        match tower[lvl].compare_exchange(
+                            curr,
+                            succ.with_tag(0),
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                            guard,
+                        ) {
+                            Ok(_) => {
+                                curr = succ.with_tag(0);
+                                continue;
+                            }
+                            Err(_) => continue 'retry,
+                        }
+                    }
+                    if self.cmp.compare(&node.key, key) != cmp::Ordering::Less {
+                        break;
+                    }
+                    tower = &node.tower;
+                    curr = succ;
+                }
+                preds[lvl] = &tower[lvl];
+                succs[lvl] = curr;
+            }
+
+            let found = unsafe { succs[0].as_ref() }
+                .filter(|node| self.cmp.compare(&node.key, key) == cmp::Ordering::Equal);
+            return Position { preds, succs, found };
+        }
+    }
+
+    /// The value of `key`, which stays readable as long as `guard` is held.
+    pub fn get<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&'g V> {
+        let node = self.search(key, guard).found?;
+        unsafe { node.value.load(Ordering::Acquire, guard).as_ref() }
+    }
+
+    /// Insert `key`, or overwrite its value if it is in the list already.
+    pub fn insert(&self, key: K, value: V, guard: &Guard) {
+        let height = self.random_height();
+        let mut new = Owned::new(Node {
+            key,
+            value: Atomic::new(value),
+            tower: (0..height).map(|_| Atomic::null()).collect(),
+            refs: AtomicUsize::new(2),
+        });
+
+        let (node, mut pos) = loop {
+            let pos = self.search(&new.key, guard);
+            if let Some(node) = pos.found {
+                let value = new.value.swap(Shared::null(), Ordering::Relaxed, guard);
+                if self.overwrite(node, value, guard) {
+                    return;
+                }
+                // the node was removed in between: insert a new one.
+                new.value.store(value, Ordering::Relaxed);
+                continue;
+            }
+
+            new.tower[0].store(pos.succs[0], Ordering::Relaxed);
+            match pos.preds[0].compare_exchange(pos.succs[0], new, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(node) => break (node, pos),
+                Err(e) => new = e.new,
+            }
+        };
+        self.len.fetch_add(1, Ordering::Relaxed);
+
+        // link the upper levels, unless the node gets removed meanwhile.
+        let node_ref = unsafe { node.deref() };
+        'build: for lvl in 1..height {
+            loop {
+                let next = node_ref.tower[lvl].load(Ordering::Acquire, guard);
+                if next.tag() == REMOVED {
+                    break 'build;
+                }
+                let succ = pos.succs[lvl];
+                if node_ref.tower[lvl]
+                    .compare_exchange(next, succ, Ordering::AcqRel, Ordering::Acquire, guard)
+                    .is_err()
+                {
+                    continue;
+                }
+                if pos.preds[lvl]
+                    .compare_exchange(succ, node, Ordering::AcqRel, Ordering::Acquire, guard)
+                    .is_ok()
+                {
+                    break;
+                }
+                pos = self.search(&node_ref.key, guard);
+                if pos.found.map(|found| found as *const _) != Some(node.as_raw()) {
+                    break 'build;
+                }
+            }
+        }
+
+        // a removal that started while the tower was built may have missed
+        // the levels linked since; unlink them again.
+        if node_ref.tower[0].load(Ordering::Acquire, guard).tag() == REMOVED {
+            self.search(&node_ref.key, guard);
+        }
+        Self::release(node, guard);
+    }
+
+    /// Replace the value of `node`, unless it was removed.
+    fn overwrite(&self, node: &Node<K, V>, value: Shared<'_, V>, guard: &Guard) -> bool {
+        let mut old = node.value.load(Ordering::Acquire, guard);
+        loop {
+            if old.is_null() {
+                return false;
+            }
+            match node.value.compare_exchange(old, value, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => {
+                    unsafe { guard.defer_destroy(old) };
+                    return true;
+                }
+                Err(e) => old = e.current,
+            }
+        }
+    }
+
+    /// Remove `key`, returning its value, which stays readable as long as
+    /// `guard` is held.
+    pub fn remove<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&'g V> {
+        let pos = self.search(key, guard);
+        let node = pos.found?;
+
+        // tag the tower top down; tagging the bottom level removes the node.
+        for lvl in (0..node.tower.len()).rev() {
+            let mut next = node.tower[lvl].load(Ordering::Acquire, guard);
+            loop {
+                if next.tag() == REMOVED {
+                    if lvl == 0 {
+                        // another thread removed it first.
+                        return None;
+                    }
+                    break;
+                }
+                match node.tower[lvl].compare_exchange(
+                    next,
+                    next.with_tag(REMOVED),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    guard,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => next = e.current,
+                }
+            }
+        }
+        self.len.fetch_sub(1, Ordering::Relaxed);
+
+        let value = node.value.swap(Shared::null(), Ordering::AcqRel, guard);
+        unsafe { guard.defer_destroy(value) };
+        // unlink the node from every level.
+        self.search(key, guard);
+        Self::release(Shared::from(node as *const _), guard);
+        unsafe { value.as_ref() }
+    }
+
+    fn release(node: Shared<'_, Node<K, V>>, guard: &Guard) {
+        if unsafe { node.deref() }.refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            unsafe { guard.defer_destroy(node) };
+        }
+    }
+
+    /// The first node after `link` that is not being removed.
+    fn next_node<'g>(link: &'g Atomic<Node<K, V>>, guard: &'g Guard) -> Option<&'g Node<K, V>> {
+        let mut node = unsafe { link.load(Ordering::Acquire, guard).as_ref() }?;
+        while node.tower[0].load(Ordering::Acquire, guard).tag() == REMOVED {
+            node = unsafe { node.tower[0].load(Ordering::Acquire, guard).as_ref() }?;
+        }
+        Some(node)
+    }
+
+    /// The last node before `bound` that is not being removed. The list
+    /// has no backward links, so this searches down from the top level.
+    fn last_before<'g>(&'g self, bound: Bound<&K>, guard: &'g Guard) -> Option<&'g Node<K, V>> {
+        let mut tower: &'g [Atomic<Node<K, V>>] = &self.head;
+        let mut last = None;
+        for lvl in (0..MAX_HEIGHT).rev() {
+            let mut curr = tower[lvl].load(Ordering::Acquire, guard);
+            while let Some(node) = unsafe { curr.as_ref() } {
+                if !self.before(&node.key, bound) {
+                    break;
+                }
+                if node.tower[0].load(Ordering::Acquire, guard).tag() != REMOVED {
+                    tower = &node.tower;
+                    last = Some(node);
+                }
+                curr = node.tower[lvl].load(Ordering::Acquire, guard);
+            }
+        }
+        last
+    }
+
+    /// Whether `key` is before the upper bound `bound`.
+    fn before(&self, key: &K, bound: Bound<&K>) -> bool {
+        match bound {
+            Bound::Included(bound) => self.cmp.compare(key, bound) != cmp::Ordering::Greater,
+            Bound::Excluded(bound) => self.cmp.compare(key, bound) == cmp::Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Whether `key` is after the lower bound `bound`.
+    fn after(&self, key: &K, bound: Bound<&K>) -> bool {
+        match bound {
+            Bound::Included(bound) => self.cmp.compare(key, bound) != cmp::Ordering::Less,
+            Bound::Excluded(bound) => self.cmp.compare(key, bound) == cmp::Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+/// The pairs of a `ConcurrentSkipList` within a range of keys. The thread
+/// stays pinned while the iterator lives, so the nodes it walks through are
+/// not freed, while pairs inserted or removed meanwhile may or may not be
+/// seen.
+///
+/// Walking backwards takes a search from the top of the list per pair.
+pub struct Range<'a, K, V> {
+    list: &'a ConcurrentSkipList<K, V>,
+    guard: Guard,
+    /// The link to follow to the next node, in the head of the list or in
+    /// a node the guard keeps from being freed.
+    link: *const Atomic<Node<K, V>>,
+    /// The bounds of the pairs not yielded yet from either end.
+    min: Bound<K>,
+    max: Bound<K>,
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V>
+where
+    K: Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            let node = ConcurrentSkipList::next_node(unsafe { &*self.link }, &self.guard)?;
+            if !self.list.before(&node.key, self.max.as_ref()) {
+                return None;
+            }
+            self.link = &node.tower[0];
+            self.min = Bound::Excluded(node.key.clone());
+            let value = node.value.load(Ordering::Acquire, &self.guard);
+            if let Some(value) = unsafe { value.as_ref() } {
+                return Some((node.key.clone(), value.clone()));
+            }
+        }
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Range<'a, K, V>
+where
+    K: Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn next_back(&mut self) -> Option<(K, V)> {
+        loop {
+            let node = self.list.last_before(self.max.as_ref(), &self.guard)?;
+            if !self.list.after(&node.key, self.min.as_ref()) {
+                return None;
+            }
+            self.max = Bound::Excluded(node.key.clone());
+            let value = node.value.load(Ordering::Acquire, &self.guard);
+            if let Some(value) = unsafe { value.as_ref() } {
+                return Some((node.key.clone(), value.clone()));
+            }
+        }
+    }
+}
+
+impl<K, V> Run<K, V> for ConcurrentSkipList<K, V>
+where
+    K: Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    type Range<'a> = Range<'a, K, V> where Self: 'a;
+
+    #[inline]
+    fn new() -> Self
+    where
+        K: Ord,
+    {
+        ConcurrentSkipList::new()
+    }
+
+    fn get_min(&mut self) -> Option<K> {
+        let guard = &epoch::pin();
+        Self::next_node(&self.head[0], guard).map(|node| node.key.clone())
+    }
+
+    fn get_max(&mut self) -> Option<K> {
+        let guard = &epoch::pin();
+        self.last_before(Bound::Unbounded, guard).map(|node| node.key.clone())
+    }
+
+    fn insert_key(&mut self, key: K, value: V) {
+        self.insert(key, value, &epoch::pin());
+    }
+
+    fn delete_key(&mut self, key: &K) -> Option<V> {
+        self.remove(key, &epoch::pin()).cloned()
+    }
+
+    fn lookup(&self, key: &K) -> Option<V> {
+        self.get(key, &epoch::pin()).cloned()
+    }
+
+    fn num_elements(&self) -> i64 {
+        self.len() as i64
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.max_size = size;
+    }
+
+    fn get_all(&mut self) -> Vec<KVpair<K, V>> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+            .map(|(k, v)| KVpair::new(k, v))
+            .collect()
+    }
+
+    fn get_all_in_range(&mut self, key1: K, key2: K) -> Vec<KVpair<K, V>> {
+        self.range(Included(&key1), Included(&key2))
+            .map(|(k, v)| KVpair::new(k, v))
+            .collect()
+    }
+
+    fn range(&self, min: Bound<&K>, max: Bound<&K>) -> Range<'_, K, V> {
+        let guard = epoch::pin();
+        let link = match min {
+            Bound::Included(min) => self.search(min, &guard).preds[0],
+            Bound::Excluded(min) => {
+                let pos = self.search(min, &guard);
+                match pos.found {
+                    Some(node) => &node.tower[0],
+                    None => pos.preds[0],
+                }
+            }
+            Bound::Unbounded => &self.head[0],
+        };
+        let link: *const Atomic<Node<K, V>> = link;
+        Range {
+            list: self,
+            guard,
+            link,
+            min: min.cloned(),
+            max: max.cloned(),
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key, &epoch::pin()).is_some()
+    }
+}
+
+impl<K, V> Default for ConcurrentSkipList<K, V>
+where
+    K: Ord + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        ConcurrentSkipList::new()
+    }
+}
+
+impl<K, V> Drop for ConcurrentSkipList<K, V> {
+    fn drop(&mut self) {
+        // no other thread can hold a reference into the list anymore; the
+        // removed nodes were already retired.
+        unsafe {
+            let guard = epoch::unprotected();
+            let mut node = self.head[0].load(Ordering::Relaxed, guard);
+            while !node.is_null() {
+                let next = node.deref().tower[0].load(Ordering::Relaxed, guard);
+                drop(node.into_owned());
+                node = next.with_tag(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{pin, ConcurrentSkipList};
+    use crate::comparator::Comparator;
+    use crate::run::Run;
+
+    struct Descending;
+
+    impl Comparator<i32> for Descending {
+        fn name(&self) -> &str {
+            "descending"
+        }
+
+        fn compare(&self, a: &i32, b: &i32) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn insert_lookup_delete() {
+        let mut sl: ConcurrentSkipList<i32, i32> = ConcurrentSkipList::new();
+        for i in (0..100).rev() {
+            sl.insert_key(i, i * 10);
+        }
+        sl.insert_key(5, -5);
+        assert_eq!(sl.num_elements(), 100);
+        assert_eq!(sl.lookup(&5), Some(-5));
+        assert_eq!(sl.lookup(&100), None);
+        assert_eq!(sl.get_min(), Some(0));
+        assert_eq!(sl.get_max(), Some(99));
+
+        for i in (0..100).filter(|i| i % 2 == 0) {
+            assert!(sl.delete_key(&i).is_some());
+        }
+        assert_eq!(sl.delete_key(&0), None);
+        assert_eq!(sl.num_elements(), 50);
+        let keys: Vec<i32> = sl.get_all().into_iter().map(|kv| kv.key.unwrap()).collect();
+        assert_eq!(keys, (0..100).filter(|i| i % 2 == 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn range() {
+        let mut sl: ConcurrentSkipList<i32, i32> = ConcurrentSkipList::new();
+        for i in 0..20 {
+            sl.insert_key(i * 2, i);
+        }
+        let keys: Vec<i32> = sl.range(Included(&4), Included(&10)).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![4, 6, 8, 10]);
+        let keys: Vec<i32> = sl.range(Excluded(&4), Excluded(&10)).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![6, 8]);
+        let keys: Vec<i32> = sl.range(Included(&35), Unbounded).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![36, 38]);
+        assert_eq!(sl.range(Included(&10), Included(&4)).count(), 0);
+        assert_eq!(sl.get_all_in_range(3, 7).len(), 2);
+
+        let keys: Vec<i32> = sl.range(Excluded(&4), Included(&10)).rev().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![10, 8, 6]);
+        sl.delete_key(&8);
+        let mut range = sl.range(Unbounded, Excluded(&12));
+        assert_eq!(range.next_back(), Some((10, 5)));
+        assert_eq!(range.next(), Some((0, 0)));
+        assert_eq!(range.next_back(), Some((6, 3)));
+        let keys: Vec<i32> = range.map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![2, 4]);
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let mut sl: ConcurrentSkipList<i32, i32> = ConcurrentSkipList::with_comparator(Arc::new(Descending));
+        for i in 0..50 {
+            sl.insert_key(i, i);
+        }
+        assert_eq!(sl.get_min(), Some(49));
+        assert_eq!(sl.get_max(), Some(0));
+        assert_eq!(sl.lookup(&7), Some(7));
+        let keys: Vec<i32> = sl.range(Included(&10), Excluded(&6)).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![10, 9, 8, 7]);
+        let keys: Vec<i32> = sl.range(Excluded(&10), Included(&6)).rev().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![6, 7, 8, 9]);
+        assert_eq!(sl.delete_key(&10), Some(10));
+        assert_eq!(sl.lookup(&10), None);
+    }
+
+    #[test]
+    fn concurrent_writers_and_readers() {
+        let sl: Arc<ConcurrentSkipList<u64, u64>> = Arc::new(ConcurrentSkipList::new());
+        let writers: Vec<_> = (0..4u64)
+            .map(|t| {
+                let sl = Arc::clone(&sl);
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        sl.insert(i * 4 + t, i, &pin());
+                    }
+                    // every writer removes the odd keys it wrote.
+                    for i in (1..2000).step_by(2) {
+                        assert_eq!(sl.remove(&(i * 4 + t), &pin()), Some(&i));
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let sl = Arc::clone(&sl);
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    let keys: Vec<u64> = sl.range(Unbounded, Unbounded).map(|(k, _)| k).collect();
+                    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(sl.len(), 4000);
+        let guard = &pin();
+        for key in 0..8000 {
+            let expected = if (key / 4) % 2 == 0 { Some(key / 4) } else { None };
+            assert_eq!(sl.get(&key, guard).copied(), expected);
+        }
+    }
+
+    #[test]
+    fn concurrent_overwrites() {
+        let sl: Arc<ConcurrentSkipList<u32, u32>> = Arc::new(ConcurrentSkipList::new());
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let sl = Arc::clone(&sl);
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        sl.insert(i % 50, t, &pin());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(sl.len(), 50);
+        assert!((0..50).all(|key| sl.get(&key, &pin()).is_some_and(|&t| t < 4)));
+    }
+}
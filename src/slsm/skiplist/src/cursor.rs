@@ -0,0 +1,154 @@
+use std::cmp::Ordering;
+
+use crate::node::Node;
+use crate::skiplist::SkipList;
+
+/// A position in a `SkipList`, which can be moved to a key and stepped
+/// forwards and backwards along the nodes.
+///
+/// A cursor is either on a node or invalid, as it is when created and once
+/// it is stepped past either end of the list. Stepping an invalid cursor
+/// does nothing.
+pub struct Cursor<'a, K, V> {
+    list: &'a SkipList<K, V>,
+    node: Option<*const Node<K, V>>,
+}
+
+impl<'a, K, V> Cursor<'a, K, V> {
+    pub fn new(list: &'a SkipList<K, V>) -> Self {
+        Cursor { list, node: None }
+    }
+
+    /// Whether the cursor is on a node.
+    #[inline]
+    pub fn valid(&self) -> bool {
+        self.node.is_some()
+    }
+
+    #[inline]
+    pub fn key(&self) -> Option<&'a K> {
+        self.node.map(|node| unsafe { (*node).key.as_ref().unwrap() })
+    }
+
+    #[inline]
+    pub fn value(&self) -> Option<&'a V> {
+        self.node.map(|node| unsafe { (*node).value.as_ref().unwrap() })
+    }
+
+    /// Move to the first key not before `key`.
+    pub fn seek(&mut self, key: &K) {
+        unsafe {
+            let node = self.list.find_key(key);
+            match (*node).key {
+                Some(ref found) if self.list.comparator().compare(found, key) == Ordering::Equal => {
+                    self.node = Some(node)
+                }
+                _ => self.set((*node).forwards()[0]),
+            }
+        }
+    }
+
+    /// Move to the last key not after `key`.
+    pub fn seek_for_prev(&mut self, key: &K) {
+        self.set(Some(self.list.find_key(key) as *mut _));
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.set(unsafe { (*self.list.head).forwards()[0] });
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.set(Some(self.list.get_last() as *mut _));
+    }
+
+    /// Step to the next key, leaving the cursor invalid after the last one.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) {
+        if let Some(node) = self.node {
+            self.set(unsafe { (*node).forwards()[0] });
+        }
+    }
+
+    /// Step to the previous key, leaving the cursor invalid before the
+    /// first one.
+    pub fn prev(&mut self) {
+        if let Some(node) = self.node {
+            self.set(unsafe { (*node).prev });
+        }
+    }
+
+    /// Point at `node`, the header meaning no node.
+    fn set(&mut self, node: Option<*mut Node<K, V>>) {
+        self.node = node
+            .filter(|&node| unsafe { !(*node).is_header() })
+            .map(|node| node as *const _);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run::Run;
+    use crate::skiplist::SkipList;
+
+    #[test]
+    fn seek_and_step() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in 0..50 {
+            sl.insert_key(i * 2, i);
+        }
+        let mut cursor = sl.cursor();
+        assert!(!cursor.valid());
+
+        cursor.seek(&11);
+        assert_eq!(cursor.key(), Some(&12));
+        cursor.seek(&12);
+        assert_eq!(cursor.key(), Some(&12));
+        cursor.seek_for_prev(&11);
+        assert_eq!(cursor.key(), Some(&10));
+        cursor.seek_for_prev(&10);
+        assert_eq!(cursor.value(), Some(&5));
+
+        cursor.next();
+        assert_eq!(cursor.key(), Some(&12));
+        cursor.prev();
+        cursor.prev();
+        assert_eq!(cursor.key(), Some(&8));
+
+        cursor.seek(&99);
+        assert!(!cursor.valid());
+        cursor.seek_for_prev(&-1);
+        assert!(!cursor.valid());
+        cursor.next();
+        assert!(!cursor.valid());
+    }
+
+    #[test]
+    fn walk_both_ways() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        let mut cursor = sl.cursor();
+        cursor.seek_to_first();
+        assert!(!cursor.valid());
+        cursor.seek_to_last();
+        assert!(!cursor.valid());
+
+        for i in (0..20).rev() {
+            sl.insert_key(i, i);
+        }
+        let mut cursor = sl.cursor();
+        let mut keys = Vec::new();
+        cursor.seek_to_last();
+        while let Some(&key) = cursor.key() {
+            keys.push(key);
+            cursor.prev();
+        }
+        assert_eq!(keys, (0..20).rev().collect::<Vec<_>>());
+
+        keys.clear();
+        cursor.seek_to_first();
+        while let Some(&key) = cursor.key() {
+            keys.push(key);
+            cursor.next();
+        }
+        assert_eq!(keys, (0..20).collect::<Vec<_>>());
+    }
+}
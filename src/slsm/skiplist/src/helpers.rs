@@ -1,3 +1,6 @@
+use std::io;
+
+use error::{Error, Result};
 use rand::prelude::*;
 
 pub trait LevelGenerator {
@@ -13,18 +16,15 @@ pub struct GeoLevelGenerator {
 }
 
 impl GeoLevelGenerator {
-    pub fn new(total: usize, p: f64) -> Self {
+    pub fn new(total: usize, p: f64) -> Result<Self> {
         if total == 0 {
-            panic!("total can not be zero.");
+            return Err(Error::invalid_argument("total can not be zero"));
         }
         if p <= 0.0 || p >= 1.0 {
-            panic!("p value must in between in (0, 1)");
-        }
-        GeoLevelGenerator {
-            total,
-            p,
-            rng: SmallRng::from_rng(thread_rng()).unwrap(),
+            return Err(Error::invalid_argument("p value must in between in (0, 1)"));
         }
+        let rng = SmallRng::from_rng(thread_rng()).map_err(io::Error::other)?;
+        Ok(GeoLevelGenerator { total, p, rng })
     }
 }
 
@@ -48,28 +48,27 @@ impl LevelGenerator for GeoLevelGenerator {
 #[cfg(test)]
 mod tests {
 
+    use error::Error;
+
     use super::GeoLevelGenerator;
 
     #[test]
-    #[should_panic]
     fn invalid_total() {
-        GeoLevelGenerator::new(0, 0.5);
+        assert!(matches!(GeoLevelGenerator::new(0, 0.5), Err(Error::InvalidArgument(_))));
     }
 
     #[test]
-    #[should_panic]
     fn invalid_p_0() {
-        GeoLevelGenerator::new(1, 0.0);
+        assert!(matches!(GeoLevelGenerator::new(1, 0.0), Err(Error::InvalidArgument(_))));
     }
 
     #[test]
-    #[should_panic]
     fn invalid_p_1() {
-        GeoLevelGenerator::new(1, 1.0);
+        assert!(matches!(GeoLevelGenerator::new(1, 1.0), Err(Error::InvalidArgument(_))));
     }
 
     #[test]
     fn new() {
-        GeoLevelGenerator::new(1, 0.5);
+        GeoLevelGenerator::new(1, 0.5).unwrap();
     }
 }
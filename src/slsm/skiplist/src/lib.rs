@@ -1,9 +1,16 @@
+pub mod arena;
+pub mod comparator;
+pub mod concurrent;
+pub mod cursor;
 pub mod helpers;
 pub mod node;
 pub mod run;
 pub mod skiplist;
 
-pub use crate::run::Run;
+pub use crate::comparator::{Comparator, OrdComparator};
+pub use crate::concurrent::ConcurrentSkipList;
+pub use crate::cursor::Cursor;
+pub use crate::run::{RangeTombstone, Run};
 pub use crate::skiplist::SkipList;
 
 extern crate rand;
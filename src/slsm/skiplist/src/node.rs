@@ -1,54 +1,74 @@
 use std::fmt;
-use std::iter;
 
+use crate::arena::Arena;
+
+/// A node of a `SkipList`, allocated in the arena of the list along with
+/// its tower of links.
 pub struct Node<K, V> {
     pub key:   Option<K>,
     pub value: Option<V>,
+    /// The write of the list that last wrote the pair, telling it apart
+    /// from the range tombstones recorded before and after it.
+    pub stamp: u64,
     pub max_level: usize,
-    // forwards: vector of links to next node at the respective level.
-    // this vector must be of length `self.level + 1`.
-    // links[0] stores a pointer to the same node as next.
-    pub forwards: Vec<Option<*mut Node<K, V>>>,
     pub prev: Option<*mut Node<K, V>>,
-    pub next: Option<Box<Node<K, V>>>,
-    pub links_len: Vec<usize>,
+    // forwards: links to the next node at the respective level.
+    // this slice is of length `self.max_level + 1`, as is `links_len`, which
+    // holds the number of nodes each link skips over.
+    forwards: *mut [Option<*mut Node<K, V>>],
+    links_len: *mut [usize],
 }
 
 impl<K, V> Node<K, V> {
-    pub fn head(max_level: usize) -> Self {
-        Node {
+    /// The header of a list, reaching all `max_level` levels.
+    pub fn alloc_head(arena: &mut Arena, max_level: usize) -> *mut Self {
+        let node = Node {
             key:   None,
             value: None,
-            next: None,
+            stamp: 0,
             prev: None,
             max_level,
-            forwards: iter::repeat(None).take(max_level).collect(),
-            links_len: iter::repeat(0).take(max_level).collect(),
-        }
+            forwards: arena.alloc_slice(max_level, None),
+            links_len: arena.alloc_slice(max_level, 0),
+        };
+        arena.alloc_value(node)
     }
 
-    pub fn new(key: K, value: V, max_level: usize) -> Self {
-        Node {
+    pub fn alloc(arena: &mut Arena, key: K, value: V, max_level: usize) -> *mut Self {
+        let node = Node {
             key: Some(key),
             value: Some(value),
-            max_level: max_level,
-            next: None,
+            stamp: 0,
+            max_level,
             prev: None,
-            forwards: iter::repeat(None).take(max_level + 1).collect(),
-            links_len: iter::repeat(0).take(max_level).collect(),
-        }
+            forwards: arena.alloc_slice(max_level + 1, None),
+            links_len: arena.alloc_slice(max_level + 1, 0),
+        };
+        arena.alloc_value(node)
     }
 
-    pub fn is_header(&self) -> bool {
-        self.prev.is_none()
+    #[inline]
+    pub fn forwards(&self) -> &[Option<*mut Node<K, V>>] {
+        unsafe { &*self.forwards }
     }
 
-    pub fn into_inner(self) -> Option<(K, V)> {
-        if self.key.is_some() {
-            Some((self.key.unwrap(), self.value.unwrap()))
-        } else {
-            None
-        }
+    #[inline]
+    pub fn forwards_mut(&mut self) -> &mut [Option<*mut Node<K, V>>] {
+        unsafe { &mut *self.forwards }
+    }
+
+    #[inline]
+    pub fn links_len(&self) -> &[usize] {
+        unsafe { &*self.links_len }
+    }
+
+    #[inline]
+    pub fn links_len_mut(&mut self) -> &mut [usize] {
+        unsafe { &mut *self.links_len }
+    }
+
+    pub fn is_header(&self) -> bool {
+        self.prev.is_none()
     }
 }
 
@@ -58,18 +78,10 @@ where
     V: fmt::Display,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let (&Some(ref k), &Some(ref v)) = (&self.key, &self.value) {
+        if let (Some(k), Some(v)) = (&self.key, &self.value) {
             write!(f, "({}, {})", k, v)
         } else {
             Ok(())
         }
     }
 }
-
-// impl<K, V> Clone for Node<K, V> {
-//     fn clone(&self) -> Node<K, V> {
-//         match self {
-
-//         }
-//     }
-// }
\ No newline at end of file
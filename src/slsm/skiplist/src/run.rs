@@ -1,25 +1,42 @@
-use std::borrow::Borrow;
-use std::cmp::Ordering;
 use std::cmp::PartialEq;
-use std::cmp::PartialOrd;
 use std::marker::PhantomData;
 use std::ops::Bound;
 
 use crate::node::Node;
 
 // Key-value pair struct start
+/// A key and its value. A pair without a value is a tombstone: it records
+/// the deletion of `key` and hides every older value of it.
+///
+/// Pairs have no order of their own: they are sorted by the `Comparator`
+/// of the run holding them.
 #[derive(Copy, Clone, Debug)]
 pub struct KVpair<K, V> {
     // todo impl iterator for KVpair
-    // todo impl partialOrd for KVpair
     pub key: Option<K>,
     pub value: Option<V>,
 }
 
-impl<K: Ord, V: Ord> Ord for KVpair<K, V> {
+impl<K, V> KVpair<K, V> {
     #[inline]
-    fn cmp(&self, other: &KVpair<K, V>) -> Ordering {
-        self.key.cmp(&other.key)
+    pub fn new(key: K, value: V) -> Self {
+        KVpair {
+            key: Some(key),
+            value: Some(value),
+        }
+    }
+
+    #[inline]
+    pub fn tombstone(key: K) -> Self {
+        KVpair {
+            key: Some(key),
+            value: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_none()
     }
 }
 
@@ -32,57 +49,30 @@ where
     fn eq(&self, other: &KVpair<K, V>) -> bool {
         self.key == other.key && self.value == other.value
     }
-
-    #[inline]
-    fn ne(&self, other: &KVpair<K, V>) -> bool {
-        self.key != other.key && self.key != other.key
-    }
 }
 
 impl<K: Eq, V: Eq> Eq for KVpair<K, V> {}
 
-impl<K, V> PartialOrd for KVpair<K, V>
-where
-    K: PartialOrd, 
-    V: PartialOrd, 
-{
-
-    // todo partial ord impl is wrong, check collection btree s impl and copy that.
-
-/*
-#[stable(feature = "rust1", since = "1.0.0")]                                    
-impl<K: PartialOrd, V: PartialOrd> PartialOrd for BTreeMap<K, V> {               
-    #[inline]                                                                    
-    fn partial_cmp(&self, other: &BTreeMap<K, V>) -> Option<Ordering> {          
-        self.iter().partial_cmp(other.iter())                                    
-    }                                                                            
-}   
-*/
-    #[inline]
-    fn partial_cmp(&self, other: &KVpair<K, V>) -> Option<Ordering> {
-        self.key.unwrap().cmp(&other.key.unwrap())
-    }
-
-    #[inline]
-    fn gt(&self, other: &KVpair<K, V>) -> bool {
-        self.key > other.key
-    }
-    
-    #[inline]
-    fn ge(&self, other: &KVpair<K, V>) -> bool {
-        self.key >= other.key
-    }
-
-    #[inline]
-    fn lt(&self, other: &KVpair<K, V>) -> bool {
-        self.key < other.key
-    }
+/// The deletion of every key from `start` included to `end` excluded,
+/// recorded once rather than as a tombstone per key.
+///
+/// The `Comparator` of the run holding it tells which keys it deletes: by
+/// default every key between its bounds, keys written after it included,
+/// while a comparator of versioned keys only deletes the versions older
+/// than the tombstone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeTombstone<K> {
+    pub start: K,
+    pub end: K,
+}
 
+impl<K> RangeTombstone<K> {
     #[inline]
-    fn le(&self, other: &KVpair<K, V>) -> bool {
-        self.key <= other.key
+    pub fn new(start: K, end: K) -> Self {
+        RangeTombstone { start, end }
     }
 }
+
 // Key value pair struct end
 
 // Run Iterator
@@ -94,22 +84,22 @@ pub struct Iter<'a, K: 'a, V: 'a> {
     pub _lifetime_v: PhantomData<&'a V>,
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
-    type Item = (&'a K, &'a V);
+impl<'a, K: Clone, V: Clone> Iterator for Iter<'a, K, V> {
+    type Item = (K, V);
 
-    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+    fn next(&mut self) -> Option<(K, V)> {
         unsafe {
             if self.start == self.end {
                 return None;
             }
-            if let Some(next) = (*self.start).forwards[0] {
+            if let Some(next) = (*self.start).forwards()[0] {
                 self.start = next;
                 if self.size > 0 {
                     self.size -= 1;
                 }
                 return Some((
-                    (*self.start).key.as_ref().unwrap(),
-                    (*self.start).value.as_ref().unwrap(),
+                    (*self.start).key.clone().unwrap(),
+                    (*self.start).value.clone().unwrap(),
                 ));
             }
             None
@@ -120,42 +110,52 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> {
         (self.size, Some(self.size))
     }
 }
+
+impl<'a, K: Clone, V: Clone> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        unsafe {
+            if self.start == self.end {
+                return None;
+            }
+            let node = self.end;
+            self.end = (*node).prev.unwrap();
+            if self.size > 0 {
+                self.size -= 1;
+            }
+            Some(((*node).key.clone().unwrap(), (*node).value.clone().unwrap()))
+        }
+    }
+}
+
+impl<'a, K: Clone, V: Clone> ExactSizeIterator for Iter<'a, K, V> {}
 // Run Iterator end
 
+/// A sorted in-memory run of pairs.
+///
+/// Keys and values are handed out by copy rather than by reference, as a
+/// run shared between threads can not lend out a value that another thread
+/// may remove. Keys are looked up by the order of the run, which need not
+/// be their `Ord` impl, so lookups take a key rather than a borrowed form
+/// of it.
 pub trait Run<K, V> {
-    fn new() -> Self;
+    /// The iterator returned by `range`, which can be walked from both
+    /// ends.
+    type Range<'a>: DoubleEndedIterator<Item = (K, V)>
+    where
+        Self: 'a;
+
+    fn new() -> Self
+    where
+        K: Ord;
     fn get_min(&mut self) -> Option<K>;
     fn get_max(&mut self) -> Option<K>;
     fn insert_key(&mut self, key: K, value: V);
-    fn delete_key<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
-    where
-        K: Borrow<Q>,
-        Q: Ord;
-    fn lookup<Q: ?Sized>(&self, key: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-        Q: Ord;
-    fn find_key<Q: ?Sized>(&self, key: &Q) -> *const Node<K, V>
-    where
-        K: Borrow<Q>,
-        Q: Ord;
+    fn delete_key(&mut self, key: &K) -> Option<V>;
+    fn lookup(&self, key: &K) -> Option<V>;
     fn num_elements(&self) -> i64;
     fn set_size(&mut self, size: usize);
-    fn get_last(&self) -> *const Node<K, V>;
     fn get_all(&mut self) -> Vec<KVpair<K, V>>;
     fn get_all_in_range(&mut self, key1: K, key2: K) -> Vec<KVpair<K, V>>;
-    fn range<Q>(&self, min: Bound<&Q>, max: Bound<&Q>) -> Iter<K, V>
-    where
-        K: Borrow<Q>,
-        Q: Ord;
-    fn link_length(
-        &self,
-        start: *mut Node<K, V>,
-        end: Option<*mut Node<K, V>>,
-        lvl: usize,
-    ) -> Result<usize, bool>;
-    fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
-    where
-        K: Borrow<Q>,
-        Q: Ord;
+    fn range(&self, min: Bound<&K>, max: Bound<&K>) -> Self::Range<'_>;
+    fn contains_key(&self, key: &K) -> bool;
 }
@@ -1,23 +1,39 @@
-use std::borrow::Borrow;
-use std::cmp;
 use std::cmp::Ordering;
 use std::default;
 use std::marker::PhantomData;
-use std::mem;
 use std::ops::Drop;
 use std::ops::Bound;
 use std::ops::Bound::Included;
+use std::ops::RangeBounds;
+use std::ptr;
+use std::sync::Arc;
 
+use crate::arena::Arena;
+use crate::comparator::{Comparator, OrdComparator};
+use crate::cursor::Cursor;
 use crate::helpers::GeoLevelGenerator;
 use crate::helpers::LevelGenerator;
 use crate::node::Node;
 use crate::run::Iter;
 use crate::run::KVpair;
+use crate::run::RangeTombstone;
 use crate::run::Run;
 
+/// A skiplist whose nodes are allocated in an arena, so a run takes a few
+/// large chunks of memory rather than three allocations per pair, and its
+/// size in bytes is known. Keys and values are stored inline in the nodes:
+/// the memory they own themselves, such as the bytes of a `String`, is out
+/// of the arena, and counted only as told by `add_heap_bytes`.
+///
+/// Keys are ordered by the comparator of the list, `OrdComparator` unless
+/// it is built by `with_comparator`.
+///
+/// The range tombstones recorded by `delete_range` are kept apart from the
+/// pairs: `lookup` and `contains_key` respect them, while iterating the
+/// list still yields the pairs they cover. A pair written after a tombstone
+/// covering it is not deleted by it.
 pub struct SkipList<K, V> {
-    pub head: Option<Box<Node<K, V>>>,
-    pub tail: Option<Box<Node<K, V>>>,
+    pub head: *mut Node<K, V>,
     pub current_max_level: isize,
     // how high the node reaches, this should be euqal to be the vector length.
     pub max_level: isize,
@@ -27,347 +43,198 @@ pub struct SkipList<K, V> {
     pub max_key: Option<K>,
     pub n: i64,
     pub max_size: usize,
+    /// Size in bytes from which the run is full, or 0 for no limit.
+    pub max_bytes: usize,
+    /// Bytes owned by the keys and values themselves.
+    heap_bytes: usize,
     level_gen: GeoLevelGenerator,
+    arena: Arena,
+    cmp: Arc<dyn Comparator<K>>,
+    range_tombstones: Vec<RangeTombstone<K>>,
+    /// The write that recorded each range tombstone.
+    tombstone_stamps: Vec<u64>,
+    /// Number of writes to the list, pairs and range tombstones.
+    writes: u64,
 }
 
 impl<K, V> Run<K, V> for SkipList<K, V>
 where
-    K: cmp::Ord,
+    K: Clone,
+    V: Clone,
 {
+    type Range<'a> = Iter<'a, K, V> where Self: 'a;
+
     #[inline]
-    fn new() -> Self {
-        let level_gen = GeoLevelGenerator::new(16, 1.0 / 2.0);
-        SkipList {
-            head: Some(Box::new(Node::head(level_gen.total()))),
-            tail: Some(Box::new(Node::head(level_gen.total()))),
-            current_max_level: 1,
-            max_level: 12,
-            min: None,
-            max: None,
-            min_key: None,
-            max_key: None,
-            n: 0,
-            max_size: 0,
-            level_gen,
-        }
+    fn new() -> Self
+    where
+        K: Ord,
+    {
+        SkipList::with_comparator(Arc::new(OrdComparator))
     }
 
     fn get_min(&mut self) -> Option<K> {
-        // self.head
         unsafe {
-            let header: Node<K, V> = mem::transmute_copy(&self.head);
-            return header.key
+            match (*self.head_ptr()).forwards()[0] {
+                Some(first) => (*first).key.clone(),
+                None => None,
+            }
         }
     }
 
     fn get_max(&mut self) -> Option<K> {
-        unsafe {
-            let max: Node<K, V> = mem::transmute_copy(&self.get_last());
-            return max.key
-        }
+        unsafe { (*self.get_last()).key.clone() }
     }
 
     fn insert_key(&mut self, key: K, value: V) {
         unsafe {
-            let mut lvl = self.level_gen.total();
-            let mut node: *mut Node<K, V> = mem::transmute_copy(&self.head);
-            let mut existing_node: Option<*mut Node<K, V>> = None;
-            let mut prev_nodes: Vec<*mut Node<K, V>> = Vec::with_capacity(self.level_gen.total());
-
+            let total = self.level_gen.total();
+            let mut node: *mut Node<K, V> = self.head_mut();
+            // prev_nodes[lvl] is the last node before `key` on level `lvl`,
+            // ranks[lvl] is its position counted from the header.
+            let mut prev_nodes: Vec<*mut Node<K, V>> = vec![ptr::null_mut(); total];
+            let mut ranks: Vec<usize> = vec![0; total];
+            let mut rank = 0;
+
+            let mut lvl = total;
             while lvl > 0 {
                 lvl -= 1;
-                if let Some(existing_node) = existing_node {
-                    while let Some(next) = (*node).forwards[lvl] {
-                        if next == existing_node {
-                            prev_nodes.push(node);
-                            break;
-                        } else {
+
+                while let Some(next) = (*node).forwards()[lvl] {
+                    match self.cmp.compare((*next).key.as_ref().unwrap(), &key) {
+                        Ordering::Less => {
+                            rank += (*node).links_len()[lvl];
                             node = next;
-                            continue;
                         }
-                    }
-                } else {
-                    while let Some(next) = (*node).forwards[lvl] {
-                        if let Some(ref next_key) = (*next).key {
-                            match next_key.cmp(&key) {
-                                Ordering::Less => {
-                                    node = next;
-                                    continue;
-                                }
-                                Ordering::Equal => {
-                                    existing_node = Some(next);
-                                    prev_nodes.push(node);
-                                    break;
-                                }
-                                Ordering::Greater => {
-                                    prev_nodes.push(node);
-                                    break;
-                                }
-                            }
+                        Ordering::Equal => {
+                            self.writes += 1;
+                            (*next).value = Some(value);
+                            (*next).stamp = self.writes;
+                            return;
                         }
-                    }
-                    if (*node).forwards[lvl].is_none() {
-                        prev_nodes.push(node);
-                        continue;
+                        Ordering::Greater => break,
                     }
                 }
+                prev_nodes[lvl] = node;
+                ranks[lvl] = rank;
             }
 
-            if let Some(existing_node) = existing_node {
-                mem::replace(&mut (*existing_node).value, Some(value));
-            } else {
-                let mut new_node = Box::new(Node::new(key, value, self.level_gen.random()));
-                let new_node_ptr: *mut Node<K, V> = mem::transmute_copy(&new_node);
-
-                for (lvl, &prev_node) in prev_nodes.iter().rev().enumerate() {
-                    if lvl <= new_node.max_level {
-                        new_node.forwards[lvl] = (*prev_node).forwards[lvl];
-                        (*prev_node).forwards[lvl] = Some(new_node_ptr);
-
-                        if lvl == 0 {
-                            new_node.prev = Some(prev_node);
-                            if let Some(next) = new_node.forwards[lvl] {
-                                (*next).prev = Some(new_node_ptr);
-                            }
-                            new_node.links_len[lvl] = 1;
-                        } else {
-                            let length = self
-                                .link_length(prev_node, Some(new_node_ptr), lvl)
-                                .unwrap();
-                            new_node.links_len[lvl] = (*prev_node).links_len[lvl] - length + 1;
-                            (*prev_node).links_len[lvl] = length;
-                        }
-                    } else {
-                        (*prev_node).links_len[lvl] += 1;
-                    }
+            let level = self.level_gen.random();
+            let new_node = Node::alloc(&mut self.arena, key, value, level);
+            self.writes += 1;
+            (*new_node).stamp = self.writes;
+            let new_rank = ranks[0] + 1;
+
+            for (lvl, &prev_node) in prev_nodes.iter().enumerate() {
+                let prev_links = (*prev_node).links_len_mut();
+                if lvl <= level {
+                    let length = new_rank - ranks[lvl];
+                    (*new_node).forwards_mut()[lvl] = (*prev_node).forwards()[lvl];
+                    (*prev_node).forwards_mut()[lvl] = Some(new_node);
+                    (*new_node).links_len_mut()[lvl] = prev_links[lvl] + 1 - length;
+                    prev_links[lvl] = length;
+                } else {
+                    prev_links[lvl] += 1;
                 }
+            }
 
-                let prev_node = (*new_node_ptr).prev.unwrap();
-                let tmp = mem::replace(&mut (*prev_node).next, Some(new_node));
-                if let Some(ref mut node) = (*prev_node).next {
-                    node.next = tmp;
-                }
-                self.n += 1;
+            (*new_node).prev = Some(prev_nodes[0]);
+            if let Some(next) = (*new_node).forwards()[0] {
+                (*next).prev = Some(new_node);
             }
+            self.n += 1;
         }
     }
 
-    fn delete_key<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
+    fn delete_key(&mut self, key: &K) -> Option<V> {
         if self.n == 0 {
             return None;
         }
 
         unsafe {
-            let mut node: *mut Node<K, V> = mem::transmute_copy(&self.head);
+            let total = self.level_gen.total();
+            let mut node: *mut Node<K, V> = self.head_mut();
             let mut return_node: Option<*mut Node<K, V>> = None;
-            let mut prev_nodes: Vec<*mut Node<K, V>> = Vec::with_capacity(self.level_gen.total());
+            let mut prev_nodes: Vec<*mut Node<K, V>> = vec![ptr::null_mut(); total];
 
-            let mut lvl = self.level_gen.total();
+            let mut lvl = total;
             while lvl > 0 {
                 lvl -= 1;
 
-                if let Some(return_node) = return_node {
-                    while let Some(next) = (*node).forwards[lvl] {
-                        if next == return_node {
-                            prev_nodes.push(node);
+                while let Some(next) = (*node).forwards()[lvl] {
+                    match self.cmp.compare((*next).key.as_ref().unwrap(), key) {
+                        Ordering::Less => node = next,
+                        Ordering::Equal => {
+                            return_node = Some(next);
                             break;
-                        } else {
-                            node = next;
-                        }
-                    }
-                } else {
-                    if (*node).forwards[lvl].is_none() {
-                        prev_nodes.push(node);
-                        continue;
-                    }
-                    while let Some(next) = (*node).forwards[lvl] {
-                        if let Some(ref next_key) = (*next).key {
-                            match next_key.borrow().cmp(key) {
-                                Ordering::Less => {
-                                    node = next;
-                                    continue;
-                                }
-                                Ordering::Equal => {
-                                    return_node = Some(next);
-                                    prev_nodes.push(node);
-                                    break;
-                                }
-                                Ordering::Greater => {
-                                    prev_nodes.push(node);
-                                    break;
-                                }
-                            }
                         }
+                        Ordering::Greater => break,
                     }
                 }
+                prev_nodes[lvl] = node;
             }
 
-            if let Some(return_node) = return_node {
-                for (lvl, &prev_node) in prev_nodes.iter().rev().enumerate() {
-                    if (*prev_node).forwards[lvl] == Some(return_node) {
-                        (*prev_node).forwards[lvl] = (*return_node).forwards[lvl];
-                        (*prev_node).links_len[lvl] += (*return_node).links_len[lvl] - 1;
-                    } else {
-                        (*prev_node).links_len[lvl] -= 1;
-                    }
-                }
-                if let Some(next_node) = (*return_node).forwards[0] {
-                    (*next_node).prev = (*return_node).prev;
-                }
-                self.n -= 1;
-                Some(
-                    mem::replace(
-                        &mut (*(*return_node).prev.unwrap()).next,
-                        mem::replace(&mut (*return_node).next, None),
-                    )
-                    .unwrap()
-                    .into_inner()
-                    .unwrap()
-                    .1,
-                )
-            } else {
-                None
-            }
-        }
-    }
-
-    fn find_key<Q: ?Sized>(&self, key: &Q) -> *const Node<K, V>
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
-        unsafe {
-            let mut node: *const Node<K, V> = mem::transmute_copy(&self.head);
-
-            let mut lvl = self.level_gen.total();
-            while lvl > 0 {
-                lvl -= 1;
-
-                while let Some(next) = (*node).forwards[lvl] {
-                    if let Some(ref next_key) = (*next).key {
-                        match next_key.borrow().cmp(key) {
-                            Ordering::Less => node = next,
-                            Ordering::Equal => return next,
-                            Ordering::Greater => break,
-                        }
-                    } else {
-                        panic!("Encountered a value-less node.");
-                    }
-                }
-            }
-            node
+            self.unlink(&prev_nodes, return_node?).map(|(_, value)| value)
         }
     }
 
-    fn lookup<Q: ?Sized>(&self, key: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
-        unsafe {
-            let mut node: *const Node<K, V> = mem::transmute_copy(&self.head);
-            let mut lvl = self.level_gen.total();
-            while lvl > 0 {
-                lvl -= 1;
-
-                while let Some(next) = (*node).forwards[lvl] {
-                    if let Some(ref next_key) = (*next).key {
-                        match next_key.borrow().cmp(key) {
-                            Ordering::Less => {
-                                node = next;
-                                continue;
-                            }
-                            Ordering::Equal => {
-                                // &mut found = true;
-                                return (*next).value.as_ref();
-                            }
-                            Ordering::Greater => break,
-                        }
-                    }
-                }
-            }
-            None
+    fn lookup(&self, key: &K) -> Option<V> {
+        let node = self.find_node(key)?;
+        if self.is_deleted_after(key, node.stamp) {
+            return None;
         }
+        node.value.clone()
     }
 
     fn num_elements(&self) -> i64 {
-        return self.n;
+        self.n
     }
+
     fn set_size(&mut self, size: usize) {
         self.max_size = size;
     }
+
     fn get_all(&mut self) -> Vec<KVpair<K, V>> {
+        let mut all: Vec<KVpair<K, V>> = Vec::with_capacity(self.n as usize);
         unsafe {
-            let mut all: Vec<KVpair<K, V>> = Vec::with_capacity(self.level_gen.total());
-
-            let mut node: *mut Node<K, V> = mem::transmute(&self.head);
-
-            let mut lvl = self.level_gen.total();
-
-            while lvl > 0 {
-                lvl -= 1;
-
-                while let Some(next) = (*node).forwards[lvl] {
-                    let node_key   = mem::transmute_copy(&(*node).key);
-                    let node_value = mem::transmute_copy(&(*node).value);
-                    let kv = KVpair {
-                        key:   node_key,
-                        value: node_value,
-                    };
-                    all.push(kv);
-                    node = next;
-                }
+            let mut node: *const Node<K, V> = self.head_ptr();
+            while let Some(next) = (*node).forwards()[0] {
+                all.push(KVpair {
+                    key: (*next).key.clone(),
+                    value: (*next).value.clone(),
+                });
+                node = next;
             }
-            all
         }
+        all
     }
 
     fn get_all_in_range(&mut self, key1: K, key2: K) -> Vec<KVpair<K, V>> {
-        unsafe {
-            let mut all: Vec<KVpair<K, V>> = Vec::with_capacity(self.level_gen.total());
-
-            for (k, v) in self.range(Included(&key1), Included(&key2)) {
-                let node_key   = mem::transmute_copy(&k);
-                let node_value = mem::transmute_copy(&v);
-                let kv = KVpair {
-                    key:   node_key, 
-                    value: node_value,
-                };
-                all.push(kv);
-            }
-            all
-        }
+        self.range(Included(&key1), Included(&key2))
+            .map(|(k, v)| KVpair::new(k, v))
+            .collect()
     }
 
-    fn range<Q>(&self, min: Bound<&Q>, max: Bound<&Q>) -> Iter<K, V>
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
+    fn range(&self, min: Bound<&K>, max: Bound<&K>) -> Iter<'_, K, V> {
         unsafe {
             let start = match min {
                 Bound::Included(min) => {
                     let mut node = self.find_key(min);
                     if let Some(ref key) = (*node).key {
-                        if key.borrow() == min {
+                        if self.cmp.compare(key, min) == Ordering::Equal {
                             node = (*node).prev.unwrap();
                         }
                     }
                     node
                 }
                 Bound::Excluded(min) => self.find_key(min),
-                Bound::Unbounded => mem::transmute_copy(&self.head),
+                Bound::Unbounded => self.head_ptr(),
             };
             let end = match max {
                 Bound::Included(max) => self.find_key(max),
                 Bound::Excluded(max) => {
                     let mut node = self.find_key(max);
                     if let Some(ref key) = (*node).key {
-                        if key.borrow() == max {
+                        if self.cmp.compare(key, max) == Ordering::Equal {
                             node = (*node).prev.unwrap();
                         }
                     }
@@ -375,11 +242,7 @@ where
                 }
                 Bound::Unbounded => self.get_last(),
             };
-            match self.link_length(
-                start as *mut Node<K, V>,
-                Some(end as *mut Node<K, V>),
-                cmp::min((*start).max_level, (*end).max_level) + 1,
-            ) {
+            match self.link_length(start as *mut Node<K, V>, Some(end as *mut Node<K, V>), 1) {
                 Err(_) => Iter {
                     start,
                     end: start,
@@ -398,15 +261,103 @@ where
         }
     }
 
-    fn get_last(&self) -> *const Node<K, V> {
+    fn contains_key(&self, key: &K) -> bool {
+        self.lookup(key).is_some()
+    }
+}
+
+impl<K, V> SkipList<K, V> {
+    /// An empty list ordering its keys by `cmp`.
+    pub fn with_comparator(cmp: Arc<dyn Comparator<K>>) -> Self {
+        let level_gen = GeoLevelGenerator::new(16, 1.0 / 2.0).expect("valid level generator parameters");
+        let mut arena = Arena::new();
+        SkipList {
+            head: Node::alloc_head(&mut arena, level_gen.total()),
+            current_max_level: 1,
+            max_level: 12,
+            min: None,
+            max: None,
+            min_key: None,
+            max_key: None,
+            n: 0,
+            max_size: 0,
+            max_bytes: 0,
+            heap_bytes: 0,
+            level_gen,
+            arena,
+            cmp,
+            range_tombstones: Vec::new(),
+            tombstone_stamps: Vec::new(),
+            writes: 0,
+        }
+    }
+
+    #[inline]
+    pub fn comparator(&self) -> &Arc<dyn Comparator<K>> {
+        &self.cmp
+    }
+
+    /// The node holding `key`, or else the last node before it, which may
+    /// be the header.
+    pub fn find_key(&self, key: &K) -> *const Node<K, V> {
         unsafe {
-            let mut node: *const Node<K, V> = mem::transmute_copy(&self.head);
+            let mut node: *const Node<K, V> = self.head_ptr();
 
             let mut lvl = self.level_gen.total();
             while lvl > 0 {
                 lvl -= 1;
 
-                while let Some(next) = (*node).forwards[lvl] {
+                while let Some(next) = (*node).forwards()[lvl] {
+                    if let Some(ref next_key) = (*next).key {
+                        match self.cmp.compare(next_key, key) {
+                            Ordering::Less => node = next,
+                            Ordering::Equal => return next,
+                            Ordering::Greater => break,
+                        }
+                    } else {
+                        panic!("Encountered a value-less node.");
+                    }
+                }
+            }
+            node
+        }
+    }
+
+    /// The position of `key` in key order, counting from 0, in O(log n).
+    pub fn rank_of(&self, key: &K) -> Option<usize> {
+        unsafe {
+            let mut node: *const Node<K, V> = self.head_ptr();
+            let mut rank = 0;
+
+            let mut lvl = self.level_gen.total();
+            while lvl > 0 {
+                lvl -= 1;
+
+                while let Some(next) = (*node).forwards()[lvl] {
+                    match self.cmp.compare((*next).key.as_ref().unwrap(), key) {
+                        Ordering::Less => {
+                            rank += (*node).links_len()[lvl];
+                            node = next;
+                        }
+                        Ordering::Equal => return Some(rank + (*node).links_len()[lvl] - 1),
+                        Ordering::Greater => break,
+                    }
+                }
+            }
+            None
+        }
+    }
+
+    /// The last node of the list, or the header if it is empty.
+    pub fn get_last(&self) -> *const Node<K, V> {
+        unsafe {
+            let mut node: *const Node<K, V> = self.head_ptr();
+
+            let mut lvl = self.level_gen.total();
+            while lvl > 0 {
+                lvl -= 1;
+
+                while let Some(next) = (*node).forwards()[lvl] {
                     node = next;
                 }
             }
@@ -414,7 +365,14 @@ where
         }
     }
 
-    fn link_length(
+    /// A cursor over the list, not on any node until it is moved.
+    pub fn cursor(&self) -> Cursor<'_, K, V> {
+        Cursor::new(self)
+    }
+
+    /// The number of nodes from `start` to `end` on level `lvl`, or an
+    /// error if `end` is not reached.
+    pub fn link_length(
         &self,
         start: *mut Node<K, V>,
         end: Option<*mut Node<K, V>>,
@@ -429,15 +387,15 @@ where
                     if (*node).is_header() {
                         length -= 1;
                     }
-                    match (*node).forwards[lvl] {
+                    match (*node).forwards()[lvl] {
                         Some(ptr) => node = ptr,
                         None => break,
                     }
                 }
             } else {
                 while Some(node) != end {
-                    length += (*node).links_len[lvl - 1];
-                    match (*node).forwards[lvl - 1] {
+                    length += (*node).links_len()[lvl - 1];
+                    match (*node).forwards()[lvl - 1] {
                         Some(ptr) => node = ptr,
                         None => break,
                     }
@@ -451,71 +409,440 @@ where
             Ok(length)
         }
     }
+}
 
-    fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
+impl<K, V> SkipList<K, V> {
+    #[inline]
+    fn head_ptr(&self) -> *const Node<K, V> {
+        self.head
+    }
+
+    #[inline]
+    fn head_mut(&mut self) -> *mut Node<K, V> {
+        self.head
+    }
+
+    /// Unlink `node` from the list, `prev_nodes[lvl]` being the last node
+    /// before it on level `lvl`, and move its pair out. The memory of the
+    /// node is only freed along with the arena.
+    unsafe fn unlink(&mut self, prev_nodes: &[*mut Node<K, V>], node: *mut Node<K, V>) -> Option<(K, V)> {
+        for (lvl, &prev_node) in prev_nodes.iter().enumerate() {
+            if (*prev_node).forwards()[lvl] == Some(node) {
+                (*prev_node).forwards_mut()[lvl] = (*node).forwards()[lvl];
+                (*prev_node).links_len_mut()[lvl] += (*node).links_len()[lvl];
+            }
+            (*prev_node).links_len_mut()[lvl] -= 1;
+        }
+        if let Some(next_node) = (*node).forwards()[0] {
+            (*next_node).prev = (*node).prev;
+        }
+        self.n -= 1;
+        (*node).key.take().zip((*node).value.take())
+    }
+
+    /// The node at position `rank` counted from the header, which is at
+    /// position 0, found by skipping over `links_len` nodes at a time.
+    fn node_at_rank(&self, rank: usize) -> *const Node<K, V> {
         unsafe {
-            let mut node: *mut Node<K, V> = mem::transmute_copy(&self.head);
+            let mut node: *const Node<K, V> = self.head_ptr();
+            let mut node_rank = 0;
 
             let mut lvl = self.level_gen.total();
             while lvl > 0 {
                 lvl -= 1;
 
-                while let Some(next) = (*node).forwards[lvl] {
-                    if let Some(ref next_key) = (*next).key {
-                        match next_key.borrow().cmp(key) {
-                            Ordering::Less => {
-                                node = next;
-                                continue;
-                            }
-                            Ordering::Equal => {
-                                return true;
-                            }
-                            Ordering::Greater => {
-                                break;
-                            }
-                        }
+                while let Some(next) = (*node).forwards()[lvl] {
+                    let next_rank = node_rank + (*node).links_len()[lvl];
+                    if next_rank > rank {
+                        break;
                     }
+                    node_rank = next_rank;
+                    node = next;
+                }
+                if node_rank == rank {
+                    break;
                 }
             }
-            false
+            node
+        }
+    }
+
+    /// The pair at position `index` in key order, counting from 0, in
+    /// O(log n).
+    pub fn get_by_index(&self, index: usize) -> Option<(&K, &V)> {
+        if index >= self.n as usize {
+            return None;
+        }
+        unsafe {
+            let node = self.node_at_rank(index + 1);
+            Some(((*node).key.as_ref()?, (*node).value.as_ref()?))
+        }
+    }
+
+    /// Remove the pair at position `index` in key order, counting from 0,
+    /// in O(log n).
+    pub fn remove_index(&mut self, index: usize) -> Option<(K, V)> {
+        if index >= self.n as usize {
+            return None;
+        }
+        unsafe {
+            let total = self.level_gen.total();
+            let mut node: *mut Node<K, V> = self.head_mut();
+            let mut node_rank = 0;
+            let mut prev_nodes: Vec<*mut Node<K, V>> = vec![ptr::null_mut(); total];
+
+            let mut lvl = total;
+            while lvl > 0 {
+                lvl -= 1;
+
+                while let Some(next) = (*node).forwards()[lvl] {
+                    let next_rank = node_rank + (*node).links_len()[lvl];
+                    if next_rank > index {
+                        break;
+                    }
+                    node_rank = next_rank;
+                    node = next;
+                }
+                prev_nodes[lvl] = node;
+            }
+
+            let return_node = (*prev_nodes[0]).forwards()[0]?;
+            self.unlink(&prev_nodes, return_node)
+        }
+    }
+
+    /// The pairs at positions `range` in key order, counting from 0. The
+    /// ends of the range are found in O(log n).
+    pub fn select_range<R: RangeBounds<usize>>(&self, range: R) -> Iter<'_, K, V> {
+        let n = self.n as usize;
+        let start = match range.start_bound() {
+            Bound::Included(&i) => i,
+            Bound::Excluded(&i) => i.saturating_add(1),
+            Bound::Unbounded => 0,
+        }
+        .min(n);
+        let end = match range.end_bound() {
+            Bound::Included(&j) => j.saturating_add(1),
+            Bound::Excluded(&j) => j,
+            Bound::Unbounded => n,
+        }
+        .clamp(start, n);
+
+        // the iterator yields the pairs after `start` up to `end` included,
+        // so both are taken one position lower.
+        Iter {
+            start: self.node_at_rank(start),
+            end: self.node_at_rank(end),
+            size: end - start,
+            _lifetime_k: PhantomData,
+            _lifetime_v: PhantomData,
+        }
+    }
+
+    /// Number of bytes taken by the nodes of the run, including those of
+    /// the removed pairs, and by the keys and values as counted by
+    /// `add_heap_bytes`.
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.arena.memory_usage() + self.heap_bytes
+    }
+
+    /// Count `bytes` of memory owned by keys or values of the run, outside
+    /// of its nodes.
+    #[inline]
+    pub fn add_heap_bytes(&mut self, bytes: usize) {
+        self.heap_bytes += bytes;
+    }
+
+    /// Set the size in bytes from which the run is full, or 0 for no limit.
+    #[inline]
+    pub fn set_max_bytes(&mut self, bytes: usize) {
+        self.max_bytes = bytes;
+    }
+
+    /// Delete every key from `start` included to `end` excluded by recording
+    /// a single range tombstone, leaving the pairs in place.
+    pub fn delete_range(&mut self, start: K, end: K) {
+        self.writes += 1;
+        self.range_tombstones.push(RangeTombstone::new(start, end));
+        self.tombstone_stamps.push(self.writes);
+    }
+
+    /// The range tombstones recorded so far, oldest first.
+    #[inline]
+    pub fn range_tombstones(&self) -> &[RangeTombstone<K>] {
+        &self.range_tombstones
+    }
+
+    /// Whether a range tombstone of the list deletes `key`: it covers the
+    /// key and was recorded after the pair of the key, if any, was written.
+    pub fn is_range_deleted(&self, key: &K) -> bool {
+        let stamp = self.find_node(key).map_or(0, |node| node.stamp);
+        self.is_deleted_after(key, stamp)
+    }
+
+    /// Whether a range tombstone recorded after write `stamp` covers `key`.
+    fn is_deleted_after(&self, key: &K, stamp: u64) -> bool {
+        self.range_tombstones
+            .iter()
+            .zip(&self.tombstone_stamps)
+            .any(|(tombstone, &deleted_at)| deleted_at > stamp && self.cmp.covers(tombstone, key))
+    }
+
+    /// The node holding `key`, if any.
+    fn find_node(&self, key: &K) -> Option<&Node<K, V>> {
+        let node = unsafe { &*self.find_key(key) };
+        match node.key {
+            Some(ref node_key) if self.cmp.compare(node_key, key) == Ordering::Equal => Some(node),
+            _ => None,
         }
     }
 }
 
 impl<K, V> SkipList<K, V>
 where
-    K: cmp::Ord,
+    K: Clone,
+    V: Clone,
 {
     #[inline]
-    fn is_empty(&self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.n == 0
     }
 
     #[inline]
-    fn elt_in(&mut self, key: K) -> bool {
+    pub fn elt_in(&mut self, key: K) -> bool {
         self.contains_key(&key)
     }
+
+    /// Whether the run has reached the size set by `set_size`, or the size
+    /// in bytes set by `set_max_bytes`.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        (self.max_size > 0 && self.n as usize >= self.max_size)
+            || (self.max_bytes > 0 && self.memory_usage() >= self.max_bytes)
+    }
 }
 
+// The nodes live in the arena of the list, and the raw links only point
+// into nodes of the same list, so the whole list moves along when it is
+// sent to another thread.
+unsafe impl<K: Send, V: Send> Send for SkipList<K, V> {}
+
 impl<K, V> Drop for SkipList<K, V> {
     #[inline]
     fn drop(&mut self) {
+        // drop the keys and values in place; the arena frees the nodes.
         unsafe {
-            let node: *mut Node<K, V> = mem::transmute_copy(&self.head);
-
-            while let Some(ref mut next) = (*node).next {
-                mem::replace(&mut (*node).next, mem::replace(&mut next.next, None));    
+            let mut node = Some(self.head);
+            while let Some(ptr) = node {
+                node = (*ptr).forwards()[0];
+                ptr::drop_in_place(ptr);
             }
-        }    
+        }
     }
 }
 
-impl<K: Ord, V> default::Default for SkipList<K, V> {
+impl<K: Ord + Clone, V: Clone> default::Default for SkipList<K, V> {
     fn default() -> SkipList<K, V> {
         SkipList::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    use std::sync::Arc;
+
+    use super::SkipList;
+    use crate::comparator::Comparator;
+    use crate::run::Run;
+
+    struct Descending;
+
+    impl Comparator<i32> for Descending {
+        fn name(&self) -> &str {
+            "descending"
+        }
+
+        fn compare(&self, a: &i32, b: &i32) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn insert_and_lookup() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in (0..100).rev() {
+            sl.insert_key(i, i * 10);
+        }
+        assert_eq!(sl.num_elements(), 100);
+        for i in 0..100 {
+            assert_eq!(sl.lookup(&i), Some(i * 10));
+        }
+        assert_eq!(sl.lookup(&100), None);
+        assert_eq!(sl.get_min(), Some(0));
+        assert_eq!(sl.get_max(), Some(99));
+    }
+
+    #[test]
+    fn insert_overwrites() {
+        let mut sl: SkipList<i32, &str> = SkipList::new();
+        sl.insert_key(1, "a");
+        sl.insert_key(1, "b");
+        assert_eq!(sl.num_elements(), 1);
+        assert_eq!(sl.lookup(&1), Some("b"));
+    }
+
+    #[test]
+    fn delete() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in 0..50 {
+            sl.insert_key(i, i);
+        }
+        for i in (0..50).filter(|i| i % 2 == 0) {
+            assert_eq!(sl.delete_key(&i), Some(i));
+        }
+        assert_eq!(sl.delete_key(&0), None);
+        assert_eq!(sl.num_elements(), 25);
+        let keys: Vec<i32> = sl.get_all().into_iter().map(|kv| kv.key.unwrap()).collect();
+        assert_eq!(keys, (0..50).filter(|i| i % 2 == 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delete_range() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in 0..50 {
+            sl.insert_key(i, i);
+        }
+        sl.delete_range(10, 20);
+        assert_eq!(sl.lookup(&9), Some(9));
+        assert_eq!(sl.lookup(&10), None);
+        assert!(!sl.contains_key(&19));
+        assert_eq!(sl.lookup(&20), Some(20));
+        // the pairs stay in the list, along with the tombstone.
+        assert_eq!(sl.num_elements(), 50);
+        assert_eq!(sl.range_tombstones().len(), 1);
+        assert!(sl.is_range_deleted(&15));
+
+        // a pair written after the tombstone is visible again.
+        sl.insert_key(15, -15);
+        sl.insert_key(55, 55);
+        assert_eq!(sl.lookup(&15), Some(-15));
+        assert!(!sl.is_range_deleted(&15));
+        assert!(sl.is_range_deleted(&16));
+        sl.delete_range(0, 60);
+        assert_eq!(sl.lookup(&15), None);
+        assert_eq!(sl.lookup(&55), None);
+    }
+
+    #[test]
+    fn range() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in 0..20 {
+            sl.insert_key(i * 2, i);
+        }
+        let keys: Vec<i32> = sl.range(Included(&4), Included(&10)).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![4, 6, 8, 10]);
+        let keys: Vec<i32> = sl.range(Excluded(&4), Excluded(&10)).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![6, 8]);
+        let keys: Vec<i32> = sl.range(Included(&35), Unbounded).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![36, 38]);
+        assert_eq!(sl.range(Included(&10), Included(&4)).count(), 0);
+        assert_eq!(sl.get_all_in_range(3, 7).len(), 2);
+
+        let keys: Vec<i32> = sl.range(Excluded(&4), Included(&10)).rev().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![10, 8, 6]);
+        let mut range = sl.range(Unbounded, Excluded(&12));
+        assert_eq!(range.next_back(), Some((10, 5)));
+        assert_eq!(range.next(), Some((0, 0)));
+        assert_eq!(range.len(), 4);
+        let keys: Vec<i32> = range.rev().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![8, 6, 4, 2]);
+    }
+
+    #[test]
+    fn index_access() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in (0..200).rev() {
+            sl.insert_key(i * 3, i);
+        }
+        assert_eq!(sl.get_by_index(0), Some((&0, &0)));
+        assert_eq!(sl.get_by_index(150), Some((&450, &150)));
+        assert_eq!(sl.get_by_index(200), None);
+        assert_eq!(sl.rank_of(&450), Some(150));
+        assert_eq!(sl.rank_of(&451), None);
+        assert!((0..200).all(|i| sl.rank_of(&(i * 3)) == Some(i as usize)));
+
+        assert_eq!(sl.remove_index(0), Some((0, 0)));
+        assert_eq!(sl.remove_index(99), Some((300, 100)));
+        assert_eq!(sl.remove_index(198), None);
+        assert_eq!(sl.num_elements(), 198);
+        assert_eq!(sl.get_by_index(0), Some((&3, &1)));
+        assert_eq!(sl.get_by_index(99), Some((&303, &101)));
+        assert_eq!(sl.rank_of(&303), Some(99));
+        assert_eq!(sl.lookup(&300), None);
+    }
+
+    #[test]
+    fn select_range() {
+        let mut sl: SkipList<i32, i32> = SkipList::new();
+        for i in 0..100 {
+            sl.insert_key(i, i);
+        }
+        sl.delete_key(&10);
+        let keys: Vec<i32> = sl.select_range(8..12).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![8, 9, 11, 12]);
+        assert_eq!(sl.select_range(8..12).len(), 4);
+        let keys: Vec<i32> = sl.select_range(97..).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![98, 99]);
+        assert_eq!(sl.select_range(..3).count(), 3);
+        assert_eq!(sl.select_range(..=3).count(), 4);
+        assert_eq!(sl.select_range(50..50).count(), 0);
+        assert_eq!(sl.select_range(120..130).count(), 0);
+    }
+
+    #[test]
+    fn full_by_bytes() {
+        let mut sl: SkipList<u64, u64> = SkipList::new();
+        sl.set_max_bytes(4096);
+        let empty = sl.memory_usage();
+        sl.insert_key(1, 1);
+        let one = sl.memory_usage();
+        assert!(one > empty);
+        // overwriting a value takes no memory.
+        sl.insert_key(1, 2);
+        assert_eq!(sl.memory_usage(), one);
+
+        let mut n = 1;
+        while !sl.is_full() {
+            n += 1;
+            sl.insert_key(n, n);
+        }
+        assert!(sl.memory_usage() >= 4096);
+        assert_eq!(sl.num_elements() as u64, n);
+        assert_eq!(sl.lookup(&n), Some(n));
+
+        let mut sl: SkipList<u64, u64> = SkipList::new();
+        sl.set_max_bytes(4096);
+        sl.insert_key(1, 1);
+        sl.add_heap_bytes(4096);
+        assert!(sl.is_full());
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let mut sl: SkipList<i32, i32> = SkipList::with_comparator(Arc::new(Descending));
+        for i in 0..50 {
+            sl.insert_key(i, i);
+        }
+        assert_eq!(sl.get_min(), Some(49));
+        assert_eq!(sl.get_max(), Some(0));
+        assert_eq!(sl.lookup(&7), Some(7));
+        assert_eq!(sl.rank_of(&47), Some(2));
+        let keys: Vec<i32> = sl.range(Included(&10), Excluded(&6)).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![10, 9, 8, 7]);
+        assert_eq!(sl.delete_key(&10), Some(10));
+        assert_eq!(sl.lookup(&10), None);
+    }
+}